@@ -1,5 +1,7 @@
 use std::ffi::c_int;
 
+use crate::errors::SqliteFailure;
+
 #[derive(thiserror::Error, Debug)]
 pub enum SqliteOpenErrors {
     /// This error occurs when SQLite is unable to allocate memory to hold
@@ -11,10 +13,29 @@ pub enum SqliteOpenErrors {
     // in case of any other errors
     #[error("SQLite error {code}: {error_msg}")]
     SqliteFailure { code: c_int, error_msg: String },
-    // TODO, handle it during compile time
-    // Make sure that there is no Null byte in the file
-    // #[error("{filename} contains a null byte. Make sure that there is no Null byte in the file")]
-    // EmbeddedNullInFileName { filename: String },
+
+    /// Make sure that there is no Null byte in the file name
+    #[error(
+        "{filename:?} contains a null byte. Make sure that there is no Null byte in the file name"
+    )]
+    EmbeddedNullInFileName { filename: String },
+
+    /// Make sure that there is no Null byte in the vfs name
+    #[error("vfs name {vfs:?} contains a null byte")]
+    EmbeddedNullInVfsName { vfs: String },
+
+    /// `LazyPool` needs WAL mode, which e.g. in memory databases cannot use
+    #[error("a pool needs WAL mode, but the database stayed in {mode:?} journal mode")]
+    WalUnavailable { mode: String },
+}
+
+impl From<SqliteFailure> for SqliteOpenErrors {
+    fn from(e: SqliteFailure) -> Self {
+        SqliteOpenErrors::SqliteFailure {
+            code: e.code,
+            error_msg: e.error_msg,
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
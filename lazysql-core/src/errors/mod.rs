@@ -1,11 +1,18 @@
-use std::ffi::c_int;
+use std::{ffi::c_int, path::PathBuf};
 
-use crate::errors::{connection::SqlitePrepareErrors, row::RowMapperError, statement::StatementStepErrors};
+use libsqlite3_sys::{SQLITE_BUSY, SQLITE_INTERRUPT};
+
+use crate::errors::{connection::{SqliteOpenErrors, SqlitePrepareErrors}, row::RowMapperError, statement::StatementStepErrors};
 
 pub mod connection;
 pub mod row;
 pub mod statement;
 
+/// Extended result code lazysql reports for a statement stopped because its deadline passed
+/// (see `LazyConnection::with_timeout`), where only a [`SqliteFailure`] can be returned.
+/// SQLite has no extended codes of its own for `SQLITE_INTERRUPT`, so `code & 0xff` is still it
+pub const SQLITE_INTERRUPT_TIMEOUT: c_int = SQLITE_INTERRUPT | (1 << 8);
+
 #[derive(thiserror::Error, Debug)]
 #[error("SQLite error {code}: {error_msg}")]
 pub struct SqliteFailure {
@@ -49,6 +56,126 @@ pub enum SqlReadErrorBindings {
     Bind(#[from] SqliteFailure),
 }
 
+/// Errors raised while binding a set of [`Params`](crate::traits::params::Params) to a statement.
+#[derive(thiserror::Error, Debug)]
+pub enum ParamsError {
+    #[error("Statement expects {expected} parameters but {provided} were provided")]
+    CountMismatch { expected: usize, provided: usize },
+
+    #[error("Statement has no parameter named '{0}'")]
+    UnknownName(String),
+
+    #[error("Parameter '{0}' is bound more than once")]
+    DuplicateName(String),
+
+    #[error("Failed to Bind: {0}")]
+    Bind(#[from] SqliteFailure),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SqlDynamicError {
+    #[error("Failed to prepare statement: {0}")]
+    Prepare(#[from] SqlitePrepareErrors),
+
+    #[error("Failed to bind parameters: {0}")]
+    Params(#[from] ParamsError),
+
+    #[error("Failed to execute statement step: {0}")]
+    Step(#[from] SqliteFailure),
+}
+
+/// Errors raised by `LazyConnection::backup_to` / `restore_from`.
+#[derive(thiserror::Error, Debug)]
+pub enum BackupError {
+    #[error("Failed to open the other database: {0}")]
+    Open(#[from] SqliteOpenErrors),
+
+    #[error("Backup failed: {0}")]
+    Step(#[from] SqliteFailure),
+}
+
+/// Errors raised by `LazyConnection::blob_open`.
+#[derive(thiserror::Error, Debug)]
+pub enum BlobError {
+    #[error("table or column name {name:?} contains a null byte")]
+    EmbeddedNullInName { name: String },
+
+    #[error("{0}")]
+    Sqlite(#[from] SqliteFailure),
+}
+
+/// Errors raised while registering a user defined function.
+#[derive(thiserror::Error, Debug)]
+pub enum FunctionError {
+    #[error("function name {name:?} contains a null byte")]
+    EmbeddedNullInName { name: String },
+
+    #[error("{0}")]
+    Sqlite(#[from] SqliteFailure),
+}
+
+/// Errors raised while registering a collation.
+#[derive(thiserror::Error, Debug)]
+pub enum CollationError {
+    #[error("collation name {name:?} contains a null byte")]
+    EmbeddedNullInName { name: String },
+
+    #[error("{0}")]
+    Sqlite(#[from] SqliteFailure),
+}
+
+/// Errors raised by `LazyConnection::serialize` / `deserialize`.
+#[derive(thiserror::Error, Debug)]
+pub enum SerializeError {
+    #[error("schema name {schema:?} contains a null byte")]
+    EmbeddedNullInSchemaName { schema: String },
+
+    #[error("{0}")]
+    Sqlite(#[from] SqliteFailure),
+}
+
+/// Errors raised by `LazyConnection::load_extension`.
+#[derive(thiserror::Error, Debug)]
+pub enum ExtensionError {
+    #[error("extension path {path:?} is not valid UTF-8 or contains a null byte")]
+    InvalidPath { path: PathBuf },
+
+    #[error("entry point {entry_point:?} contains a null byte")]
+    EmbeddedNullInEntryPoint { entry_point: String },
+
+    #[error("failed to load extension {path:?}: {error_msg}")]
+    Load { path: PathBuf, error_msg: String },
+
+    #[error("{0}")]
+    Sqlite(#[from] SqliteFailure),
+}
+
+/// Errors raised by `LazyConnection::create_module`.
+#[derive(thiserror::Error, Debug)]
+pub enum ModuleError {
+    #[error("module name {name:?} contains a null byte")]
+    EmbeddedNullInName { name: String },
+
+    #[error("{0}")]
+    Sqlite(#[from] SqliteFailure),
+}
+
+/// Errors raised by `LazyConnection::session`, `Session` and `apply_changeset`.
+#[cfg(feature = "session")]
+#[derive(thiserror::Error, Debug)]
+pub enum SessionError {
+    #[error("database or table name {name:?} contains a null byte")]
+    EmbeddedNullInName { name: String },
+
+    #[error("changeset of {len} bytes is too large, at most 2 GiB are supported")]
+    TooLarge { len: usize },
+
+    #[error("applying the changeset was aborted by the conflict handler")]
+    Aborted,
+
+    #[error("{0}")]
+    Sqlite(#[from] SqliteFailure),
+}
 
 /// Unified Error type for transactios since anything can go wrong.
 #[derive(thiserror::Error, Debug)]
@@ -65,9 +192,188 @@ pub enum Error {
     #[error(transparent)]
     ReadBinding(#[from] SqlReadErrorBindings),
 
+    #[error(transparent)]
+    Dynamic(#[from] SqlDynamicError),
+
     #[error(transparent)]
     Row(#[from] RowMapperError), // Needed when iterating over results
 
     #[error(transparent)]
-    Db(#[from] SqliteFailure), // Needed for Transaction BEGIN/COMMIT failures
-}
\ No newline at end of file
+    Db(#[from] SqliteFailure), // Needed for Transaction BEGIN failures
+
+    /// `COMMIT` (or `RELEASE` of a savepoint) failed. The transaction was rolled back
+    #[error("COMMIT failed: {0}")]
+    Commit(SqliteFailure),
+
+    /// The transaction closure failed with `cause` and the `ROLLBACK` that followed failed as well.
+    /// `cause` is the closure's error itself, `downcast` it to get it back
+    #[error("ROLLBACK failed: {rollback} (rolled back because of: {cause})")]
+    Rollback {
+        cause: Box<dyn std::error::Error + Send + Sync>,
+        rollback: SqliteFailure,
+    },
+}
+
+/// Errors that may have been caused by SQLite, and which result code it reported.
+/// The `transaction` methods use it on lazysql's [`Error`] and on boxed errors to tell whether
+/// a failed attempt is worth retrying.
+pub trait ErrorCode {
+    /// The (possibly extended) result code, if SQLite reported one
+    fn sqlite_code(&self) -> Option<c_int>;
+}
+
+impl ErrorCode for SqliteFailure {
+    fn sqlite_code(&self) -> Option<c_int> {
+        Some(self.code)
+    }
+}
+
+impl ErrorCode for SqlitePrepareErrors {
+    fn sqlite_code(&self) -> Option<c_int> {
+        match self {
+            SqlitePrepareErrors::SqliteFailure { code, .. } => Some(*code),
+        }
+    }
+}
+
+impl ErrorCode for StatementStepErrors {
+    fn sqlite_code(&self) -> Option<c_int> {
+        match self {
+            StatementStepErrors::SqliteBusy => Some(SQLITE_BUSY),
+            StatementStepErrors::Interrupted => Some(SQLITE_INTERRUPT),
+            StatementStepErrors::Timeout => Some(SQLITE_INTERRUPT_TIMEOUT),
+            StatementStepErrors::ForeignKeyConstraint { code, .. }
+            | StatementStepErrors::UniqueConstraint { code, .. }
+            | StatementStepErrors::CheckConstraint { code, .. }
+            | StatementStepErrors::SqliteFailure { code, .. } => Some(*code),
+        }
+    }
+}
+
+impl ErrorCode for RowMapperError {
+    fn sqlite_code(&self) -> Option<c_int> {
+        match self {
+            RowMapperError::SqliteBusy => Some(SQLITE_BUSY),
+            RowMapperError::Interrupted => Some(SQLITE_INTERRUPT),
+            RowMapperError::Timeout => Some(SQLITE_INTERRUPT_TIMEOUT),
+            RowMapperError::SqliteFailure { code, .. } => Some(*code),
+        }
+    }
+}
+
+impl ErrorCode for SqlWriteError {
+    fn sqlite_code(&self) -> Option<c_int> {
+        match self {
+            SqlWriteError::Prepare(e) => e.sqlite_code(),
+            SqlWriteError::Step(e) => e.sqlite_code(),
+        }
+    }
+}
+
+impl ErrorCode for SqlWriteBindingError {
+    fn sqlite_code(&self) -> Option<c_int> {
+        match self {
+            SqlWriteBindingError::Prepare(e) => e.sqlite_code(),
+            SqlWriteBindingError::Step(e) => e.sqlite_code(),
+            SqlWriteBindingError::Bind(e) => e.sqlite_code(),
+        }
+    }
+}
+
+impl ErrorCode for SqlReadError {
+    fn sqlite_code(&self) -> Option<c_int> {
+        match self {
+            SqlReadError::Prepare(e) => e.sqlite_code(),
+        }
+    }
+}
+
+impl ErrorCode for SqlReadErrorBindings {
+    fn sqlite_code(&self) -> Option<c_int> {
+        match self {
+            SqlReadErrorBindings::Prepare(e) => e.sqlite_code(),
+            SqlReadErrorBindings::Bind(e) => e.sqlite_code(),
+        }
+    }
+}
+
+impl ErrorCode for ParamsError {
+    fn sqlite_code(&self) -> Option<c_int> {
+        match self {
+            ParamsError::Bind(e) => e.sqlite_code(),
+            ParamsError::CountMismatch { .. }
+            | ParamsError::UnknownName(_)
+            | ParamsError::DuplicateName(_) => None,
+        }
+    }
+}
+
+impl ErrorCode for SqlDynamicError {
+    fn sqlite_code(&self) -> Option<c_int> {
+        match self {
+            SqlDynamicError::Prepare(e) => e.sqlite_code(),
+            SqlDynamicError::Params(e) => e.sqlite_code(),
+            SqlDynamicError::Step(e) => e.sqlite_code(),
+        }
+    }
+}
+
+impl ErrorCode for Error {
+    fn sqlite_code(&self) -> Option<c_int> {
+        match self {
+            Error::Write(e) => e.sqlite_code(),
+            Error::WriteBinding(e) => e.sqlite_code(),
+            Error::Read(e) => e.sqlite_code(),
+            Error::ReadBinding(e) => e.sqlite_code(),
+            Error::Dynamic(e) => e.sqlite_code(),
+            Error::Row(e) => e.sqlite_code(),
+            Error::Db(e) | Error::Commit(e) => e.sqlite_code(),
+            Error::Rollback { rollback, .. } => rollback.sqlite_code(),
+        }
+    }
+}
+
+impl ErrorCode for Box<dyn std::error::Error> {
+    fn sqlite_code(&self) -> Option<c_int> {
+        sqlite_code_in_chain(self.as_ref())
+    }
+}
+
+impl ErrorCode for Box<dyn std::error::Error + Send + Sync> {
+    fn sqlite_code(&self) -> Option<c_int> {
+        sqlite_code_in_chain(self.as_ref())
+    }
+}
+
+/// Looks for a lazysql error in `err` and its sources
+fn sqlite_code_in_chain(err: &(dyn std::error::Error + 'static)) -> Option<c_int> {
+    macro_rules! code_of_any {
+        ($err:expr, $($ty:ty),*) => {
+            $(
+                if let Some(e) = $err.downcast_ref::<$ty>() {
+                    return e.sqlite_code();
+                }
+            )*
+        };
+    }
+
+    let mut next = Some(err);
+    while let Some(err) = next {
+        code_of_any!(
+            err,
+            Error,
+            SqliteFailure,
+            SqlitePrepareErrors,
+            StatementStepErrors,
+            RowMapperError,
+            SqlWriteError,
+            SqlWriteBindingError,
+            SqlReadError,
+            SqlReadErrorBindings,
+            ParamsError,
+            SqlDynamicError
+        );
+        next = err.source();
+    }
+    None
+}
@@ -5,6 +5,12 @@ pub enum RowMapperError {
     #[error("SqliteBusy. Operation took more than 5 seconds")]
     SqliteBusy,
 
+    #[error("Interrupted through an InterruptHandle")]
+    Interrupted,
+
+    #[error("Timeout. The deadline of the query passed")]
+    Timeout,
+
     // in case of any other errors
     #[error("SQLite error {code}: {error_msg}")]
     SqliteFailure { code: c_int, error_msg: String },
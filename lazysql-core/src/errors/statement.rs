@@ -5,6 +5,12 @@ pub enum StatementStepErrors {
     #[error("SqliteBusy. Operation took more than 5 seconds")]
     SqliteBusy,
 
+    #[error("Interrupted through an InterruptHandle")]
+    Interrupted,
+
+    #[error("Timeout. The deadline of the statement passed")]
+    Timeout,
+
     #[error("Foreign key constraint failed. Sqlite error {code} : {error_msg}")]
     ForeignKeyConstraint { code: c_int, error_msg: String },
 
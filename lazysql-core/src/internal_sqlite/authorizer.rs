@@ -0,0 +1,491 @@
+use std::{
+    collections::HashSet,
+    ffi::{CStr, c_char, c_int, c_void},
+    panic::{AssertUnwindSafe, catch_unwind},
+    ptr,
+};
+
+use libsqlite3_sys::{
+    SQLITE_ALTER_TABLE, SQLITE_ANALYZE, SQLITE_ATTACH, SQLITE_CREATE_INDEX, SQLITE_CREATE_TABLE,
+    SQLITE_CREATE_TEMP_INDEX, SQLITE_CREATE_TEMP_TABLE, SQLITE_CREATE_TEMP_TRIGGER,
+    SQLITE_CREATE_TEMP_VIEW, SQLITE_CREATE_TRIGGER, SQLITE_CREATE_VIEW, SQLITE_CREATE_VTABLE,
+    SQLITE_DELETE, SQLITE_DENY, SQLITE_DETACH, SQLITE_DROP_INDEX, SQLITE_DROP_TABLE,
+    SQLITE_DROP_TEMP_INDEX, SQLITE_DROP_TEMP_TABLE, SQLITE_DROP_TEMP_TRIGGER,
+    SQLITE_DROP_TEMP_VIEW, SQLITE_DROP_TRIGGER, SQLITE_DROP_VIEW, SQLITE_DROP_VTABLE,
+    SQLITE_FUNCTION, SQLITE_IGNORE, SQLITE_INSERT, SQLITE_OK, SQLITE_PRAGMA, SQLITE_READ,
+    SQLITE_RECURSIVE, SQLITE_REINDEX, SQLITE_SAVEPOINT, SQLITE_SELECT, SQLITE_TRANSACTION,
+    SQLITE_UPDATE, sqlite3_set_authorizer,
+};
+
+use crate::{
+    internal_sqlite::{
+        lazy_connection::LazyConnection,
+        threading::{PerThread, ThreadingMode},
+    },
+    utility::utils::DbMutexGuard,
+};
+
+/// What a statement being prepared wants to do, as reported to the authorizer.
+/// `temp` is set for the `TEMP` variants of `CREATE`/`DROP`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthAction<'a> {
+    CreateIndex {
+        index: &'a str,
+        table: &'a str,
+        temp: bool,
+    },
+    CreateTable {
+        table: &'a str,
+        temp: bool,
+    },
+    CreateTrigger {
+        trigger: &'a str,
+        table: &'a str,
+        temp: bool,
+    },
+    CreateView {
+        view: &'a str,
+        temp: bool,
+    },
+    CreateVirtualTable {
+        table: &'a str,
+        module: &'a str,
+    },
+    DropIndex {
+        index: &'a str,
+        table: &'a str,
+        temp: bool,
+    },
+    DropTable {
+        table: &'a str,
+        temp: bool,
+    },
+    DropTrigger {
+        trigger: &'a str,
+        table: &'a str,
+        temp: bool,
+    },
+    DropView {
+        view: &'a str,
+        temp: bool,
+    },
+    DropVirtualTable {
+        table: &'a str,
+        module: &'a str,
+    },
+    AlterTable {
+        database: &'a str,
+        table: &'a str,
+    },
+    Reindex {
+        index: &'a str,
+    },
+    Analyze {
+        table: &'a str,
+    },
+    /// A `SELECT` statement (or subquery), followed by a `Read` per column it uses
+    Select,
+    /// A column read by a query. `column` is empty when the query reads the table
+    /// without naming a column, e.g. `SELECT count(*) FROM t`
+    Read {
+        table: &'a str,
+        column: &'a str,
+    },
+    Insert {
+        table: &'a str,
+    },
+    Update {
+        table: &'a str,
+        column: &'a str,
+    },
+    Delete {
+        table: &'a str,
+    },
+    /// `operation` is `BEGIN`, `COMMIT` or `ROLLBACK`
+    Transaction {
+        operation: &'a str,
+    },
+    /// `operation` is `BEGIN`, `RELEASE` or `ROLLBACK`
+    Savepoint {
+        operation: &'a str,
+        name: &'a str,
+    },
+    Pragma {
+        name: &'a str,
+        arg: Option<&'a str>,
+    },
+    Function {
+        name: &'a str,
+    },
+    Attach {
+        filename: &'a str,
+    },
+    Detach {
+        database: &'a str,
+    },
+    /// A recursive common table expression
+    Recursive,
+    /// An action code added by a later SQLite version
+    Unknown {
+        code: c_int,
+        arg1: Option<&'a str>,
+        arg2: Option<&'a str>,
+    },
+}
+
+/// One call of the authorizer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthContext<'a> {
+    pub action: AuthAction<'a>,
+    /// `main`, `temp` or the name of an attached database, if the action concerns one
+    pub database: Option<&'a str>,
+    /// The innermost trigger or view responsible for the action, if any
+    pub accessor: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthDecision {
+    Allow,
+    /// Preparing the statement fails with `SQLITE_AUTH` ("not authorized")
+    Deny,
+    /// For `Read` the column is read as NULL, for `Delete` the table is deleted row by row
+    /// (so `on_change` sees it), everything else is skipped silently
+    Ignore,
+}
+
+/// Decides whether a statement may be prepared. Implemented for closures
+/// taking an `&AuthContext`.
+///
+/// It is called while SQLite prepares the statement, so it must not use the connection.
+/// A panic inside it denies the action.
+pub trait AuthPolicy: Send {
+    fn authorize(&mut self, ctx: &AuthContext) -> AuthDecision;
+}
+
+impl<F: FnMut(&AuthContext) -> AuthDecision + Send> AuthPolicy for F {
+    fn authorize(&mut self, ctx: &AuthContext) -> AuthDecision {
+        self(ctx)
+    }
+}
+
+/// An [`AuthPolicy`] for untrusted queries: allows reading tables and calling functions,
+/// denies everything else (writes, schema changes, `ATTACH`, `PRAGMA`, transactions).
+///
+/// Table and function names are compared case insensitively, tables apply to every database.
+/// Reading a view needs both the view and the tables behind it to be allowed.
+/// Every function is allowed unless [`allow_functions`](Self::allow_functions) narrows them
+/// down, which is recommended for SQL from untrusted sources.
+#[derive(Debug, Clone, Default)]
+pub struct ReadOnlySandbox {
+    tables: NameFilter,
+    functions: NameFilter,
+}
+
+/// Names that are allowed, all of them if `allowed` is `None`, minus the denied ones
+#[derive(Debug, Clone, Default)]
+struct NameFilter {
+    allowed: Option<HashSet<String>>,
+    denied: HashSet<String>,
+}
+
+impl NameFilter {
+    fn allow<I, S>(&mut self, names: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.allowed
+            .get_or_insert_with(HashSet::new)
+            .extend(names.into_iter().map(|n| n.as_ref().to_lowercase()));
+    }
+
+    fn deny<I, S>(&mut self, names: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.denied
+            .extend(names.into_iter().map(|n| n.as_ref().to_lowercase()));
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        !self.denied.contains(&name)
+            && self
+                .allowed
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(&name))
+    }
+}
+
+impl ReadOnlySandbox {
+    /// Allows reading every table and calling every function
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only the given tables may be read. Calling it again adds to the list
+    pub fn allow_tables<I, S>(mut self, tables: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.tables.allow(tables);
+        self
+    }
+
+    /// The given tables may not be read, even if they are allowed
+    pub fn deny_tables<I, S>(mut self, tables: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.tables.deny(tables);
+        self
+    }
+
+    /// Only the given functions (built-in or user defined, including aggregates) may be
+    /// called. Calling it again adds to the list
+    pub fn allow_functions<I, S>(mut self, functions: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.functions.allow(functions);
+        self
+    }
+
+    /// The given functions may not be called, even if they are allowed
+    pub fn deny_functions<I, S>(mut self, functions: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.functions.deny(functions);
+        self
+    }
+
+    pub fn can_read(&self, table: &str) -> bool {
+        self.tables.contains(table)
+    }
+
+    pub fn can_call(&self, function: &str) -> bool {
+        self.functions.contains(function)
+    }
+}
+
+impl AuthPolicy for ReadOnlySandbox {
+    fn authorize(&mut self, ctx: &AuthContext) -> AuthDecision {
+        match ctx.action {
+            AuthAction::Select | AuthAction::Recursive => AuthDecision::Allow,
+            AuthAction::Read { table, .. } if self.can_read(table) => AuthDecision::Allow,
+            AuthAction::Function { name } if self.can_call(name) => AuthDecision::Allow,
+            _ => AuthDecision::Deny,
+        }
+    }
+}
+
+pub(crate) struct Authorizer {
+    policy: Box<dyn AuthPolicy>,
+}
+
+impl<M: ThreadingMode> LazyConnection<M> {
+    /// Asks `policy` about every action of the statements prepared from now on,
+    /// replacing the previous policy. Cached statements (including the ones of `lazy_sql`
+    /// structs) are prepared again, and checked, the next time they run.
+    ///
+    /// The policy also sees the statements this crate runs itself,
+    /// e.g. `BEGIN` and `SAVEPOINT` of [`transaction`](Self::transaction).
+    pub fn set_authorizer(&self, policy: impl AuthPolicy + 'static) {
+        self.install_authorizer(Some(Box::new(Authorizer {
+            policy: Box::new(policy),
+        })));
+    }
+
+    /// Removes the policy set by [`set_authorizer`](Self::set_authorizer)
+    pub fn remove_authorizer(&self) {
+        self.install_authorizer(None);
+    }
+
+    /// Points the authorizer callback at `authorizer` and returns the one it replaced
+    fn install_authorizer(&self, authorizer: Option<Box<Authorizer>>) -> Option<Box<Authorizer>> {
+        let data = authorizer
+            .as_deref()
+            .map_or(ptr::null_mut(), |a| a as *const Authorizer as *mut c_void);
+        let callback = authorizer.as_ref().map(|_| authorize as AuthCallback);
+
+        // a callback in flight holds the connection mutex, so the old policy is unused afterwards
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        unsafe {
+            // sqlite only expires the prepared statements when a callback is set, without this
+            // statements prepared under the old policy would keep its `Ignore` decisions
+            if callback.is_none() {
+                sqlite3_set_authorizer(self.db, Some(allow_all), ptr::null_mut());
+            }
+            sqlite3_set_authorizer(self.db, callback, data);
+        }
+        std::mem::replace(
+            &mut *self.authorizer.lock().unwrap_or_else(|e| e.into_inner()),
+            authorizer,
+        )
+    }
+}
+
+impl LazyConnection<PerThread> {
+    /// Runs `f` with `policy` as the authorizer, then puts back the previous one (if any),
+    /// even if `f` panics. Rows read after `f` returned are no longer checked.
+    ///
+    /// Only available on [`PerThread`] connections: the authorizer applies to the whole
+    /// connection, so on a shared one it would check the statements of other threads too.
+    /// Use [`set_authorizer`](Self::set_authorizer) there.
+    pub fn with_authorizer<T>(
+        &self,
+        policy: impl AuthPolicy + 'static,
+        f: impl FnOnce(&Self) -> T,
+    ) -> T {
+        let previous = self.install_authorizer(Some(Box::new(Authorizer {
+            policy: Box::new(policy),
+        })));
+
+        struct Restore<'a>(&'a LazyConnection<PerThread>, Option<Box<Authorizer>>);
+
+        impl Drop for Restore<'_> {
+            fn drop(&mut self) {
+                self.0.install_authorizer(self.1.take());
+            }
+        }
+
+        let _restore = Restore(self, previous);
+        f(self)
+    }
+}
+
+type AuthCallback = unsafe extern "C" fn(
+    *mut c_void,
+    c_int,
+    *const c_char,
+    *const c_char,
+    *const c_char,
+    *const c_char,
+) -> c_int;
+
+unsafe extern "C" fn authorize(
+    data: *mut c_void,
+    code: c_int,
+    arg1: *const c_char,
+    arg2: *const c_char,
+    database: *const c_char,
+    accessor: *const c_char,
+) -> c_int {
+    let authorizer = unsafe { &mut *(data as *mut Authorizer) };
+    let (arg1, arg2) = unsafe { (text(arg1), text(arg2)) };
+    let ctx = AuthContext {
+        action: action(code, arg1, arg2),
+        database: unsafe { text(database) },
+        accessor: unsafe { text(accessor) },
+    };
+
+    match catch_unwind(AssertUnwindSafe(|| authorizer.policy.authorize(&ctx))) {
+        Ok(AuthDecision::Allow) => SQLITE_OK,
+        Ok(AuthDecision::Ignore) => SQLITE_IGNORE,
+        Ok(AuthDecision::Deny) | Err(_) => SQLITE_DENY,
+    }
+}
+
+unsafe extern "C" fn allow_all(
+    _: *mut c_void,
+    _: c_int,
+    _: *const c_char,
+    _: *const c_char,
+    _: *const c_char,
+    _: *const c_char,
+) -> c_int {
+    SQLITE_OK
+}
+
+fn action<'a>(code: c_int, arg1: Option<&'a str>, arg2: Option<&'a str>) -> AuthAction<'a> {
+    let (a, b) = (arg1.unwrap_or(""), arg2.unwrap_or(""));
+    match code {
+        SQLITE_CREATE_INDEX | SQLITE_CREATE_TEMP_INDEX => AuthAction::CreateIndex {
+            index: a,
+            table: b,
+            temp: code == SQLITE_CREATE_TEMP_INDEX,
+        },
+        SQLITE_CREATE_TABLE | SQLITE_CREATE_TEMP_TABLE => AuthAction::CreateTable {
+            table: a,
+            temp: code == SQLITE_CREATE_TEMP_TABLE,
+        },
+        SQLITE_CREATE_TRIGGER | SQLITE_CREATE_TEMP_TRIGGER => AuthAction::CreateTrigger {
+            trigger: a,
+            table: b,
+            temp: code == SQLITE_CREATE_TEMP_TRIGGER,
+        },
+        SQLITE_CREATE_VIEW | SQLITE_CREATE_TEMP_VIEW => AuthAction::CreateView {
+            view: a,
+            temp: code == SQLITE_CREATE_TEMP_VIEW,
+        },
+        SQLITE_CREATE_VTABLE => AuthAction::CreateVirtualTable {
+            table: a,
+            module: b,
+        },
+        SQLITE_DROP_INDEX | SQLITE_DROP_TEMP_INDEX => AuthAction::DropIndex {
+            index: a,
+            table: b,
+            temp: code == SQLITE_DROP_TEMP_INDEX,
+        },
+        SQLITE_DROP_TABLE | SQLITE_DROP_TEMP_TABLE => AuthAction::DropTable {
+            table: a,
+            temp: code == SQLITE_DROP_TEMP_TABLE,
+        },
+        SQLITE_DROP_TRIGGER | SQLITE_DROP_TEMP_TRIGGER => AuthAction::DropTrigger {
+            trigger: a,
+            table: b,
+            temp: code == SQLITE_DROP_TEMP_TRIGGER,
+        },
+        SQLITE_DROP_VIEW | SQLITE_DROP_TEMP_VIEW => AuthAction::DropView {
+            view: a,
+            temp: code == SQLITE_DROP_TEMP_VIEW,
+        },
+        SQLITE_DROP_VTABLE => AuthAction::DropVirtualTable {
+            table: a,
+            module: b,
+        },
+        SQLITE_ALTER_TABLE => AuthAction::AlterTable {
+            database: a,
+            table: b,
+        },
+        SQLITE_REINDEX => AuthAction::Reindex { index: a },
+        SQLITE_ANALYZE => AuthAction::Analyze { table: a },
+        SQLITE_SELECT => AuthAction::Select,
+        SQLITE_READ => AuthAction::Read {
+            table: a,
+            column: b,
+        },
+        SQLITE_INSERT => AuthAction::Insert { table: a },
+        SQLITE_UPDATE => AuthAction::Update {
+            table: a,
+            column: b,
+        },
+        SQLITE_DELETE => AuthAction::Delete { table: a },
+        SQLITE_TRANSACTION => AuthAction::Transaction { operation: a },
+        SQLITE_SAVEPOINT => AuthAction::Savepoint {
+            operation: a,
+            name: b,
+        },
+        SQLITE_PRAGMA => AuthAction::Pragma { name: a, arg: arg2 },
+        SQLITE_FUNCTION => AuthAction::Function { name: b },
+        SQLITE_ATTACH => AuthAction::Attach { filename: a },
+        SQLITE_DETACH => AuthAction::Detach { database: a },
+        SQLITE_RECURSIVE => AuthAction::Recursive,
+        code => AuthAction::Unknown { code, arg1, arg2 },
+    }
+}
+
+/// Arguments that do not apply are NULL. SQLite only hands out UTF-8,
+/// anything else is treated as missing
+unsafe fn text<'a>(ptr: *const c_char) -> Option<&'a str> {
+    if ptr.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(ptr) }.to_str().ok()
+}
@@ -0,0 +1,191 @@
+use std::{
+    ffi::{CStr, c_int},
+    marker::PhantomData,
+    path::Path,
+    sync::Arc,
+    thread,
+};
+
+use libsqlite3_sys::{
+    SQLITE_DONE, SQLITE_OK, SQLITE_OPEN_CREATE, SQLITE_OPEN_READONLY, SQLITE_OPEN_READWRITE,
+    sqlite3, sqlite3_backup_finish, sqlite3_backup_init, sqlite3_backup_pagecount,
+    sqlite3_backup_remaining, sqlite3_backup_step, sqlite3_errstr,
+};
+
+use crate::{
+    errors::{BackupError, SqliteFailure, connection::SqliteOpenErrors},
+    internal_sqlite::{
+        lazy_connection::LazyConnection,
+        retry::is_busy,
+        threading::{Serialized, ThreadingMode},
+    },
+    utility::utils::get_sqlite_failiure,
+};
+
+/// How far a backup got, passed to the progress callback after every step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupProgress {
+    /// pages still to be copied
+    pub remaining: i32,
+    /// pages in the source database
+    pub page_count: i32,
+}
+
+/// The other side of a backup: a path to a database file or an open connection.
+/// A destination file is created if needed, a source file has to exist and is only read.
+pub trait BackupDb<'a> {
+    #[doc(hidden)]
+    fn backup_db(self, is_source: bool) -> Result<Handle<'a>, SqliteOpenErrors>;
+}
+
+/// Either borrowed from a connection or opened for the duration of the backup
+#[doc(hidden)]
+pub enum Handle<'a> {
+    Borrowed(*mut sqlite3, PhantomData<&'a ()>),
+    Opened(LazyConnection),
+}
+
+impl Handle<'_> {
+    fn db(&self) -> *mut sqlite3 {
+        match self {
+            Handle::Borrowed(db, _) => *db,
+            Handle::Opened(conn) => conn.db,
+        }
+    }
+}
+
+impl<'a, M: ThreadingMode> BackupDb<'a> for &'a LazyConnection<M> {
+    fn backup_db(self, _is_source: bool) -> Result<Handle<'a>, SqliteOpenErrors> {
+        Ok(Handle::Borrowed(self.db, PhantomData))
+    }
+}
+
+impl<'a, M: ThreadingMode> BackupDb<'a> for &'a Arc<LazyConnection<M>> {
+    fn backup_db(self, _is_source: bool) -> Result<Handle<'a>, SqliteOpenErrors> {
+        Ok(Handle::Borrowed(self.db, PhantomData))
+    }
+}
+
+impl<'a> BackupDb<'a> for &str {
+    fn backup_db(self, is_source: bool) -> Result<Handle<'a>, SqliteOpenErrors> {
+        // creating a missing source would restore an empty database over the live one
+        let flag = if is_source {
+            SQLITE_OPEN_READONLY
+        } else {
+            SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE
+        };
+        LazyConnection::<Serialized>::open_with_flags(self, flag).map(Handle::Opened)
+    }
+}
+
+impl<'a> BackupDb<'a> for &String {
+    fn backup_db(self, is_source: bool) -> Result<Handle<'a>, SqliteOpenErrors> {
+        self.as_str().backup_db(is_source)
+    }
+}
+
+impl<'a> BackupDb<'a> for &Path {
+    fn backup_db(self, is_source: bool) -> Result<Handle<'a>, SqliteOpenErrors> {
+        self.to_string_lossy().as_ref().backup_db(is_source)
+    }
+}
+
+impl<M: ThreadingMode> LazyConnection<M> {
+    /// Copies the main database into `dest` while it stays usable by everyone else (online backup).
+    ///
+    /// `pages_per_step` pages are copied at a time (all of them if negative) and `progress` is
+    /// called after every step. Between steps other connections may write to the database,
+    /// in which case SQLite restarts the backup on its own. A step that reports
+    /// `SQLITE_BUSY`/`SQLITE_LOCKED` is retried according to the connection's
+    /// [`RetryPolicy`](crate::internal_sqlite::retry::RetryPolicy), just like a transaction.
+    pub fn backup_to<'a>(
+        &self,
+        dest: impl BackupDb<'a>,
+        pages_per_step: c_int,
+        progress: impl FnMut(BackupProgress),
+    ) -> Result<(), BackupError> {
+        let dest = dest.backup_db(false)?;
+        unsafe { self.run_backup(dest.db(), self.db, pages_per_step, progress) }
+    }
+
+    /// Replaces the main database with the one in `source`. Counterpart of `backup_to`.
+    /// A `source` path that does not exist is an error and leaves the database untouched.
+    pub fn restore_from<'a>(
+        &self,
+        source: impl BackupDb<'a>,
+        pages_per_step: c_int,
+        progress: impl FnMut(BackupProgress),
+    ) -> Result<(), BackupError> {
+        let source = source.backup_db(true)?;
+        // statements prepared against the old content would only be re-prepared anyway
+        self.clear_statement_cache();
+        unsafe { self.run_backup(self.db, source.db(), pages_per_step, progress) }
+    }
+
+    /// Writes the whole database to the file at `path` in one go, e.g. to persist an in memory database
+    pub fn save_to_file(&self, path: impl AsRef<Path>) -> Result<(), BackupError> {
+        self.backup_to(path.as_ref(), -1, |_| {})
+    }
+
+    /// Replaces the database with the content of the file at `path`, e.g. to load a file into memory
+    pub fn load_from_file(&self, path: impl AsRef<Path>) -> Result<(), BackupError> {
+        self.restore_from(path.as_ref(), -1, |_| {})
+    }
+
+    unsafe fn run_backup(
+        &self,
+        dest: *mut sqlite3,
+        source: *mut sqlite3,
+        pages_per_step: c_int,
+        mut progress: impl FnMut(BackupProgress),
+    ) -> Result<(), BackupError> {
+        let main = c"main";
+        let backup = unsafe { sqlite3_backup_init(dest, main.as_ptr(), source, main.as_ptr()) };
+        if backup.is_null() {
+            let (code, error_msg) = unsafe { get_sqlite_failiure(dest) };
+            return Err(SqliteFailure { code, error_msg }.into());
+        }
+
+        let policy = self.retry_policy();
+        let mut attempts = 1;
+        let code = loop {
+            let code = unsafe { sqlite3_backup_step(backup, pages_per_step) };
+
+            if code == SQLITE_OK || code == SQLITE_DONE {
+                attempts = 1;
+                progress(unsafe {
+                    BackupProgress {
+                        remaining: sqlite3_backup_remaining(backup),
+                        page_count: sqlite3_backup_pagecount(backup),
+                    }
+                });
+                if code == SQLITE_DONE {
+                    break SQLITE_OK;
+                }
+            } else if is_busy(code) {
+                match policy.as_ref().and_then(|p| p.delay_after(attempts)) {
+                    Some(delay) => {
+                        thread::sleep(delay);
+                        attempts += 1;
+                    }
+                    None => break code,
+                }
+            } else {
+                break code;
+            }
+        };
+
+        // returns the error of a failed step, if any, and frees the backup
+        let finish_code = unsafe { sqlite3_backup_finish(backup) };
+        let code = if code == SQLITE_OK { finish_code } else { code };
+
+        if code == SQLITE_OK {
+            Ok(())
+        } else {
+            let error_msg = unsafe { CStr::from_ptr(sqlite3_errstr(code)) }
+                .to_string_lossy()
+                .into_owned();
+            Err(SqliteFailure { code, error_msg }.into())
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    ffi::{CString, c_int, c_void},
+    io::{self, Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    ptr,
+};
+
+use libsqlite3_sys::{
+    SQLITE_OK, sqlite3, sqlite3_blob, sqlite3_blob_bytes, sqlite3_blob_close, sqlite3_blob_open,
+    sqlite3_blob_read, sqlite3_blob_reopen, sqlite3_blob_write,
+};
+
+use crate::{
+    errors::{BlobError, SqliteFailure},
+    internal_sqlite::{lazy_connection::LazyConnection, threading::ThreadingMode},
+    utility::utils::{DbMutexGuard, get_sqlite_failiure},
+};
+
+/// A handle on a single BLOB value for reading and writing it in pieces,
+/// see [`LazyConnection::blob_open`].
+///
+/// The size of the BLOB is fixed: writes past its end fail, so reserve the space up front
+/// with [`ZeroBlob`](crate::traits::to_sql::ZeroBlob). If the row is modified or deleted
+/// by anything else than this handle, every further read and write fails with `SQLITE_ABORT`.
+pub struct Blob<'a> {
+    blob: *mut sqlite3_blob,
+    conn: *mut sqlite3,
+    len: i32,
+    pos: i32,
+    _marker: PhantomData<&'a ()>,
+}
+
+impl<M: ThreadingMode> LazyConnection<M> {
+    /// Opens the BLOB in `column` of the row `rowid` of `table` (in the main database).
+    /// Without `read_write` the handle is read only.
+    pub fn blob_open(
+        &self,
+        table: &str,
+        column: &str,
+        rowid: i64,
+        read_write: bool,
+    ) -> Result<Blob<'_>, BlobError> {
+        let c_table = name(table)?;
+        let c_column = name(column)?;
+        let mut blob = ptr::null_mut();
+
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        let code = unsafe {
+            sqlite3_blob_open(
+                self.db,
+                c"main".as_ptr(),
+                c_table.as_ptr(),
+                c_column.as_ptr(),
+                rowid,
+                read_write as c_int,
+                &mut blob,
+            )
+        };
+
+        if code != SQLITE_OK {
+            let (code, error_msg) = unsafe { get_sqlite_failiure(self.db) };
+            // sqlite may hand out a handle even on failure
+            unsafe { sqlite3_blob_close(blob) };
+            return Err(SqliteFailure { code, error_msg }.into());
+        }
+
+        Ok(Blob {
+            blob,
+            conn: self.db,
+            len: unsafe { sqlite3_blob_bytes(blob) },
+            pos: 0,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl Blob<'_> {
+    /// Size of the BLOB in bytes
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Points the handle at the same column of another row, which is faster than opening a new one.
+    /// The position goes back to the start.
+    pub fn reopen(&mut self, rowid: i64) -> Result<(), SqliteFailure> {
+        let _lock = unsafe { DbMutexGuard::lock(self.conn) };
+        let code = unsafe { sqlite3_blob_reopen(self.blob, rowid) };
+        if code != SQLITE_OK {
+            let (code, error_msg) = unsafe { get_sqlite_failiure(self.conn) };
+            return Err(SqliteFailure { code, error_msg });
+        }
+
+        self.len = unsafe { sqlite3_blob_bytes(self.blob) };
+        self.pos = 0;
+        Ok(())
+    }
+
+    fn failure(&self) -> io::Error {
+        let (code, error_msg) = unsafe { get_sqlite_failiure(self.conn) };
+        io::Error::other(SqliteFailure { code, error_msg })
+    }
+}
+
+impl Read for Blob<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min((self.len - self.pos) as usize) as c_int;
+        if n == 0 {
+            return Ok(0);
+        }
+
+        let _lock = unsafe { DbMutexGuard::lock(self.conn) };
+        let code =
+            unsafe { sqlite3_blob_read(self.blob, buf.as_mut_ptr() as *mut c_void, n, self.pos) };
+        if code != SQLITE_OK {
+            return Err(self.failure());
+        }
+
+        self.pos += n;
+        Ok(n as usize)
+    }
+}
+
+impl Write for Blob<'_> {
+    /// Writes at most up to the end of the BLOB, after that `Ok(0)` is returned
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min((self.len - self.pos) as usize) as c_int;
+        if n == 0 {
+            return Ok(0);
+        }
+
+        let _lock = unsafe { DbMutexGuard::lock(self.conn) };
+        let code =
+            unsafe { sqlite3_blob_write(self.blob, buf.as_ptr() as *const c_void, n, self.pos) };
+        if code != SQLITE_OK {
+            return Err(self.failure());
+        }
+
+        self.pos += n;
+        Ok(n as usize)
+    }
+
+    /// Writes go straight to the database, there is nothing to flush
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Blob<'_> {
+    /// Positions past the end of the BLOB are rejected since it cannot grow
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => i64::try_from(offset).unwrap_or(i64::MAX),
+            SeekFrom::End(offset) => self.len as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+
+        if target < 0 || target > self.len as i64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("seek to {target} outside of a BLOB of {} bytes", self.len),
+            ));
+        }
+
+        self.pos = target as i32;
+        Ok(self.pos as u64)
+    }
+}
+
+impl Drop for Blob<'_> {
+    fn drop(&mut self) {
+        unsafe { sqlite3_blob_close(self.blob) };
+    }
+}
+
+fn name(name: &str) -> Result<CString, BlobError> {
+    CString::new(name).map_err(|_| BlobError::EmbeddedNullInName {
+        name: name.to_string(),
+    })
+}
@@ -0,0 +1,113 @@
+use std::{
+    cmp::Ordering,
+    ffi::{CString, c_int, c_void},
+    panic::{AssertUnwindSafe, catch_unwind},
+    ptr, slice,
+};
+
+use libsqlite3_sys::{SQLITE_OK, SQLITE_UTF8, sqlite3_create_collation_v2};
+
+use crate::{
+    errors::{CollationError, SqliteFailure},
+    internal_sqlite::{
+        functions::drop_boxed, lazy_connection::LazyConnection, threading::ThreadingMode,
+    },
+    utility::utils::{DbMutexGuard, get_sqlite_failiure},
+};
+
+impl<M: ThreadingMode> LazyConnection<M> {
+    /// Makes `compare` usable as `COLLATE name` in queries and column definitions.
+    /// Registering the same name again replaces the collation.
+    ///
+    /// `compare` has to be a total order (consistent, antisymmetric and transitive),
+    /// otherwise sorting and indexes using it return garbage. Text that is not valid UTF-8
+    /// is passed with the invalid parts replaced by `U+FFFD`. A panic inside `compare`
+    /// cannot be reported to SQLite, the values then compare as equal.
+    pub fn create_collation<F>(&self, name: &str, compare: F) -> Result<(), CollationError>
+    where
+        F: Fn(&str, &str) -> Ordering + Send + 'static,
+    {
+        let c_name = collation_name(name)?;
+        let user_data = Box::into_raw(Box::new(compare));
+
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        // unlike create_function_v2, sqlite does not call the destructor when this fails
+        let code = unsafe {
+            sqlite3_create_collation_v2(
+                self.db,
+                c_name.as_ptr(),
+                SQLITE_UTF8,
+                user_data as *mut c_void,
+                Some(call_compare::<F>),
+                Some(drop_boxed::<F>),
+            )
+        };
+
+        if code != SQLITE_OK {
+            drop(unsafe { Box::from_raw(user_data) });
+        }
+        self.check_collation(code)
+    }
+
+    /// Removes the collation registered as `name`.
+    /// Statements that use it fail to prepare afterwards
+    pub fn remove_collation(&self, name: &str) -> Result<(), CollationError> {
+        let c_name = collation_name(name)?;
+
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        let code = unsafe {
+            sqlite3_create_collation_v2(
+                self.db,
+                c_name.as_ptr(),
+                SQLITE_UTF8,
+                ptr::null_mut(),
+                None,
+                None,
+            )
+        };
+
+        self.check_collation(code)
+    }
+
+    fn check_collation(&self, code: c_int) -> Result<(), CollationError> {
+        if code != SQLITE_OK {
+            let (code, error_msg) = unsafe { get_sqlite_failiure(self.db) };
+            return Err(SqliteFailure { code, error_msg }.into());
+        }
+        Ok(())
+    }
+}
+
+unsafe extern "C" fn call_compare<F>(
+    user_data: *mut c_void,
+    len_a: c_int,
+    a: *const c_void,
+    len_b: c_int,
+    b: *const c_void,
+) -> c_int
+where
+    F: Fn(&str, &str) -> Ordering,
+{
+    let compare = unsafe { &*(user_data as *const F) };
+    let a = String::from_utf8_lossy(unsafe { bytes(a, len_a) });
+    let b = String::from_utf8_lossy(unsafe { bytes(b, len_b) });
+
+    match catch_unwind(AssertUnwindSafe(|| compare(&a, &b))) {
+        Ok(ordering) => ordering as c_int,
+        Err(_) => 0,
+    }
+}
+
+/// The strings are not null terminated and may be a null pointer when empty
+unsafe fn bytes<'a>(data: *const c_void, len: c_int) -> &'a [u8] {
+    if data.is_null() || len <= 0 {
+        return &[];
+    }
+    unsafe { slice::from_raw_parts(data as *const u8, len as usize) }
+}
+
+fn collation_name(name: &str) -> Result<CString, CollationError> {
+    CString::new(name).map_err(|_| CollationError::EmbeddedNullInName {
+        name: name.to_string(),
+    })
+}
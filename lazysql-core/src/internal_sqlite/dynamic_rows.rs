@@ -1,43 +1,91 @@
 use libsqlite3_sys::{
-    SQLITE_BUSY, SQLITE_DONE, SQLITE_FLOAT, SQLITE_INTEGER, SQLITE_ROW, SQLITE_TEXT, sqlite3,
-    sqlite3_column_count, sqlite3_column_double, sqlite3_column_int64, sqlite3_column_text,
-    sqlite3_column_type, sqlite3_finalize, sqlite3_step, sqlite3_stmt,
+    SQLITE_BLOB, SQLITE_BUSY, SQLITE_DONE, SQLITE_FLOAT, SQLITE_INTEGER, SQLITE_ROW, SQLITE_TEXT,
+    sqlite3, sqlite3_column_blob, sqlite3_column_bytes, sqlite3_column_count,
+    sqlite3_column_double, sqlite3_column_int64, sqlite3_column_text, sqlite3_column_type,
+    sqlite3_finalize, sqlite3_stmt,
 };
-use std::ffi::CStr;
+use std::{ffi::CStr, sync::Mutex, time::Instant};
 
 use crate::{
-    errors::row::RowMapperError, traits::dynamic::Value, utility::utils::get_sqlite_failiure,
+    errors::row::RowMapperError,
+    internal_sqlite::{
+        hooks::DeliverOnDrop,
+        interrupt::{self, Interruption},
+        statement_cache::StatementCache,
+    },
+    traits::dynamic::Value,
+    utility::utils::{DbMutexGuard, get_sqlite_failiure},
 };
 
-pub struct DynamicRows {
+pub struct DynamicRows<'a> {
     stmt: *mut sqlite3_stmt,
     conn: *mut sqlite3,
     pub column_names: Vec<String>,
+    /// Where the statement goes back to once the rows are dropped. `None` means it is finalized
+    cache: Option<(&'a Mutex<StatementCache>, String)>,
+    deadline: Option<Instant>,
 }
 
-impl DynamicRows {
+impl<'a> DynamicRows<'a> {
     pub fn new(stmt: *mut sqlite3_stmt, conn: *mut sqlite3, column_names: Vec<String>) -> Self {
         DynamicRows {
             stmt,
             conn,
             column_names,
+            cache: None,
+            deadline: None,
         }
     }
+
+    /// Same as `new` but hands the statement back to `cache` instead of finalizing it
+    pub fn cached(
+        stmt: *mut sqlite3_stmt,
+        conn: *mut sqlite3,
+        column_names: Vec<String>,
+        cache: &'a Mutex<StatementCache>,
+        sql: &str,
+    ) -> Self {
+        DynamicRows {
+            stmt,
+            conn,
+            column_names,
+            cache: Some((cache, sql.to_string())),
+            deadline: None,
+        }
+    }
+
+    /// Reading rows fails with `Timeout` after `deadline`
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
 }
 
-impl Drop for DynamicRows {
+impl Drop for DynamicRows<'_> {
     fn drop(&mut self) {
-        unsafe {
-            sqlite3_finalize(self.stmt);
+        match &self.cache {
+            Some((cache, sql)) => {
+                let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+                unsafe { cache.put(sql, self.stmt) };
+            }
+            None => unsafe {
+                sqlite3_finalize(self.stmt);
+            },
         }
     }
 }
 
-impl Iterator for DynamicRows {
+impl Iterator for DynamicRows<'_> {
     type Item = Result<Vec<Value>, RowMapperError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let result_code = unsafe { sqlite3_step(self.stmt) };
+        let _lock = unsafe { DbMutexGuard::lock(self.conn) };
+        let _deliver = DeliverOnDrop(self.conn);
+        let result_code = match unsafe { interrupt::step(self.conn, self.stmt, self.deadline) } {
+            Ok(code) => code,
+            Err(Interruption::Interrupted) => return Some(Err(RowMapperError::Interrupted)),
+            Err(Interruption::Timeout) => return Some(Err(RowMapperError::Timeout)),
+        };
 
         if result_code == SQLITE_ROW {
             let count = unsafe { sqlite3_column_count(self.stmt) };
@@ -59,7 +107,19 @@ impl Iterator for DynamicRows {
                                 Value::Text(s)
                             }
                         }
-                        // TODO BLOB
+                        SQLITE_BLOB => {
+                            let ptr = sqlite3_column_blob(self.stmt, i);
+                            let len = sqlite3_column_bytes(self.stmt, i);
+                            // zero-length BLOBs come back as a NULL pointer
+                            if ptr.is_null() || len <= 0 {
+                                Value::Blob(Vec::new())
+                            } else {
+                                Value::Blob(
+                                    std::slice::from_raw_parts(ptr as *const u8, len as usize)
+                                        .to_vec(),
+                                )
+                            }
+                        }
                         _ => Value::Null,
                     }
                 };
@@ -77,7 +137,7 @@ impl Iterator for DynamicRows {
     }
 }
 
-impl DynamicRows {
+impl DynamicRows<'_> {
     /// Returns the first row if available, or `None` if the query returned no results.
     pub fn first(mut self) -> Result<Option<Vec<Value>>, RowMapperError> {
         self.next().transpose()
@@ -0,0 +1,133 @@
+use std::{
+    ffi::{CStr, CString, c_char, c_int, c_void},
+    path::Path,
+    ptr,
+};
+
+use libsqlite3_sys::{
+    SQLITE_DBCONFIG_ENABLE_LOAD_EXTENSION, SQLITE_OK, sqlite3, sqlite3_db_config, sqlite3_free,
+    sqlite3_load_extension,
+};
+
+use crate::{
+    errors::{ExtensionError, SqliteFailure},
+    internal_sqlite::{lazy_connection::LazyConnection, threading::ThreadingMode},
+    utility::utils::{DbMutexGuard, get_sqlite_failiure},
+};
+
+impl<M: ThreadingMode> LazyConnection<M> {
+    /// Loads the SQLite extension at `path` (the platform suffix such as `.so` may be left out)
+    /// and calls its `entry_point`, by default derived from the file name
+    /// (`sqlite3_spellfix_init` for `spellfix.so`).
+    ///
+    /// Loading is only enabled (`SQLITE_DBCONFIG_ENABLE_LOAD_EXTENSION`) for the duration of the
+    /// call, the `load_extension()` sql function stays disabled throughout.
+    ///
+    /// # Safety
+    /// The extension is native code running inside the process with the same privileges,
+    /// nothing it does can be checked. Only load files you trust.
+    pub unsafe fn load_extension(
+        &self,
+        path: impl AsRef<Path>,
+        entry_point: Option<&str>,
+    ) -> Result<(), ExtensionError> {
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        unsafe { load(self.db, path.as_ref(), entry_point) }
+    }
+}
+
+/// Turns on `SQLITE_DBCONFIG_ENABLE_LOAD_EXTENSION` until dropped, then puts back the
+/// previous state
+struct LoadExtensionGuard {
+    db: *mut sqlite3,
+    was_enabled: bool,
+}
+
+impl LoadExtensionGuard {
+    unsafe fn enable(db: *mut sqlite3) -> Result<Self, SqliteFailure> {
+        let was_enabled = unsafe { set_load_extension(db, -1) }?;
+        if !was_enabled {
+            unsafe { set_load_extension(db, 1) }?;
+        }
+        Ok(LoadExtensionGuard { db, was_enabled })
+    }
+}
+
+impl Drop for LoadExtensionGuard {
+    fn drop(&mut self) {
+        if !self.was_enabled {
+            let _ = unsafe { set_load_extension(self.db, 0) };
+        }
+    }
+}
+
+/// `value` of -1 only reads the switch. Returns its state afterwards
+unsafe fn set_load_extension(db: *mut sqlite3, value: c_int) -> Result<bool, SqliteFailure> {
+    let mut current: c_int = 0;
+    let code = unsafe {
+        sqlite3_db_config(
+            db,
+            SQLITE_DBCONFIG_ENABLE_LOAD_EXTENSION,
+            value,
+            &mut current as *mut c_int,
+        )
+    };
+
+    if code != SQLITE_OK {
+        let (code, error_msg) = unsafe { get_sqlite_failiure(db) };
+        return Err(SqliteFailure { code, error_msg });
+    }
+    Ok(current != 0)
+}
+
+/// Loads an extension into `db`, which is also how `lazy_sql` prepares its validation database.
+/// The connection mutex has to be held
+pub(crate) unsafe fn load(
+    db: *mut sqlite3,
+    path: &Path,
+    entry_point: Option<&str>,
+) -> Result<(), ExtensionError> {
+    let c_path = path
+        .to_str()
+        .and_then(|p| CString::new(p).ok())
+        .ok_or_else(|| ExtensionError::InvalidPath {
+            path: path.to_path_buf(),
+        })?;
+    let c_entry_point = entry_point
+        .map(|name| {
+            CString::new(name).map_err(|_| ExtensionError::EmbeddedNullInEntryPoint {
+                entry_point: name.to_string(),
+            })
+        })
+        .transpose()?;
+
+    let _guard = unsafe { LoadExtensionGuard::enable(db) }?;
+    let mut err_msg: *mut c_char = ptr::null_mut();
+    let code = unsafe {
+        sqlite3_load_extension(
+            db,
+            c_path.as_ptr(),
+            c_entry_point
+                .as_ref()
+                .map_or(ptr::null(), |name| name.as_ptr()),
+            &mut err_msg,
+        )
+    };
+
+    if code != SQLITE_OK {
+        let error_msg = if err_msg.is_null() {
+            unsafe { get_sqlite_failiure(db) }.1
+        } else {
+            let msg = unsafe { CStr::from_ptr(err_msg) }
+                .to_string_lossy()
+                .into_owned();
+            unsafe { sqlite3_free(err_msg as *mut c_void) };
+            msg
+        };
+        return Err(ExtensionError::Load {
+            path: path.to_path_buf(),
+            error_msg,
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,436 @@
+use std::{
+    any::Any,
+    ffi::{CString, c_int, c_void},
+    fmt::Display,
+    mem,
+    ops::BitOr,
+    panic::{AssertUnwindSafe, catch_unwind},
+    ptr, slice, thread,
+};
+
+use libsqlite3_sys::{
+    SQLITE_DETERMINISTIC, SQLITE_DIRECTONLY, SQLITE_INNOCUOUS, SQLITE_OK, SQLITE_UTF8, sqlite3,
+    sqlite3_aggregate_context, sqlite3_context, sqlite3_create_function_v2,
+    sqlite3_create_window_function, sqlite3_result_error, sqlite3_result_error_nomem,
+    sqlite3_user_data, sqlite3_value,
+};
+
+use crate::{
+    errors::{FunctionError, SqliteFailure},
+    internal_sqlite::{lazy_connection::LazyConnection, threading::ThreadingMode},
+    traits::{dynamic::Value, from_sql::FromSqlValue, to_sql::ToSqlResult},
+    utility::utils::{DbMutexGuard, get_sqlite_failiure},
+};
+
+/// Properties of a user defined function, combined with `|`
+/// (e.g. `FunctionFlags::DETERMINISTIC | FunctionFlags::INNOCUOUS`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FunctionFlags(c_int);
+
+impl FunctionFlags {
+    pub const NONE: FunctionFlags = FunctionFlags(0);
+    /// Always returns the same result for the same arguments, which lets SQLite
+    /// factor calls out of loops and use the function in indexes and generated columns
+    pub const DETERMINISTIC: FunctionFlags = FunctionFlags(SQLITE_DETERMINISTIC);
+    /// Has no side effects and cannot leak information, so it may be used from
+    /// triggers and views of an untrusted schema (see `OpenOptions::trusted_schema`)
+    pub const INNOCUOUS: FunctionFlags = FunctionFlags(SQLITE_INNOCUOUS);
+    /// Can only be called from top level SQL, never from triggers, views or the schema
+    pub const DIRECT_ONLY: FunctionFlags = FunctionFlags(SQLITE_DIRECTONLY);
+
+    pub fn contains(self, other: FunctionFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub(crate) fn bits(self) -> c_int {
+        self.0
+    }
+}
+
+impl BitOr for FunctionFlags {
+    type Output = FunctionFlags;
+
+    fn bitor(self, rhs: FunctionFlags) -> FunctionFlags {
+        FunctionFlags(self.0 | rhs.0)
+    }
+}
+
+/// What the methods of [`Aggregate`] and [`WindowAggregate`] return. Any error converts into
+/// it with `?`, and so do strings: `Err("negative percentile".into())`
+pub type FunctionResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// A user defined aggregate function such as `median(x)`, registered with
+/// `LazyConnection::create_aggregate_function`.
+///
+/// Every group gets its own [`State`](Aggregate::State), created by `init` before the first row.
+/// An `Err` or a panic in any method fails the query with that message.
+pub trait Aggregate {
+    type State;
+    type Output: ToSqlResult;
+
+    fn init(&self) -> Self::State;
+
+    /// Called for every row of the group
+    fn step(&self, state: &mut Self::State, args: &FunctionArgs) -> FunctionResult<()>;
+
+    /// Called once after the last row. For an empty group `state` comes straight from `init`
+    fn finalize(&self, state: Self::State) -> FunctionResult<Self::Output>;
+}
+
+/// An [`Aggregate`] that can also be used as a window function (`median(x) OVER (...)`),
+/// registered with `LazyConnection::create_window_function`.
+///
+/// As the window moves, rows entering it are passed to `step` and rows leaving it to `inverse`.
+pub trait WindowAggregate: Aggregate {
+    /// Undoes the `step` of a row that left the window
+    fn inverse(&self, state: &mut Self::State, args: &FunctionArgs) -> FunctionResult<()>;
+
+    /// The result for the current window, which keeps being updated afterwards
+    fn value(&self, state: &Self::State) -> FunctionResult<Self::Output>;
+}
+
+/// The arguments of one call of a user defined function
+pub struct FunctionArgs<'a> {
+    values: &'a [*mut sqlite3_value],
+}
+
+impl<'a> FunctionArgs<'a> {
+    /// # Safety
+    /// `argv` must point to `argc` valid values, as handed over by SQLite
+    pub(crate) unsafe fn new(argc: c_int, argv: *mut *mut sqlite3_value) -> Self {
+        let values = if argc <= 0 || argv.is_null() {
+            &[][..]
+        } else {
+            unsafe { slice::from_raw_parts(argv, argc as usize) }
+        };
+        FunctionArgs { values }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Argument `index` (starting at 0) converted to `T` the way SQLite converts values,
+    /// so e.g. a TEXT read as `i64` is parsed and NULL becomes 0.
+    /// Use `Option<T>` to tell NULL apart.
+    ///
+    /// # Panics
+    /// If there is no argument `index`. The panic is reported as an SQL error.
+    pub fn get<T: FromSqlValue>(&self, index: usize) -> T {
+        unsafe { T::from_sql_value(self.values[index]) }
+    }
+
+    /// Argument `index` with its storage class
+    pub fn value(&self, index: usize) -> Value {
+        self.get(index)
+    }
+
+    /// All arguments with their storage class
+    pub fn values(&self) -> Vec<Value> {
+        (0..self.len()).map(|i| self.value(i)).collect()
+    }
+}
+
+impl<M: ThreadingMode> LazyConnection<M> {
+    /// Makes `function` callable from SQL as `name`, with `n_args` arguments (`-1` for any number).
+    /// Registering the same name and number of arguments again replaces the function.
+    ///
+    /// The returned value is converted through [`ToSqlResult`]. An `Err` or a panic inside
+    /// `function` fails the statement calling it with that message.
+    pub fn create_scalar_function<F, R, E>(
+        &self,
+        name: &str,
+        n_args: c_int,
+        flags: FunctionFlags,
+        function: F,
+    ) -> Result<(), FunctionError>
+    where
+        F: Fn(&FunctionArgs) -> Result<R, E> + Send + 'static,
+        R: ToSqlResult,
+        E: Display,
+    {
+        let c_name = function_name(name)?;
+        let user_data = Box::into_raw(Box::new(function));
+
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        // sqlite calls drop_boxed on user_data even when this fails
+        let code = unsafe {
+            sqlite3_create_function_v2(
+                self.db,
+                c_name.as_ptr(),
+                n_args,
+                SQLITE_UTF8 | flags.bits(),
+                user_data as *mut c_void,
+                Some(call_scalar::<F, R, E>),
+                None,
+                None,
+                Some(drop_boxed::<F>),
+            )
+        };
+
+        check(self.db, code)
+    }
+
+    /// Makes `aggregate` callable from SQL as `name`, with `n_args` arguments (`-1` for any number).
+    /// It can be used with `GROUP BY` like any built-in aggregate.
+    pub fn create_aggregate_function<A>(
+        &self,
+        name: &str,
+        n_args: c_int,
+        flags: FunctionFlags,
+        aggregate: A,
+    ) -> Result<(), FunctionError>
+    where
+        A: Aggregate + Send + 'static,
+    {
+        unsafe { self.create_window_function_raw(name, n_args, flags, aggregate, None, None) }
+    }
+
+    /// Same as [`create_aggregate_function`](Self::create_aggregate_function) but the function
+    /// can be used as a window function (`OVER (...)`) as well
+    pub fn create_window_function<A>(
+        &self,
+        name: &str,
+        n_args: c_int,
+        flags: FunctionFlags,
+        aggregate: A,
+    ) -> Result<(), FunctionError>
+    where
+        A: WindowAggregate + Send + 'static,
+    {
+        unsafe {
+            self.create_window_function_raw(
+                name,
+                n_args,
+                flags,
+                aggregate,
+                Some(window_value::<A>),
+                Some(window_inverse::<A>),
+            )
+        }
+    }
+
+    unsafe fn create_window_function_raw<A>(
+        &self,
+        name: &str,
+        n_args: c_int,
+        flags: FunctionFlags,
+        aggregate: A,
+        value: Option<unsafe extern "C" fn(*mut sqlite3_context)>,
+        inverse: Option<unsafe extern "C" fn(*mut sqlite3_context, c_int, *mut *mut sqlite3_value)>,
+    ) -> Result<(), FunctionError>
+    where
+        A: Aggregate + Send + 'static,
+    {
+        let c_name = function_name(name)?;
+        let user_data = Box::into_raw(Box::new(aggregate));
+
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        // sqlite calls drop_boxed on user_data even when this fails
+        let code = unsafe {
+            sqlite3_create_window_function(
+                self.db,
+                c_name.as_ptr(),
+                n_args,
+                SQLITE_UTF8 | flags.bits(),
+                user_data as *mut c_void,
+                Some(aggregate_step::<A>),
+                Some(aggregate_final::<A>),
+                value,
+                inverse,
+                Some(drop_boxed::<A>),
+            )
+        };
+
+        check(self.db, code)
+    }
+
+    /// Removes the function registered as `name` with `n_args` arguments
+    pub fn remove_function(&self, name: &str, n_args: c_int) -> Result<(), FunctionError> {
+        let c_name = function_name(name)?;
+
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        let code = unsafe {
+            sqlite3_create_function_v2(
+                self.db,
+                c_name.as_ptr(),
+                n_args,
+                SQLITE_UTF8,
+                ptr::null_mut(),
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+
+        check(self.db, code)
+    }
+}
+
+unsafe extern "C" fn call_scalar<F, R, E>(
+    ctx: *mut sqlite3_context,
+    argc: c_int,
+    argv: *mut *mut sqlite3_value,
+) where
+    F: Fn(&FunctionArgs) -> Result<R, E>,
+    R: ToSqlResult,
+    E: Display,
+{
+    let function = unsafe { &*(sqlite3_user_data(ctx) as *const F) };
+    let args = unsafe { FunctionArgs::new(argc, argv) };
+
+    // unwinding into C is undefined behaviour
+    let result = catch_unwind(AssertUnwindSafe(|| function(&args)));
+    unsafe { set_result(ctx, result) };
+}
+
+/// The slot in the aggregate context of the current group holding its boxed state.
+/// With `create` it is allocated (zeroed, i.e. without a state) on the first call,
+/// otherwise it is NULL if the group never got one.
+unsafe fn state_slot<A: Aggregate>(ctx: *mut sqlite3_context, create: bool) -> *mut *mut A::State {
+    let size = if create {
+        mem::size_of::<*mut A::State>() as c_int
+    } else {
+        0
+    };
+    unsafe { sqlite3_aggregate_context(ctx, size) as *mut *mut A::State }
+}
+
+unsafe extern "C" fn aggregate_step<A: Aggregate>(
+    ctx: *mut sqlite3_context,
+    argc: c_int,
+    argv: *mut *mut sqlite3_value,
+) {
+    unsafe {
+        update_state::<A>(ctx, argc, argv, |aggregate, state, args| {
+            aggregate.step(state, args)
+        })
+    }
+}
+
+unsafe extern "C" fn window_inverse<A: WindowAggregate>(
+    ctx: *mut sqlite3_context,
+    argc: c_int,
+    argv: *mut *mut sqlite3_value,
+) {
+    unsafe {
+        update_state::<A>(ctx, argc, argv, |aggregate, state, args| {
+            aggregate.inverse(state, args)
+        })
+    }
+}
+
+unsafe fn update_state<A: Aggregate>(
+    ctx: *mut sqlite3_context,
+    argc: c_int,
+    argv: *mut *mut sqlite3_value,
+    update: impl FnOnce(&A, &mut A::State, &FunctionArgs) -> FunctionResult<()>,
+) {
+    let aggregate = unsafe { &*(sqlite3_user_data(ctx) as *const A) };
+    let args = unsafe { FunctionArgs::new(argc, argv) };
+
+    let slot = unsafe { state_slot::<A>(ctx, true) };
+    if slot.is_null() {
+        unsafe { sqlite3_result_error_nomem(ctx) };
+        return;
+    }
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        if unsafe { (*slot).is_null() } {
+            unsafe { *slot = Box::into_raw(Box::new(aggregate.init())) };
+        }
+        update(aggregate, unsafe { &mut **slot }, &args)
+    }));
+
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => unsafe { result_error(ctx, &err.to_string()) },
+        Err(panic) => unsafe { result_error(ctx, &panic_message(panic)) },
+    }
+}
+
+/// Also called when the query is aborted, so the state is always freed here
+unsafe extern "C" fn aggregate_final<A: Aggregate>(ctx: *mut sqlite3_context) {
+    let aggregate = unsafe { &*(sqlite3_user_data(ctx) as *const A) };
+
+    let slot = unsafe { state_slot::<A>(ctx, false) };
+    let state = if slot.is_null() || unsafe { (*slot).is_null() } {
+        None
+    } else {
+        let state = unsafe { Box::from_raw(*slot) };
+        unsafe { *slot = ptr::null_mut() };
+        Some(*state)
+    };
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        let state = state.unwrap_or_else(|| aggregate.init());
+        aggregate.finalize(state)
+    }));
+
+    unsafe { set_result(ctx, result) };
+}
+
+unsafe extern "C" fn window_value<A: WindowAggregate>(ctx: *mut sqlite3_context) {
+    let aggregate = unsafe { &*(sqlite3_user_data(ctx) as *const A) };
+
+    let slot = unsafe { state_slot::<A>(ctx, false) };
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        if slot.is_null() || unsafe { (*slot).is_null() } {
+            aggregate.value(&aggregate.init())
+        } else {
+            aggregate.value(unsafe { &**slot })
+        }
+    }));
+
+    unsafe { set_result(ctx, result) };
+}
+
+/// Reports the outcome of a (panic catching) call of a user defined function to SQLite
+unsafe fn set_result<R: ToSqlResult, E: Display>(
+    ctx: *mut sqlite3_context,
+    result: thread::Result<Result<R, E>>,
+) {
+    match result {
+        Ok(Ok(value)) => unsafe { value.result_to(ctx) },
+        Ok(Err(err)) => unsafe { result_error(ctx, &err.to_string()) },
+        Err(panic) => unsafe { result_error(ctx, &panic_message(panic)) },
+    }
+}
+
+pub(crate) unsafe extern "C" fn drop_boxed<T>(data: *mut c_void) {
+    drop(unsafe { Box::from_raw(data as *mut T) });
+}
+
+pub(crate) unsafe fn result_error(ctx: *mut sqlite3_context, msg: &str) {
+    unsafe { sqlite3_result_error(ctx, msg.as_ptr() as *const _, msg.len() as c_int) };
+}
+
+pub(crate) fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    let msg = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    format!("panicked: {msg}")
+}
+
+pub(crate) fn function_name(name: &str) -> Result<CString, FunctionError> {
+    CString::new(name).map_err(|_| FunctionError::EmbeddedNullInName {
+        name: name.to_string(),
+    })
+}
+
+/// Turns the `code` of a registration into an error. The connection mutex must still be
+/// held since that call so the error message is the right one
+pub(crate) fn check(db: *mut sqlite3, code: c_int) -> Result<(), FunctionError> {
+    if code != SQLITE_OK {
+        let (code, error_msg) = unsafe { get_sqlite_failiure(db) };
+        return Err(SqliteFailure { code, error_msg }.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,366 @@
+use std::{
+    ffi::{CStr, c_char, c_int, c_void},
+    mem,
+    panic::{AssertUnwindSafe, catch_unwind},
+    ptr,
+    sync::{Mutex, MutexGuard},
+};
+
+use libsqlite3_sys::{
+    SQLITE_DELETE, SQLITE_INSERT, SQLITE_UPDATE, sqlite3, sqlite3_changes, sqlite3_commit_hook,
+    sqlite3_get_autocommit, sqlite3_get_clientdata, sqlite3_rollback_hook, sqlite3_set_clientdata,
+    sqlite3_update_hook,
+};
+
+use crate::{
+    internal_sqlite::{lazy_connection::LazyConnection, threading::ThreadingMode},
+    utility::utils::DbMutexGuard,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// A row inserted, updated or deleted on the connection
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChangeEvent {
+    pub op: ChangeOp,
+    /// `main`, `temp` or the name of an attached database
+    pub database: String,
+    pub table: String,
+    pub rowid: i64,
+}
+
+/// Identifies a callback registered with `on_change`, `on_commit` or `on_rollback`,
+/// see [`LazyConnection::unsubscribe`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Subscription(u64);
+
+type ChangeCallback = Box<dyn FnMut(&ChangeEvent) + Send>;
+type BatchCallback = Box<dyn FnMut(&[ChangeEvent]) + Send>;
+
+/// Client data key under which a connection's [`ChangeHooks`] can be found from its `db`
+const CLIENT_DATA_KEY: &CStr = c"lazysql.change_hooks";
+
+/// State behind the update, commit and rollback hooks of a connection.
+/// Installed on the first subscription and kept until the connection is closed.
+#[derive(Default)]
+pub(crate) struct ChangeHooks {
+    state: Mutex<HookState>,
+}
+
+#[derive(Default)]
+struct HookState {
+    next_id: u64,
+    on_change: Vec<(u64, ChangeCallback)>,
+    on_commit: Vec<(u64, BatchCallback)>,
+    on_rollback: Vec<(u64, BatchCallback)>,
+    /// changes of the transaction that is still open
+    pending: Vec<ChangeEvent>,
+    /// changes handed to the commit hook, the `COMMIT` may still fail
+    committing: Vec<ChangeEvent>,
+    /// finished transactions waiting to be delivered, true if they were committed
+    outbox: Vec<(bool, Vec<ChangeEvent>)>,
+    /// the commit and rollback callbacks are running (and taken out of the state)
+    delivering: bool,
+}
+
+impl ChangeHooks {
+    fn state(&self) -> MutexGuard<'_, HookState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Removes the hooks from `db`, which must happen before the connection is closed
+    pub(crate) unsafe fn uninstall(db: *mut sqlite3) {
+        unsafe {
+            sqlite3_update_hook(db, None, ptr::null_mut());
+            sqlite3_commit_hook(db, None, ptr::null_mut());
+            sqlite3_rollback_hook(db, None, ptr::null_mut());
+            sqlite3_set_clientdata(db, CLIENT_DATA_KEY.as_ptr(), ptr::null_mut(), None);
+        }
+    }
+}
+
+/// The hooks installed on `db`, if anything subscribed
+unsafe fn installed<'a>(db: *mut sqlite3) -> Option<&'a ChangeHooks> {
+    let data = unsafe { sqlite3_get_clientdata(db, CLIENT_DATA_KEY.as_ptr()) };
+    unsafe { (data as *const ChangeHooks).as_ref() }
+}
+
+/// Number of pending changes before a statement is stepped, passed to [`settle`] afterwards
+///
+/// # Safety
+/// `db` must be an open connection and the connection mutex has to be held
+pub(crate) unsafe fn change_mark(db: *mut sqlite3) -> usize {
+    unsafe { installed(db) }.map_or(0, |hooks| hooks.state().pending.len())
+}
+
+/// Decides what happened to the changes the commit hook saw, once the statement that
+/// committed finished. `done` tells whether it ended in `SQLITE_DONE`, i.e. whether the
+/// `COMMIT` went through. They are delivered by [`DeliverOnDrop`].
+///
+/// A statement that failed inside a transaction undid its own changes, so the ones recorded
+/// after `mark` (taken with [`change_mark`] before the step) are dropped again.
+///
+/// # Safety
+/// `db` must be an open connection and the connection mutex has to be held
+pub(crate) unsafe fn settle(db: *mut sqlite3, done: bool, mark: usize) {
+    let Some(hooks) = (unsafe { installed(db) }) else {
+        return;
+    };
+
+    let mut state = hooks.state();
+    let committing = mem::take(&mut state.committing);
+    if !committing.is_empty() {
+        if done {
+            state.outbox.push((true, committing));
+        } else if unsafe { sqlite3_get_autocommit(db) } == 0 {
+            // e.g. SQLITE_BUSY on COMMIT, the transaction is still open and can be committed later
+            let pending = mem::replace(&mut state.pending, committing);
+            state.pending.extend(pending);
+        } else {
+            state.outbox.push((false, committing));
+        }
+    }
+
+    // `OR FAIL` keeps what the statement changed before it failed, which shows in
+    // sqlite3_changes. Otherwise nothing of it is left
+    if !done && unsafe { sqlite3_get_autocommit(db) } == 0 && unsafe { sqlite3_changes(db) } == 0 {
+        state.pending.truncate(mark);
+    }
+}
+
+/// Runs the commit and rollback callbacks for the transactions that finished on the connection
+/// when dropped. Taken right after the connection mutex by everything that steps statements,
+/// so the callbacks only run once the error of the step was read (they may use the connection
+/// and replace it).
+pub(crate) struct DeliverOnDrop(pub(crate) *mut sqlite3);
+
+impl Drop for DeliverOnDrop {
+    fn drop(&mut self) {
+        if let Some(hooks) = unsafe { installed(self.0) } {
+            hooks.deliver_finished();
+        }
+    }
+}
+
+impl ChangeHooks {
+    fn deliver_finished(&self) {
+        let mut state = self.state();
+        // a callback that uses the connection ends up here again, the outer call delivers for it
+        if state.delivering {
+            return;
+        }
+        state.delivering = true;
+        loop {
+            let outbox = mem::take(&mut state.outbox);
+            if outbox.is_empty() {
+                break;
+            }
+
+            // the lock is released so that the callbacks can use the connection
+            let mut on_commit = mem::take(&mut state.on_commit);
+            let mut on_rollback = mem::take(&mut state.on_rollback);
+            drop(state);
+            for (committed, changes) in outbox {
+                let callbacks = if committed {
+                    &mut on_commit
+                } else {
+                    &mut on_rollback
+                };
+                HookState::deliver(callbacks, &changes);
+            }
+
+            state = self.state();
+            // keep the order, callbacks registered in the meantime come last
+            on_commit.append(&mut state.on_commit);
+            on_rollback.append(&mut state.on_rollback);
+            state.on_commit = on_commit;
+            state.on_rollback = on_rollback;
+        }
+        state.delivering = false;
+    }
+}
+
+impl<M: ThreadingMode> LazyConnection<M> {
+    /// Calls `callback` for every row inserted, updated or deleted, right when it happens.
+    /// Inside a transaction this includes changes that are rolled back later,
+    /// use [`on_commit`](Self::on_commit) to only see the ones that persist.
+    ///
+    /// The callbacks run inside SQLite while the statement is executing. They must not use
+    /// this connection (not even to register or remove callbacks), hand the events over
+    /// to somewhere else instead, e.g. through a channel. A panic in a callback is ignored.
+    ///
+    /// Like `sqlite3_update_hook` this misses changes to `WITHOUT ROWID` tables and rows removed
+    /// by `DELETE FROM table` without a `WHERE` clause, which SQLite implements by dropping
+    /// all rows at once.
+    pub fn on_change<F>(&self, callback: F) -> Subscription
+    where
+        F: FnMut(&ChangeEvent) + Send + 'static,
+    {
+        let mut state = self.change_hooks().state();
+        let id = state.next_id();
+        state.on_change.push((id, Box::new(callback)));
+        Subscription(id)
+    }
+
+    /// Calls `callback` with every change of a transaction once it is committed
+    /// (statements outside of a transaction commit on their own). Commits without changes
+    /// are skipped. On `ROLLBACK` the buffered changes are discarded instead, and so are the
+    /// changes since a savepoint of a [`Transaction`](super::transaction::Transaction)
+    /// that is rolled back.
+    ///
+    /// The callback runs once the `COMMIT` went through, after the statement that committed
+    /// finished, so it may use the connection (but not remove callbacks). If the commit fails
+    /// and SQLite rolls back, the changes go to [`on_rollback`](Self::on_rollback) instead.
+    /// A statement that fails inside a transaction undoes its own changes, so they are removed
+    /// from the buffer as well.
+    pub fn on_commit<F>(&self, callback: F) -> Subscription
+    where
+        F: FnMut(&[ChangeEvent]) + Send + 'static,
+    {
+        let mut state = self.change_hooks().state();
+        let id = state.next_id();
+        state.on_commit.push((id, Box::new(callback)));
+        Subscription(id)
+    }
+
+    /// Calls `callback` with the discarded changes whenever a transaction is rolled back,
+    /// including by a failed `COMMIT`. Runs after the statement like [`on_commit`](Self::on_commit)
+    pub fn on_rollback<F>(&self, callback: F) -> Subscription
+    where
+        F: FnMut(&[ChangeEvent]) + Send + 'static,
+    {
+        let mut state = self.change_hooks().state();
+        let id = state.next_id();
+        state.on_rollback.push((id, Box::new(callback)));
+        Subscription(id)
+    }
+
+    /// Removes a callback. Returns false if it was already removed
+    pub fn unsubscribe(&self, subscription: Subscription) -> bool {
+        let Some(hooks) = self.change_hooks.get() else {
+            return false;
+        };
+
+        let mut state = hooks.state();
+        let before = state.len();
+        state.on_change.retain(|(id, _)| *id != subscription.0);
+        state.on_commit.retain(|(id, _)| *id != subscription.0);
+        state.on_rollback.retain(|(id, _)| *id != subscription.0);
+        state.len() != before
+    }
+
+    /// Changes of the open transaction that have not been committed yet.
+    /// Always empty until something subscribed
+    pub fn pending_changes(&self) -> Vec<ChangeEvent> {
+        self.change_hooks
+            .get()
+            .map(|hooks| hooks.state().pending.clone())
+            .unwrap_or_default()
+    }
+
+    /// Number of pending changes, taken when a savepoint starts
+    pub(crate) fn pending_change_mark(&self) -> usize {
+        self.change_hooks
+            .get()
+            .map_or(0, |hooks| hooks.state().pending.len())
+    }
+
+    /// Drops the pending changes made after `mark`, once their savepoint was rolled back
+    pub(crate) fn discard_changes_since(&self, mark: usize) {
+        if let Some(hooks) = self.change_hooks.get() {
+            hooks.state().pending.truncate(mark);
+        }
+    }
+
+    fn change_hooks(&self) -> &ChangeHooks {
+        self.change_hooks.get_or_init(|| {
+            let hooks = Box::<ChangeHooks>::default();
+            let data = &*hooks as *const ChangeHooks as *mut c_void;
+
+            let _lock = unsafe { DbMutexGuard::lock(self.db) };
+            unsafe {
+                sqlite3_set_clientdata(self.db, CLIENT_DATA_KEY.as_ptr(), data, None);
+                sqlite3_update_hook(self.db, Some(update_hook), data);
+                sqlite3_commit_hook(self.db, Some(commit_hook), data);
+                sqlite3_rollback_hook(self.db, Some(rollback_hook), data);
+            }
+            hooks
+        })
+    }
+}
+
+impl HookState {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn len(&self) -> usize {
+        self.on_change.len() + self.on_commit.len() + self.on_rollback.len()
+    }
+
+    fn deliver(callbacks: &mut [(u64, BatchCallback)], changes: &[ChangeEvent]) {
+        for (_, callback) in callbacks {
+            let _ = catch_unwind(AssertUnwindSafe(|| callback(changes)));
+        }
+    }
+}
+
+unsafe extern "C" fn update_hook(
+    data: *mut c_void,
+    op: c_int,
+    database: *const c_char,
+    table: *const c_char,
+    rowid: i64,
+) {
+    let op = match op {
+        SQLITE_INSERT => ChangeOp::Insert,
+        SQLITE_UPDATE => ChangeOp::Update,
+        SQLITE_DELETE => ChangeOp::Delete,
+        _ => return,
+    };
+    let event = ChangeEvent {
+        op,
+        database: unsafe { CStr::from_ptr(database) }
+            .to_string_lossy()
+            .into_owned(),
+        table: unsafe { CStr::from_ptr(table) }
+            .to_string_lossy()
+            .into_owned(),
+        rowid,
+    };
+
+    let hooks = unsafe { &*(data as *const ChangeHooks) };
+    let mut state = hooks.state();
+    for (_, callback) in &mut state.on_change {
+        let _ = catch_unwind(AssertUnwindSafe(|| callback(&event)));
+    }
+    state.pending.push(event);
+}
+
+// Both only set the changes aside, `settle` delivers them once the statement finished.
+// The commit can still fail after the commit hook ran, SQLite then calls the rollback hook.
+
+unsafe extern "C" fn commit_hook(data: *mut c_void) -> c_int {
+    let hooks = unsafe { &*(data as *const ChangeHooks) };
+    let mut state = hooks.state();
+    let changes = mem::take(&mut state.pending);
+    state.committing.extend(changes);
+    // non zero would turn the commit into a rollback
+    0
+}
+
+unsafe extern "C" fn rollback_hook(data: *mut c_void) {
+    let hooks = unsafe { &*(data as *const ChangeHooks) };
+    let mut state = hooks.state();
+    let mut changes = mem::take(&mut state.committing);
+    changes.append(&mut state.pending);
+    if !changes.is_empty() {
+        state.outbox.push((false, changes));
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    cell::RefCell,
+    ffi::{c_int, c_void},
+    fmt, ptr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use libsqlite3_sys::{
+    SQLITE_DONE, SQLITE_INTERRUPT, SQLITE_ROW, sqlite3, sqlite3_interrupt,
+    sqlite3_progress_handler, sqlite3_step, sqlite3_stmt,
+};
+
+use crate::{
+    errors::SQLITE_INTERRUPT_TIMEOUT,
+    internal_sqlite::{hooks, lazy_connection::LazyConnection, retry, threading::ThreadingMode},
+};
+
+/// Number of virtual machine instructions between two checks of a deadline
+const DEADLINE_CHECK_INTERVAL: c_int = 1000;
+
+thread_local! {
+    /// Deadlines of the `with_timeout` calls running on this thread, innermost last,
+    /// together with the connection they belong to
+    static DEADLINES: RefCell<Vec<(*mut sqlite3, Option<Instant>)>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+/// Stops whatever its connection is running, from any thread. See [`LazyConnection::interrupt_handle`]
+#[derive(Clone)]
+pub struct InterruptHandle {
+    db: Arc<Mutex<InterruptTarget>>,
+}
+
+/// The connection an [`InterruptHandle`] points at. Set to NULL before the connection is closed
+pub(crate) struct InterruptTarget(pub(crate) *mut sqlite3);
+
+// sqlite3_interrupt may be called from any thread, the mutex keeps the connection from
+// being closed in the meantime
+unsafe impl Send for InterruptTarget {}
+
+impl InterruptHandle {
+    /// Makes the statements running on the connection fail with `Interrupted` as soon as possible.
+    /// Statements started after they finished are not affected, and neither is anything
+    /// once the connection was closed.
+    pub fn interrupt(&self) {
+        let db = self.db.lock().unwrap_or_else(|e| e.into_inner());
+        if !db.0.is_null() {
+            unsafe { sqlite3_interrupt(db.0) };
+        }
+    }
+}
+
+impl<M: ThreadingMode> LazyConnection<M> {
+    /// A handle that can interrupt queries of this connection from another thread,
+    /// e.g. when a user cancels a long running report
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle {
+            db: Arc::clone(&self.interrupt_target),
+        }
+    }
+
+    /// Runs `f` with a deadline of `timeout` from now. Statements started by `f` fail
+    /// with `Timeout` once it passed, including rows read after `f` returned.
+    /// Nested calls can only shorten the deadline.
+    ///
+    /// The deadline only applies to the calling thread, statements other threads start on
+    /// the same connection in the meantime are not affected. Each statement keeps the deadline
+    /// it was started with.
+    ///
+    /// `exec` ignores the deadline, so `BEGIN`, `COMMIT` and `ROLLBACK` always run to the end.
+    pub fn with_timeout<T>(&self, timeout: Duration, f: impl FnOnce(&Self) -> T) -> T {
+        let deadline = earliest(self.deadline(), Instant::now().checked_add(timeout));
+        DEADLINES.with_borrow_mut(|deadlines| deadlines.push((self.db, deadline)));
+
+        struct Restore;
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                DEADLINES.with_borrow_mut(|deadlines| deadlines.pop());
+            }
+        }
+
+        let _restore = Restore;
+        f(self)
+    }
+
+    /// The deadline set by the innermost [`with_timeout`](Self::with_timeout) running on
+    /// this thread, if any
+    pub fn deadline(&self) -> Option<Instant> {
+        DEADLINES.with_borrow(|deadlines| {
+            deadlines
+                .iter()
+                .rev()
+                .find(|(db, _)| *db == self.db)
+                .and_then(|(_, deadline)| *deadline)
+        })
+    }
+}
+
+/// Why a statement stopped with `SQLITE_INTERRUPT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Interruption {
+    /// through an [`InterruptHandle`]
+    Interrupted,
+    /// its deadline passed
+    Timeout,
+}
+
+impl Interruption {
+    /// The result code reported where only a `SqliteFailure` can be returned
+    pub(crate) fn code(self) -> c_int {
+        match self {
+            Interruption::Interrupted => SQLITE_INTERRUPT,
+            Interruption::Timeout => SQLITE_INTERRUPT_TIMEOUT,
+        }
+    }
+}
+
+impl fmt::Display for Interruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interruption::Interrupted => write!(f, "interrupted"),
+            Interruption::Timeout => write!(f, "timed out"),
+        }
+    }
+}
+
+/// The earlier of two deadlines, `None` being no deadline at all
+#[doc(hidden)]
+pub fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// `sqlite3_step` that gives up once `deadline` has passed.
+/// Returns the result code, or why the statement was interrupted.
+/// Once the statement finished, the transactions it committed or rolled back are handed
+/// to the change callbacks, see [`hooks::DeliverOnDrop`].
+///
+/// # Safety
+/// `stmt` must be a valid statement of `db` and the connection mutex has to be held
+/// (the progress handler is installed for this call only)
+pub(crate) unsafe fn step(
+    db: *mut sqlite3,
+    stmt: *mut sqlite3_stmt,
+    deadline: Option<Instant>,
+) -> Result<c_int, Interruption> {
+    let mark = unsafe { hooks::change_mark(db) };
+    let result = unsafe { step_until(db, stmt, deadline) };
+    if let Ok(code) = result {
+        retry::note_step_result(code);
+    }
+    if result != Ok(SQLITE_ROW) {
+        unsafe { hooks::settle(db, result == Ok(SQLITE_DONE), mark) };
+    }
+    result
+}
+
+unsafe fn step_until(
+    db: *mut sqlite3,
+    stmt: *mut sqlite3_stmt,
+    deadline: Option<Instant>,
+) -> Result<c_int, Interruption> {
+    let Some(deadline) = deadline else {
+        let code = unsafe { sqlite3_step(stmt) };
+        return if code == SQLITE_INTERRUPT {
+            Err(Interruption::Interrupted)
+        } else {
+            Ok(code)
+        };
+    };
+
+    let mut check = DeadlineCheck {
+        deadline,
+        expired: false,
+    };
+    let code = unsafe {
+        sqlite3_progress_handler(
+            db,
+            DEADLINE_CHECK_INTERVAL,
+            Some(check_deadline),
+            &mut check as *mut DeadlineCheck as *mut c_void,
+        );
+        let code = sqlite3_step(stmt);
+        sqlite3_progress_handler(db, 0, None, ptr::null_mut());
+        code
+    };
+
+    match code {
+        SQLITE_INTERRUPT if check.expired => Err(Interruption::Timeout),
+        SQLITE_INTERRUPT => Err(Interruption::Interrupted),
+        code => Ok(code),
+    }
+}
+
+struct DeadlineCheck {
+    deadline: Instant,
+    expired: bool,
+}
+
+unsafe extern "C" fn check_deadline(data: *mut c_void) -> c_int {
+    let check = unsafe { &mut *(data as *mut DeadlineCheck) };
+    check.expired = Instant::now() >= check.deadline;
+    // non zero makes sqlite stop with SQLITE_INTERRUPT
+    check.expired as c_int
+}
@@ -1,16 +1,37 @@
 use libsqlite3_sys::{
-    self as ffi, SQLITE_DONE, SQLITE_OK, SQLITE_OPEN_CREATE, SQLITE_OPEN_MEMORY,
-    SQLITE_OPEN_READWRITE, sqlite3, sqlite3_busy_timeout, sqlite3_changes, sqlite3_column_count,
-    sqlite3_column_name, sqlite3_exec, sqlite3_finalize, sqlite3_step,
+    self as ffi, SQLITE_DONE, SQLITE_MISUSE, SQLITE_OK, SQLITE_OPEN_CREATE, SQLITE_OPEN_READWRITE,
+    SQLITE_ROW, sqlite3, sqlite3_busy_timeout, sqlite3_changes, sqlite3_column_count,
+    sqlite3_column_name, sqlite3_finalize, sqlite3_get_autocommit, sqlite3_prepare_v2,
+    sqlite3_stmt,
 };
 use std::{
+    error::Error as StdError,
     ffi::{CStr, CString, c_int},
-    ptr, sync::Arc,
+    marker::PhantomData,
+    ptr,
+    sync::{
+        Arc, Mutex, MutexGuard, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
 };
 
 use crate::{
-    errors::{Error, connection::SqlitePrepareErrors},
-    utility::utils::{close_db, get_sqlite_failiure},
+    errors::{Error, ParamsError, SqlDynamicError, connection::SqlitePrepareErrors},
+    internal_sqlite::{
+        authorizer::Authorizer,
+        hooks::{ChangeHooks, DeliverOnDrop},
+        interrupt::{self, InterruptTarget},
+        open_options::{DbConfig, OpenOptions},
+        preparred_statement::PreparredStmt,
+        retry::{Attempt, RetryPolicy},
+        statement_cache::{DEFAULT_STATEMENT_CACHE_CAPACITY, StatementCache, StatementCacheStats},
+        threading::{PerThread, Serialized, ThreadingMode},
+        trace::Tracer,
+        transaction::{Transaction, TransactionBehavior},
+    },
+    traits::{dynamic::Value, params::Params},
+    utility::utils::{DbMutexGuard, close_db, get_sqlite_failiure},
 };
 use crate::{
     errors::{SqliteFailure, connection::SqliteOpenErrors},
@@ -18,48 +39,153 @@ use crate::{
     utility::utils::prepare_stmt,
 };
 
-unsafe impl Send for LazyConnection {}
-unsafe impl Sync for LazyConnection {}
+/// Busy timeout every connection starts with
+pub const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_millis(5000);
 
-pub struct LazyConnection {
+// A connection may always be moved to another thread (SQLite's multi-thread mode guarantees that much).
+unsafe impl<M: ThreadingMode> Send for LazyConnection<M> {}
+// Only a serialized connection may be used by several threads at once, since SQLite then holds
+// the connection mutex during every call. Anything that reads state left behind by a previous
+// call (error code/message, changes) holds it as well through `DbMutexGuard`.
+unsafe impl Sync for LazyConnection<Serialized> {}
+
+/// A connection to a SQLite database.
+///
+/// `M` is the [`ThreadingMode`] the connection was opened with. [`Serialized`] (the default)
+/// can be shared between threads, [`PerThread`] can only be moved to another thread.
+pub struct LazyConnection<M: ThreadingMode = Serialized> {
     pub db: *mut sqlite3,
+    /// prepared statements reused by `query_dynamic` and `execute_dynamic`
+    stmt_cache: Mutex<StatementCache>,
+    /// number of savepoints opened by nested `transaction` calls
+    savepoint_depth: AtomicUsize,
+    retry_policy: Mutex<Option<RetryPolicy>>,
+    /// subscribers of `on_change`/`on_commit`/`on_rollback`, installed on first use
+    pub(crate) change_hooks: OnceLock<Box<ChangeHooks>>,
+    /// shared with every `InterruptHandle`
+    pub(crate) interrupt_target: Arc<Mutex<InterruptTarget>>,
+    /// sink of `trace`, referenced by the trace callback
+    pub(crate) tracer: Mutex<Option<Box<Tracer>>>,
+    /// policy of `set_authorizer`, referenced by the authorizer callback
+    pub(crate) authorizer: Mutex<Option<Box<Authorizer>>>,
+    _mode: PhantomData<M>,
 }
 
-impl Drop for LazyConnection {
+impl<M: ThreadingMode> Drop for LazyConnection<M> {
     fn drop(&mut self) {
+        // cached statements must be finalized before the connection can be closed
+        self.stmt_cache
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        // interrupt handles may outlive the connection
+        self.interrupt_target
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .0 = ptr::null_mut();
         unsafe {
+            // the hooks point into change_hooks, which is freed right after
+            if self.change_hooks.get().is_some() {
+                ChangeHooks::uninstall(self.db);
+            }
             close_db(self.db);
         };
     }
 }
 
 impl LazyConnection {
+    /// Opens a [`Serialized`] connection that can be shared between threads
     pub fn open(filename: &str) -> Result<Arc<Self>, SqliteOpenErrors> {
         let flag = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE;
-        LazyConnection::open_with_flags(filename, flag)
+        LazyConnection::open_with_flags(filename, flag).map(Arc::new)
     }
 
+    /// Opens a [`Serialized`] in memory connection that can be shared between threads
     pub fn open_memory() -> Result<Arc<Self>, SqliteOpenErrors> {
-        let flag = SQLITE_OPEN_MEMORY | SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE;
+        // the name alone is enough. SQLITE_OPEN_MEMORY would be inherited by `attach`,
+        // which then opens every attached file in memory as well
+        let flag = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE;
+        LazyConnection::open_with_flags(":memory:", flag).map(Arc::new)
+    }
+
+    /// Configures how the connection is opened: read-only, URI filenames, VFS, pragmas, ...
+    pub fn builder() -> OpenOptions {
+        OpenOptions::new()
+    }
+}
+
+impl LazyConnection<PerThread> {
+    /// Opens a [`PerThread`] connection. It skips SQLite's connection mutex and can only be
+    /// used from one thread at a time, which the compiler enforces since it is not `Sync`.
+    pub fn open_per_thread(filename: &str) -> Result<Self, SqliteOpenErrors> {
+        let flag = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE;
+        LazyConnection::open_with_flags(filename, flag)
+    }
+
+    /// In memory version of `open_per_thread`
+    pub fn open_memory_per_thread() -> Result<Self, SqliteOpenErrors> {
+        let flag = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE;
         LazyConnection::open_with_flags(":memory:", flag)
     }
+}
+
+impl<M: ThreadingMode> LazyConnection<M> {
+    /// the threading flag of `M` is added to `flag`
+    pub(crate) fn open_with_flags(filename: &str, flag: c_int) -> Result<Self, SqliteOpenErrors> {
+        LazyConnection::open_v2(filename, flag, None)
+    }
 
-    fn open_with_flags(filename: &str, flag: c_int) -> Result<Arc<Self>, SqliteOpenErrors> {
+    /// Thin wrapper around `sqlite3_open_v2`. The threading flag of `M` is added to `flag`,
+    /// the busy timeout is set to [`DEFAULT_BUSY_TIMEOUT`] and loading extensions is disabled.
+    pub(crate) fn open_v2(
+        filename: &str,
+        flag: c_int,
+        vfs: Option<&str>,
+    ) -> Result<Self, SqliteOpenErrors> {
         let mut db = ptr::null_mut();
-        let c_filename = CString::new(filename).unwrap(); //TODO
+        let c_filename =
+            CString::new(filename).map_err(|_| SqliteOpenErrors::EmbeddedNullInFileName {
+                filename: filename.to_string(),
+            })?;
+        let c_vfs = vfs
+            .map(|vfs| {
+                CString::new(vfs).map_err(|_| SqliteOpenErrors::EmbeddedNullInVfsName {
+                    vfs: vfs.to_string(),
+                })
+            })
+            .transpose()?;
+        let flag = flag | M::OPEN_FLAG;
 
-        let code = unsafe { ffi::sqlite3_open_v2(c_filename.as_ptr(), &mut db, flag, ptr::null()) };
+        let code = unsafe {
+            ffi::sqlite3_open_v2(
+                c_filename.as_ptr(),
+                &mut db,
+                flag,
+                c_vfs.as_ref().map_or(ptr::null(), |vfs| vfs.as_ptr()),
+            )
+        };
 
         if code == SQLITE_OK && db.is_null() {
             unsafe { close_db(db) };
             Err(SqliteOpenErrors::ConnectionAllocationFailed)
         } else if code == SQLITE_OK {
-            //TODO sqlite3_busy_timeout does return an int. It is nearly a gurantee for this
-            // function to never fail. but its still good to handle it. If it fails mean
-            // the sql query is taking more than 5 second which means its inefficent lol
-
-            unsafe { sqlite3_busy_timeout(db, 5000) };
-            Ok(Arc::new(Self { db }))
+            // from here on Drop closes the connection
+            let conn = Self {
+                db,
+                stmt_cache: Mutex::new(StatementCache::new(DEFAULT_STATEMENT_CACHE_CAPACITY)),
+                savepoint_depth: AtomicUsize::new(0),
+                retry_policy: Mutex::new(None),
+                change_hooks: OnceLock::new(),
+                interrupt_target: Arc::new(Mutex::new(InterruptTarget(db))),
+                tracer: Mutex::new(None),
+                authorizer: Mutex::new(None),
+                _mode: PhantomData,
+            };
+            conn.set_busy_timeout(DEFAULT_BUSY_TIMEOUT)?;
+            // the bundled sqlite turns it on for every connection, `load_extension` enables it
+            // only while loading
+            conn.db_config(DbConfig::EnableLoadExtension, false)?;
+            Ok(conn)
         } else {
             let (code, error_msg) = unsafe { get_sqlite_failiure(db) };
             unsafe { close_db(db) };
@@ -67,90 +193,349 @@ impl LazyConnection {
         }
     }
 
-    // note unused internally and undocumented. rarelys used anyways
-    pub fn exec(&self, sql: &str) -> Result<(), SqliteFailure> {
-        let c_sql = CString::new(sql).unwrap(); //TODO
+    /// How long a statement waits for a lock held by another connection before
+    /// giving up with `SqliteBusy`. Defaults to [`DEFAULT_BUSY_TIMEOUT`]
+    pub fn set_busy_timeout(&self, timeout: Duration) -> Result<(), SqliteFailure> {
+        let ms = timeout.as_millis().min(c_int::MAX as u128) as c_int;
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        let code = unsafe { sqlite3_busy_timeout(self.db, ms) };
+
+        if code != SQLITE_OK {
+            let (code, error_msg) = unsafe { get_sqlite_failiure(self.db) };
+            return Err(SqliteFailure { code, error_msg });
+        }
+        Ok(())
+    }
+
+    /// How `transaction` retries on `SQLITE_BUSY`/`SQLITE_LOCKED`. `None` (the default) never retries
+    pub fn set_retry_policy(&self, policy: Option<RetryPolicy>) {
+        *self.retry_policy.lock().unwrap_or_else(|e| e.into_inner()) = policy;
+    }
+
+    pub fn retry_policy(&self) -> Option<RetryPolicy> {
+        self.retry_policy
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Sets one of the boolean `sqlite3_db_config` switches and returns its new state
+    pub fn db_config(&self, config: DbConfig, enabled: bool) -> Result<bool, SqliteFailure> {
+        self.set_db_config(config, enabled as c_int)
+    }
+
+    /// The current state of one of the boolean `sqlite3_db_config` switches
+    pub fn db_config_enabled(&self, config: DbConfig) -> Result<bool, SqliteFailure> {
+        // a negative value leaves the switch as it is
+        self.set_db_config(config, -1)
+    }
+
+    fn set_db_config(&self, config: DbConfig, value: c_int) -> Result<bool, SqliteFailure> {
+        let mut current: c_int = 0;
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
         let code = unsafe {
-            sqlite3_exec(
-                self.db,
-                c_sql.as_ptr(),
-                None,
-                ptr::null_mut(),
-                ptr::null_mut(),
-            )
+            ffi::sqlite3_db_config(self.db, config.op(), value, &mut current as *mut c_int)
         };
 
         if code != SQLITE_OK {
             let (code, error_msg) = unsafe { get_sqlite_failiure(self.db) };
             return Err(SqliteFailure { code, error_msg });
         }
-        Ok(())
+        Ok(current != 0)
     }
 
-    pub fn query_dynamic(&self, sql: &str) -> Result<DynamicRows, SqliteFailure> {
-        let mut stmt = std::ptr::null_mut();
-        unsafe {
-            prepare_stmt(self.db, &mut stmt, sql).map_err(|e| match e {
-                SqlitePrepareErrors::SqliteFailure { code, error_msg } => {
-                    SqliteFailure { code, error_msg }
-                }
-            })?;
+    /// Runs `sql`, which may hold several statements, one after the other like `sqlite3_exec`
+    /// and stops at the first one that fails. Rows they return are skipped.
+    /// A null byte in `sql` fails with `SQLITE_MISUSE` before anything runs
+    pub fn exec(&self, sql: &str) -> Result<(), SqliteFailure> {
+        let c_sql = CString::new(sql).map_err(|_| SqliteFailure {
+            code: SQLITE_MISUSE,
+            error_msg: format!("{sql:?} contains a null byte"),
+        })?;
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        let _deliver = DeliverOnDrop(self.db);
 
-            let count = sqlite3_column_count(stmt);
-            let mut column_names = Vec::with_capacity(count as usize);
-            for i in 0..count {
-                let ptr = sqlite3_column_name(stmt, i);
-                let name = CStr::from_ptr(ptr).to_string_lossy().into_owned();
-                column_names.push(name);
+        // stepped one by one so that the change hooks can tell which statement failed
+        let mut tail = c_sql.as_ptr();
+        loop {
+            let mut stmt = ptr::null_mut();
+            let code = unsafe { sqlite3_prepare_v2(self.db, tail, -1, &mut stmt, &mut tail) };
+            if code != SQLITE_OK {
+                let (code, error_msg) = unsafe { get_sqlite_failiure(self.db) };
+                return Err(SqliteFailure { code, error_msg });
+            }
+            // only whitespace or comments were left
+            if stmt.is_null() {
+                return Ok(());
             }
 
-            Ok(DynamicRows::new(stmt, self.db, column_names))
+            let result = loop {
+                match unsafe { interrupt::step(self.db, stmt, None) } {
+                    Ok(SQLITE_ROW) => continue,
+                    result => break result,
+                }
+            };
+            let outcome = match result {
+                Ok(SQLITE_DONE) => Ok(()),
+                Err(interruption) => Err(SqliteFailure {
+                    code: interruption.code(),
+                    error_msg: interruption.to_string(),
+                }),
+                Ok(_) => {
+                    let (code, error_msg) = unsafe { get_sqlite_failiure(self.db) };
+                    Err(SqliteFailure { code, error_msg })
+                }
+            };
+            unsafe { sqlite3_finalize(stmt) };
+            outcome?;
         }
     }
 
+    pub fn query_dynamic(&self, sql: &str) -> Result<DynamicRows<'_>, SqliteFailure> {
+        let stmt = self.prepare_cached(sql).map_err(|e| match e {
+            SqlitePrepareErrors::SqliteFailure { code, error_msg } => {
+                SqliteFailure { code, error_msg }
+            }
+        })?;
+
+        Ok(unsafe { self.dynamic_rows(stmt, sql) })
+    }
+
+    /// Runs `sql` and returns how many rows it changed. A statement stopped through an
+    /// [`InterruptHandle`](super::interrupt::InterruptHandle) fails with `SQLITE_INTERRUPT`,
+    /// one whose deadline passed with [`SQLITE_INTERRUPT_TIMEOUT`](crate::errors::SQLITE_INTERRUPT_TIMEOUT)
     pub fn execute_dynamic(&self, sql: &str) -> Result<u64, SqliteFailure> {
-        let mut stmt = std::ptr::null_mut();
+        let stmt = self.prepare_cached(sql).map_err(|e| match e {
+            SqlitePrepareErrors::SqliteFailure { code, error_msg } => {
+                SqliteFailure { code, error_msg }
+            }
+        })?;
 
-        unsafe {
-            prepare_stmt(self.db, &mut stmt, sql).map_err(|e| match e {
-                SqlitePrepareErrors::SqliteFailure { code, error_msg } => {
-                    SqliteFailure { code, error_msg }
-                }
-            })?;
+        unsafe { self.step_dynamic(stmt, sql) }
+    }
 
-            let result = sqlite3_step(stmt);
-            sqlite3_finalize(stmt);
+    /// Same as `query_dynamic` but binds `params` to the `?`, `?NNN`, `:name`, `@name`
+    /// or `$name` placeholders in the sql first. See [`Params`] for what can be passed in.
+    pub fn query_dynamic_with(
+        &self,
+        sql: &str,
+        params: impl Params,
+    ) -> Result<DynamicRows<'_>, SqlDynamicError> {
+        let stmt = self.prepare_cached(sql)?;
 
-            if result == SQLITE_DONE {
-                // Return how many rows were modified (e.g., "3 rows updated")
-                let changes = sqlite3_changes(self.db);
-                Ok(changes as u64)
-            } else {
-                let (code, error_msg) = get_sqlite_failiure(self.db);
-                Err(SqliteFailure { code, error_msg })
-            }
+        if let Err(e) = self.bind_dynamic(stmt, params) {
+            unsafe { self.statement_cache().put(sql, stmt) };
+            return Err(e.into());
         }
+
+        Ok(unsafe { self.dynamic_rows(stmt, sql) })
     }
 
-    pub fn transaction<T, F>(&self, f: F) -> Result<T, Error>
+    /// Same as `execute_dynamic` but binds `params` to the `?`, `?NNN`, `:name`, `@name`
+    /// or `$name` placeholders in the sql first. See [`Params`] for what can be passed in.
+    pub fn execute_dynamic_with(
+        &self,
+        sql: &str,
+        params: impl Params,
+    ) -> Result<u64, SqlDynamicError> {
+        let stmt = self.prepare_cached(sql)?;
+
+        if let Err(e) = self.bind_dynamic(stmt, params) {
+            unsafe { self.statement_cache().put(sql, stmt) };
+            return Err(e.into());
+        }
+
+        Ok(unsafe { self.step_dynamic(stmt, sql)? })
+    }
+
+    /// Attaches the database at `path` (created if missing, `:memory:` works too) under the
+    /// schema name `schema`, so its tables are reachable as `schema.table`.
+    /// Unqualified names are looked up in `temp`, `main` and then the attached databases.
+    pub fn attach(&self, path: &str, schema: &str) -> Result<(), SqlDynamicError> {
+        self.execute_dynamic_with(
+            "ATTACH DATABASE ? AS ?",
+            [Value::from(path), Value::from(schema)],
+        )?;
+        Ok(())
+    }
+
+    /// Detaches a database attached with `attach`. Fails while a statement reading it is running
+    pub fn detach(&self, schema: &str) -> Result<(), SqlDynamicError> {
+        self.execute_dynamic_with("DETACH DATABASE ?", [Value::from(schema)])?;
+        Ok(())
+    }
+
+    /// Changes how many prepared statements `query_dynamic` and `execute_dynamic` keep around.
+    /// Setting it to 0 disables the cache. Least recently used statements are finalized
+    /// if the new capacity is smaller.
+    pub fn set_statement_cache_capacity(&self, capacity: usize) {
+        self.statement_cache().set_capacity(capacity);
+    }
+
+    /// Hit/miss counters of the dynamic statement cache
+    pub fn statement_cache_stats(&self) -> StatementCacheStats {
+        self.statement_cache().stats()
+    }
+
+    /// Finalizes every cached dynamic statement.
+    pub fn clear_statement_cache(&self) {
+        self.statement_cache().clear();
+    }
+
+    pub(crate) fn statement_cache(&self) -> MutexGuard<'_, StatementCache> {
+        self.stmt_cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Takes the statement out of the cache or prepares a new one on a miss
+    pub(crate) fn prepare_cached(
+        &self,
+        sql: &str,
+    ) -> Result<*mut sqlite3_stmt, SqlitePrepareErrors> {
+        if let Some(stmt) = self.statement_cache().take(sql) {
+            return Ok(stmt);
+        }
+
+        let mut stmt = ptr::null_mut();
+        unsafe { prepare_stmt(self.db, &mut stmt, sql)? };
+        Ok(stmt)
+    }
+
+    fn bind_dynamic(
+        &self,
+        stmt: *mut sqlite3_stmt,
+        params: impl Params,
+    ) -> Result<(), ParamsError> {
+        let preparred_statement = PreparredStmt {
+            stmt,
+            conn: self.db,
+            deadline: None,
+            reader: None,
+        };
+        params.bind_params(&preparred_statement)
+    }
+
+    /// # Safety
+    /// stmt must be a valid prepared statement for `sql`. Ownership is handed over to `DynamicRows`
+    unsafe fn dynamic_rows(&self, stmt: *mut sqlite3_stmt, sql: &str) -> DynamicRows<'_> {
+        let count = unsafe { sqlite3_column_count(stmt) };
+        let mut column_names = Vec::with_capacity(count as usize);
+        for i in 0..count {
+            let name = unsafe { CStr::from_ptr(sqlite3_column_name(stmt, i)) };
+            column_names.push(name.to_string_lossy().into_owned());
+        }
+
+        DynamicRows::cached(stmt, self.db, column_names, &self.stmt_cache, sql)
+            .with_deadline(self.deadline())
+    }
+
+    /// # Safety
+    /// stmt must be a valid prepared statement for `sql`. It goes back to the cache afterwards
+    unsafe fn step_dynamic(
+        &self,
+        stmt: *mut sqlite3_stmt,
+        sql: &str,
+    ) -> Result<u64, SqliteFailure> {
+        let lock = unsafe { DbMutexGuard::lock(self.db) };
+        let deliver = DeliverOnDrop(self.db);
+        let result = unsafe { interrupt::step(self.db, stmt, self.deadline()) };
+
+        let outcome = if result == Ok(SQLITE_DONE) {
+            // Return how many rows were modified (e.g., "3 rows updated")
+            let changes = unsafe { sqlite3_changes(self.db) };
+            Ok(changes as u64)
+        } else if let Err(interruption) = result {
+            Err(SqliteFailure {
+                code: interruption.code(),
+                error_msg: interruption.to_string(),
+            })
+        } else {
+            let (code, error_msg) = unsafe { get_sqlite_failiure(self.db) };
+            Err(SqliteFailure { code, error_msg })
+        };
+        drop(deliver);
+        drop(lock);
+
+        unsafe { self.statement_cache().put(sql, stmt) };
+        outcome
+    }
+
+    /// Runs `f` between `BEGIN` and `COMMIT`, rolling back if it returns an error.
+    /// Inside another transaction it runs in a savepoint instead, see `transaction_with`.
+    ///
+    /// `f` may fail with any error that lazysql's [`Error`] converts into and that can be boxed as
+    /// a `std::error::Error`. A failed `COMMIT` is reported as [`Error::Commit`], a failed
+    /// `ROLLBACK` as [`Error::Rollback`] holding the error of `f`.
+    ///
+    /// With a [`RetryPolicy`] set, `f` is re-run from scratch if `BEGIN`, one of its statements
+    /// or `COMMIT` fail with `SQLITE_BUSY`/`SQLITE_LOCKED`. If `f` returns lazysql's [`Error`]
+    /// or a boxed error, its own result code decides. For other error types `f` is re-run
+    /// if one of its statements failed that way.
+    pub fn transaction<T, E, F>(&self, f: F) -> Result<T, E>
     where
-        F: FnOnce(&Self) -> Result<T, Error>,
+        F: FnMut(&Self) -> Result<T, E>,
+        E: From<Error> + Into<Box<dyn StdError + Send + Sync>> + 'static,
     {
-        self.exec("BEGIN").map_err(Error::from)?;
-
-        let result = f(self);
+        self.transaction_with(TransactionBehavior::Deferred, f)
+    }
 
-        match result {
-            Ok(val) => {
-                if let Err(e) = self.exec("COMMIT") {
-                    return Err(Error::from(e));
-                }
-                Ok(val)
-            }
-            Err(e) => {
-                let _ = self.exec("ROLLBACK");
-                Err(e)
+    /// Same as `transaction` but starts it with `BEGIN DEFERRED`, `BEGIN IMMEDIATE` or `BEGIN EXCLUSIVE`.
+    ///
+    /// If a transaction is already open, `f` runs in `SAVEPOINT sp_N` instead (`behavior` is
+    /// then ignored). Its changes are released into the outer transaction on success and
+    /// rolled back on error without affecting the rest of the outer transaction.
+    pub fn transaction_with<T, E, F>(&self, behavior: TransactionBehavior, mut f: F) -> Result<T, E>
+    where
+        F: FnMut(&Self) -> Result<T, E>,
+        E: From<Error> + Into<Box<dyn StdError + Send + Sync>> + 'static,
+    {
+        let policy = self.retry_policy();
+        RetryPolicy::run(policy.as_ref(), || match self.begin_with(behavior) {
+            Ok(tx) => {
+                let result = f(&tx);
+                tx.finish_attempt(result)
             }
-        }
+            Err(e) => Attempt::begin_failed(e),
+        })
+    }
+
+    /// Runs `f` in `SAVEPOINT name`, releasing it on success and rolling back to it on error.
+    /// Outside of a transaction the savepoint behaves like a deferred transaction.
+    pub fn savepoint<T, E, F>(&self, name: &str, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&Self) -> Result<T, E>,
+        E: From<Error> + Into<Box<dyn StdError + Send + Sync>>,
+    {
+        let tx = Transaction::savepoint(self, name).map_err(Error::from)?;
+        let result = f(&tx);
+        tx.finish_with(result)
+    }
+
+    /// Starts a deferred transaction that is rolled back unless committed before it is dropped.
+    /// Inside another transaction it starts a savepoint instead.
+    pub fn begin(&self) -> Result<Transaction<'_, &Self>, SqliteFailure> {
+        self.begin_with(TransactionBehavior::Deferred)
+    }
+
+    /// Same as `begin` with `BEGIN DEFERRED`, `BEGIN IMMEDIATE` or `BEGIN EXCLUSIVE`
+    pub fn begin_with(
+        &self,
+        behavior: TransactionBehavior,
+    ) -> Result<Transaction<'_, &Self>, SqliteFailure> {
+        Transaction::begin(self, behavior)
+    }
+
+    /// Whether a transaction (or savepoint) is open on this connection
+    pub fn in_transaction(&self) -> bool {
+        unsafe { sqlite3_get_autocommit(self.db) == 0 }
+    }
+
+    /// Name of the savepoint a nested transaction opens
+    pub(crate) fn push_savepoint(&self) -> String {
+        let depth = self.savepoint_depth.fetch_add(1, Ordering::SeqCst) + 1;
+        format!("sp_{depth}")
+    }
+
+    pub(crate) fn pop_savepoint(&self) {
+        self.savepoint_depth.fetch_sub(1, Ordering::SeqCst);
     }
 }
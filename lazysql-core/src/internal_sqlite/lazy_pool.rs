@@ -0,0 +1,337 @@
+use libsqlite3_sys::{
+    SQLITE_BUSY, SQLITE_ERROR, SQLITE_OPEN_CREATE, SQLITE_OPEN_READONLY, SQLITE_OPEN_READWRITE,
+    sqlite3, sqlite3_stmt,
+};
+use std::{
+    marker::PhantomData,
+    ops::Deref,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    errors::{
+        ErrorCode, SqliteFailure,
+        connection::{SqliteOpenErrors, SqlitePrepareErrors},
+        row::RowMapperError,
+    },
+    internal_sqlite::lazy_connection::{DEFAULT_BUSY_TIMEOUT, LazyConnection},
+};
+
+struct PoolInner {
+    writer: Arc<LazyConnection>,
+    // set while the writer is in use so that transactions of different users never interleave
+    writer_owner: Mutex<WriterOwner>,
+    writer_released: Condvar,
+    idle_readers: Mutex<Vec<Arc<LazyConnection>>>,
+    reader_returned: Condvar,
+    reader_count: usize,
+    reader_timeout: Mutex<Duration>,
+}
+
+/// The thread holding the writer, and how many `WriterLock`s it holds
+#[derive(Default)]
+struct WriterOwner {
+    thread: Option<ThreadId>,
+    depth: usize,
+}
+
+/// A pool of one writer connection and N read-only reader connections to the same
+/// database file, opened in WAL mode so that readers never block the writer (and vice versa).
+///
+/// Cloning is cheap and hands out the same pool.
+#[derive(Clone)]
+pub struct LazyPool {
+    inner: Arc<PoolInner>,
+}
+
+impl LazyPool {
+    /// Opens the database at `filename` (creating it if it does not exist), switches it to
+    /// WAL mode and opens `readers` read-only connections next to the writer.
+    /// At least one reader is always opened.
+    ///
+    /// Fails with `WalUnavailable` if the database cannot be switched to WAL mode,
+    /// e.g. for in memory databases.
+    pub fn open(filename: &str, readers: usize) -> Result<Self, SqliteOpenErrors> {
+        let writer = Arc::new(LazyConnection::open_with_flags(
+            filename,
+            SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE,
+        )?);
+
+        enable_wal(&writer)?;
+
+        let reader_count = readers.max(1);
+        let mut idle_readers = Vec::with_capacity(reader_count);
+        for _ in 0..reader_count {
+            idle_readers.push(Arc::new(LazyConnection::open_with_flags(
+                filename,
+                SQLITE_OPEN_READONLY,
+            )?));
+        }
+
+        Ok(LazyPool {
+            inner: Arc::new(PoolInner {
+                writer,
+                writer_owner: Mutex::default(),
+                writer_released: Condvar::new(),
+                idle_readers: Mutex::new(idle_readers),
+                reader_returned: Condvar::new(),
+                reader_count,
+                reader_timeout: Mutex::new(DEFAULT_BUSY_TIMEOUT),
+            }),
+        })
+    }
+
+    /// Waits until no other thread uses the writer and hands it out.
+    /// The thread already holding it gets it right away, e.g. inside a transaction.
+    pub fn writer(&self) -> WriterGuard<'_> {
+        WriterGuard {
+            _lock: self.lock_writer(),
+            conn: &self.inner.writer,
+        }
+    }
+
+    /// Waits until a reader is idle and hands it out. It goes back to the pool once the guard is dropped.
+    ///
+    /// Fails with `SQLITE_BUSY` if none became idle within the reader timeout, e.g. because
+    /// the calling thread itself holds every reader.
+    pub fn reader(&self) -> Result<ReaderGuard, SqliteFailure> {
+        let timeout = self.reader_timeout();
+        let deadline = Instant::now().checked_add(timeout);
+        let mut idle = self.idle_readers();
+        loop {
+            if let Some(conn) = idle.pop() {
+                return Ok(ReaderGuard {
+                    pool: self.clone(),
+                    conn: Some(conn),
+                });
+            }
+
+            let left = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if left.is_some_and(|left| left.is_zero()) {
+                return Err(SqliteFailure {
+                    code: SQLITE_BUSY,
+                    error_msg: format!("no reader of the pool became idle within {timeout:?}"),
+                });
+            }
+            idle = match left {
+                Some(left) => {
+                    self.inner
+                        .reader_returned
+                        .wait_timeout(idle, left)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self
+                    .inner
+                    .reader_returned
+                    .wait(idle)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+
+    /// How long `reader` (and the read only queries of `from_pool` structs) wait for an idle
+    /// reader before failing with `SQLITE_BUSY`. Defaults to [`DEFAULT_BUSY_TIMEOUT`]
+    pub fn set_reader_timeout(&self, timeout: Duration) {
+        *self
+            .inner
+            .reader_timeout
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = timeout;
+    }
+
+    fn reader_timeout(&self) -> Duration {
+        *self
+            .inner
+            .reader_timeout
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Same as `reader` but returns `None` instead of waiting if every reader is in use.
+    pub fn try_reader(&self) -> Option<ReaderGuard> {
+        self.idle_readers().pop().map(|conn| ReaderGuard {
+            pool: self.clone(),
+            conn: Some(conn),
+        })
+    }
+
+    /// Number of reader connections owned by the pool
+    pub fn reader_count(&self) -> usize {
+        self.inner.reader_count
+    }
+
+    /// Used by the `#[lazy_sql]` structs created with `from_pool`. Prefer `writer()`
+    #[doc(hidden)]
+    pub fn writer_connection(&self) -> Arc<LazyConnection> {
+        self.inner.writer.clone()
+    }
+
+    /// Locks the writer without handing it out. Used by the `#[lazy_sql]` structs created with `from_pool`.
+    /// Reentrant, so a write nested in a transaction of the same thread does not wait for itself
+    #[doc(hidden)]
+    pub fn lock_writer(&self) -> WriterLock {
+        let me = thread::current().id();
+        let mut owner = self.writer_owner();
+        while owner.thread.is_some_and(|thread| thread != me) {
+            owner = self
+                .inner
+                .writer_released
+                .wait(owner)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        owner.thread = Some(me);
+        owner.depth += 1;
+
+        WriterLock {
+            pool: self.clone(),
+            _not_send: PhantomData,
+        }
+    }
+
+    fn writer_owner(&self) -> MutexGuard<'_, WriterOwner> {
+        self.inner
+            .writer_owner
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn idle_readers(&self) -> MutexGuard<'_, Vec<Arc<LazyConnection>>> {
+        self.inner
+            .idle_readers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Switches the database to WAL mode. SQLite keeps the old mode if it cannot use WAL (in memory
+/// databases, some VFS), in which case every reader would see a database of its own
+fn enable_wal(writer: &LazyConnection) -> Result<(), SqliteOpenErrors> {
+    let to_open_error = |e: RowMapperError| SqliteOpenErrors::SqliteFailure {
+        code: e.sqlite_code().unwrap_or(SQLITE_ERROR),
+        error_msg: e.to_string(),
+    };
+
+    // returns the mode the database is in afterwards
+    let row = writer
+        .query_dynamic("PRAGMA journal_mode=WAL")?
+        .first()
+        .map_err(to_open_error)?;
+    let mode = row
+        .and_then(|row| row.into_iter().next())
+        .map(|mode| mode.as_string())
+        .unwrap_or_default();
+
+    if mode.eq_ignore_ascii_case("wal") {
+        Ok(())
+    } else {
+        Err(SqliteOpenErrors::WalUnavailable { mode })
+    }
+}
+
+/// Exclusive access to the writer connection of a [`LazyPool`].
+pub struct WriterGuard<'a> {
+    _lock: WriterLock,
+    conn: &'a LazyConnection,
+}
+
+/// Keeps other threads away from the writer of a [`LazyPool`] until dropped.
+/// Owned so that it can be kept for as long as a transaction is open.
+#[doc(hidden)]
+pub struct WriterLock {
+    pool: LazyPool,
+    // released by the thread that took it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for WriterLock {
+    fn drop(&mut self) {
+        let mut owner = self.pool.writer_owner();
+        owner.depth -= 1;
+        if owner.depth == 0 {
+            owner.thread = None;
+            self.pool.inner.writer_released.notify_one();
+        }
+    }
+}
+
+impl Deref for WriterGuard<'_> {
+    type Target = LazyConnection;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn
+    }
+}
+
+/// A reader connection checked out of a [`LazyPool`]. Goes back to the pool on drop.
+pub struct ReaderGuard {
+    pool: LazyPool,
+    conn: Option<Arc<LazyConnection>>,
+}
+
+impl ReaderGuard {
+    /// The pool this reader belongs to
+    pub fn pool(&self) -> &LazyPool {
+        &self.pool
+    }
+
+    #[doc(hidden)]
+    pub fn db(&self) -> *mut sqlite3 {
+        self.deref().db
+    }
+}
+
+impl Deref for ReaderGuard {
+    type Target = LazyConnection;
+
+    fn deref(&self) -> &Self::Target {
+        // only taken out in drop
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl Drop for ReaderGuard {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.idle_readers().push(conn);
+            self.pool.inner.reader_returned.notify_one();
+        }
+    }
+}
+
+/// A read only statement of a `#[lazy_sql]` struct created with `from_pool`, prepared on
+/// a reader that is checked out just for it. Both go back to the pool once it is dropped.
+#[doc(hidden)]
+pub struct ReaderStatement {
+    reader: ReaderGuard,
+    sql: &'static str,
+    stmt: *mut sqlite3_stmt,
+}
+
+impl ReaderStatement {
+    /// Waits for an idle reader and takes the statement for `sql` out of its cache,
+    /// or prepares it there
+    pub fn prepare(pool: &LazyPool, sql: &'static str) -> Result<Self, SqlitePrepareErrors> {
+        let reader = pool.reader().map_err(|SqliteFailure { code, error_msg }| {
+            SqlitePrepareErrors::SqliteFailure { code, error_msg }
+        })?;
+        let stmt = reader.prepare_cached(sql)?;
+        Ok(ReaderStatement { reader, sql, stmt })
+    }
+
+    pub fn stmt(&self) -> *mut sqlite3_stmt {
+        self.stmt
+    }
+
+    pub fn db(&self) -> *mut sqlite3 {
+        self.reader.db()
+    }
+}
+
+impl Drop for ReaderStatement {
+    fn drop(&mut self) {
+        unsafe { self.reader.statement_cache().put(self.sql, self.stmt) };
+    }
+}
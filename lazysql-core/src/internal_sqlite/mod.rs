@@ -1,5 +1,24 @@
+pub mod authorizer;
+pub mod backup;
+pub mod blob;
+pub mod collation;
+pub mod extension;
+pub mod functions;
+pub mod hooks;
+pub mod interrupt;
 pub mod lazy_connection;
+pub mod lazy_pool;
 pub mod lazy_statement;
+pub mod open_options;
 pub mod preparred_statement;
+pub mod retry;
 pub mod rows_dao;
+pub mod serialize;
+#[cfg(feature = "session")]
+pub mod session;
+pub mod statement_cache;
+pub mod threading;
+pub mod trace;
+pub mod vtab;
+pub mod transaction;
 pub mod dynamic_rows;
\ No newline at end of file
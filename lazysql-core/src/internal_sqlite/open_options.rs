@@ -0,0 +1,342 @@
+use std::{ffi::c_int, marker::PhantomData, time::Duration};
+
+use libsqlite3_sys::{
+    SQLITE_DBCONFIG_DEFENSIVE, SQLITE_DBCONFIG_DQS_DDL, SQLITE_DBCONFIG_DQS_DML,
+    SQLITE_DBCONFIG_ENABLE_FKEY, SQLITE_DBCONFIG_ENABLE_LOAD_EXTENSION,
+    SQLITE_DBCONFIG_ENABLE_TRIGGER, SQLITE_DBCONFIG_ENABLE_VIEW,
+    SQLITE_DBCONFIG_LEGACY_ALTER_TABLE, SQLITE_DBCONFIG_TRUSTED_SCHEMA,
+    SQLITE_DBCONFIG_WRITABLE_SCHEMA, SQLITE_OPEN_CREATE, SQLITE_OPEN_READONLY,
+    SQLITE_OPEN_READWRITE, SQLITE_OPEN_SHAREDCACHE, SQLITE_OPEN_URI,
+};
+
+use crate::{
+    errors::connection::SqliteOpenErrors,
+    internal_sqlite::{
+        lazy_connection::{DEFAULT_BUSY_TIMEOUT, LazyConnection},
+        retry::RetryPolicy,
+        statement_cache::DEFAULT_STATEMENT_CACHE_CAPACITY,
+        threading::{PerThread, Serialized, ThreadingMode},
+    },
+};
+
+/// `PRAGMA journal_mode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Persist => "PERSIST",
+            JournalMode::Memory => "MEMORY",
+            JournalMode::Wal => "WAL",
+            JournalMode::Off => "OFF",
+        }
+    }
+}
+
+/// `PRAGMA synchronous`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn as_str(self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+/// Boolean switches of `sqlite3_db_config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbConfig {
+    /// `SQLITE_DBCONFIG_DEFENSIVE`. Disallows features that can corrupt the database file on purpose
+    Defensive,
+    /// `SQLITE_DBCONFIG_TRUSTED_SCHEMA`. Whether sql functions and virtual tables may be used from
+    /// within the schema (views, triggers, ...) without being marked innocuous
+    TrustedSchema,
+    /// `SQLITE_DBCONFIG_ENABLE_FKEY`
+    EnableForeignKeys,
+    /// `SQLITE_DBCONFIG_ENABLE_TRIGGER`
+    EnableTriggers,
+    /// `SQLITE_DBCONFIG_ENABLE_VIEW`
+    EnableViews,
+    /// `SQLITE_DBCONFIG_DQS_DML`. Double quoted string literals in DML statements
+    DqsDml,
+    /// `SQLITE_DBCONFIG_DQS_DDL`. Double quoted string literals in DDL statements
+    DqsDdl,
+    /// `SQLITE_DBCONFIG_LEGACY_ALTER_TABLE`
+    LegacyAlterTable,
+    /// `SQLITE_DBCONFIG_WRITABLE_SCHEMA`
+    WritableSchema,
+    /// `SQLITE_DBCONFIG_ENABLE_LOAD_EXTENSION`. Whether extensions may be loaded through the C API
+    /// (the `load_extension()` sql function stays disabled). Off on every connection opened here,
+    /// [`LazyConnection::load_extension`] only enables it while loading
+    EnableLoadExtension,
+}
+
+impl DbConfig {
+    pub(crate) fn op(self) -> c_int {
+        match self {
+            DbConfig::Defensive => SQLITE_DBCONFIG_DEFENSIVE,
+            DbConfig::TrustedSchema => SQLITE_DBCONFIG_TRUSTED_SCHEMA,
+            DbConfig::EnableForeignKeys => SQLITE_DBCONFIG_ENABLE_FKEY,
+            DbConfig::EnableTriggers => SQLITE_DBCONFIG_ENABLE_TRIGGER,
+            DbConfig::EnableViews => SQLITE_DBCONFIG_ENABLE_VIEW,
+            DbConfig::DqsDml => SQLITE_DBCONFIG_DQS_DML,
+            DbConfig::DqsDdl => SQLITE_DBCONFIG_DQS_DDL,
+            DbConfig::LegacyAlterTable => SQLITE_DBCONFIG_LEGACY_ALTER_TABLE,
+            DbConfig::WritableSchema => SQLITE_DBCONFIG_WRITABLE_SCHEMA,
+            DbConfig::EnableLoadExtension => SQLITE_DBCONFIG_ENABLE_LOAD_EXTENSION,
+        }
+    }
+}
+
+/// Builder for a [`LazyConnection`], created with [`LazyConnection::builder`].
+///
+/// Nothing is set on the connection unless asked for, apart from the busy timeout
+/// ([`DEFAULT_BUSY_TIMEOUT`]), the statement cache capacity and disabling
+/// [`DbConfig::EnableLoadExtension`].
+#[derive(Debug, Clone)]
+pub struct OpenOptions<M: ThreadingMode = Serialized> {
+    flags: c_int,
+    immutable: bool,
+    vfs: Option<String>,
+    busy_timeout: Duration,
+    foreign_keys: Option<bool>,
+    journal_mode: Option<JournalMode>,
+    synchronous: Option<Synchronous>,
+    cache_size: Option<i64>,
+    db_config: Vec<(DbConfig, bool)>,
+    statement_cache_capacity: usize,
+    retry_policy: Option<RetryPolicy>,
+    _mode: PhantomData<M>,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions {
+            flags: SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE,
+            immutable: false,
+            vfs: None,
+            busy_timeout: DEFAULT_BUSY_TIMEOUT,
+            foreign_keys: None,
+            journal_mode: None,
+            synchronous: None,
+            cache_size: None,
+            db_config: Vec::new(),
+            statement_cache_capacity: DEFAULT_STATEMENT_CACHE_CAPACITY,
+            retry_policy: None,
+            _mode: PhantomData,
+        }
+    }
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens a [`PerThread`] connection instead
+    pub fn per_thread(self) -> OpenOptions<PerThread> {
+        self.with_mode()
+    }
+}
+
+impl OpenOptions<PerThread> {
+    /// Opens a [`Serialized`] connection instead
+    pub fn serialized(self) -> OpenOptions<Serialized> {
+        self.with_mode()
+    }
+}
+
+impl<M: ThreadingMode> OpenOptions<M> {
+    /// Opens the database read-only (`SQLITE_OPEN_READONLY`). It must already exist.
+    pub fn read_only(mut self) -> Self {
+        self.flags &= !(SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE);
+        self.flags |= SQLITE_OPEN_READONLY;
+        self
+    }
+
+    /// Whether the database is created if it does not exist. Defaults to true
+    pub fn create(mut self, create: bool) -> Self {
+        if create {
+            self.flags &= !SQLITE_OPEN_READONLY;
+            self.flags |= SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE;
+        } else {
+            self.flags &= !SQLITE_OPEN_CREATE;
+        }
+        self
+    }
+
+    /// Opens the database read-only with `immutable=1`, telling SQLite the file can never change
+    /// so that it skips locking and change detection entirely.
+    pub fn immutable(mut self) -> Self {
+        self.immutable = true;
+        self.read_only()
+    }
+
+    /// Interprets the filename as a URI (`SQLITE_OPEN_URI`), e.g. `file:data.db?mode=ro`
+    /// or `file::memory:?cache=shared`
+    pub fn uri(mut self) -> Self {
+        self.flags |= SQLITE_OPEN_URI;
+        self
+    }
+
+    /// `SQLITE_OPEN_SHAREDCACHE`
+    pub fn shared_cache(mut self) -> Self {
+        self.flags |= SQLITE_OPEN_SHAREDCACHE;
+        self
+    }
+
+    /// Name of the VFS the connection uses instead of the default one
+    pub fn vfs(mut self, vfs: &str) -> Self {
+        self.vfs = Some(vfs.to_string());
+        self
+    }
+
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = timeout;
+        self
+    }
+
+    /// `PRAGMA foreign_keys`. The bundled SQLite enables them by default
+    pub fn foreign_keys(mut self, enabled: bool) -> Self {
+        self.foreign_keys = Some(enabled);
+        self
+    }
+
+    pub fn journal_mode(mut self, mode: JournalMode) -> Self {
+        self.journal_mode = Some(mode);
+        self
+    }
+
+    pub fn synchronous(mut self, synchronous: Synchronous) -> Self {
+        self.synchronous = Some(synchronous);
+        self
+    }
+
+    /// `PRAGMA cache_size`. Positive values are pages, negative values are KiB
+    pub fn cache_size(mut self, cache_size: i64) -> Self {
+        self.cache_size = Some(cache_size);
+        self
+    }
+
+    /// Shortcut for `db_config(DbConfig::Defensive, enabled)`
+    pub fn defensive(self, enabled: bool) -> Self {
+        self.db_config(DbConfig::Defensive, enabled)
+    }
+
+    /// Shortcut for `db_config(DbConfig::TrustedSchema, enabled)`
+    pub fn trusted_schema(self, enabled: bool) -> Self {
+        self.db_config(DbConfig::TrustedSchema, enabled)
+    }
+
+    /// Sets a `sqlite3_db_config` switch right after opening
+    pub fn db_config(mut self, config: DbConfig, enabled: bool) -> Self {
+        self.db_config.push((config, enabled));
+        self
+    }
+
+    /// Capacity of the statement cache used by `query_dynamic` and `execute_dynamic`
+    pub fn statement_cache_capacity(mut self, capacity: usize) -> Self {
+        self.statement_cache_capacity = capacity;
+        self
+    }
+
+    /// How `transaction` retries on `SQLITE_BUSY`/`SQLITE_LOCKED`. Never by default
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    pub fn open(&self, filename: &str) -> Result<LazyConnection<M>, SqliteOpenErrors> {
+        if self.immutable {
+            let uri = format!("file:{}?immutable=1", escape_uri_path(filename));
+            self.open_raw(&uri, self.flags | SQLITE_OPEN_URI)
+        } else {
+            self.open_raw(filename, self.flags)
+        }
+    }
+
+    pub fn open_memory(&self) -> Result<LazyConnection<M>, SqliteOpenErrors> {
+        // not SQLITE_OPEN_MEMORY, see `LazyConnection::open_memory`
+        self.open_raw(":memory:", self.flags)
+    }
+
+    fn open_raw(
+        &self,
+        filename: &str,
+        flags: c_int,
+    ) -> Result<LazyConnection<M>, SqliteOpenErrors> {
+        let conn = LazyConnection::<M>::open_v2(filename, flags, self.vfs.as_deref())?;
+
+        if self.busy_timeout != DEFAULT_BUSY_TIMEOUT {
+            conn.set_busy_timeout(self.busy_timeout)?;
+        }
+        for &(config, enabled) in &self.db_config {
+            conn.db_config(config, enabled)?;
+        }
+        if let Some(enabled) = self.foreign_keys {
+            conn.exec(&format!(
+                "PRAGMA foreign_keys = {}",
+                if enabled { "ON" } else { "OFF" }
+            ))?;
+        }
+        if let Some(mode) = self.journal_mode {
+            conn.exec(&format!("PRAGMA journal_mode = {}", mode.as_str()))?;
+        }
+        if let Some(synchronous) = self.synchronous {
+            conn.exec(&format!("PRAGMA synchronous = {}", synchronous.as_str()))?;
+        }
+        if let Some(cache_size) = self.cache_size {
+            conn.exec(&format!("PRAGMA cache_size = {cache_size}"))?;
+        }
+        if self.statement_cache_capacity != DEFAULT_STATEMENT_CACHE_CAPACITY {
+            conn.set_statement_cache_capacity(self.statement_cache_capacity);
+        }
+        conn.set_retry_policy(self.retry_policy.clone());
+
+        Ok(conn)
+    }
+
+    fn with_mode<N: ThreadingMode>(self) -> OpenOptions<N> {
+        OpenOptions {
+            flags: self.flags,
+            immutable: self.immutable,
+            vfs: self.vfs,
+            busy_timeout: self.busy_timeout,
+            foreign_keys: self.foreign_keys,
+            journal_mode: self.journal_mode,
+            synchronous: self.synchronous,
+            cache_size: self.cache_size,
+            db_config: self.db_config,
+            statement_cache_capacity: self.statement_cache_capacity,
+            retry_policy: self.retry_policy,
+            _mode: PhantomData,
+        }
+    }
+}
+
+/// `?` and `#` would end the path part of the uri, `%` would start an escape
+fn escape_uri_path(path: &str) -> String {
+    path.replace('%', "%25")
+        .replace('?', "%3f")
+        .replace('#', "%23")
+}
@@ -1,20 +1,31 @@
-use std::marker::PhantomData;
+use std::{ffi::CString, marker::PhantomData, time::Instant};
 
 use libsqlite3_sys::{
     SQLITE_BUSY, SQLITE_CONSTRAINT_CHECK, SQLITE_CONSTRAINT_FOREIGNKEY, SQLITE_CONSTRAINT_UNIQUE,
-    SQLITE_DONE, SQLITE_OK, SQLITE_ROW, sqlite3, sqlite3_reset, sqlite3_step, sqlite3_stmt,
+    SQLITE_DONE, SQLITE_OK, SQLITE_ROW, sqlite3, sqlite3_bind_parameter_count,
+    sqlite3_bind_parameter_index, sqlite3_reset, sqlite3_stmt,
 };
 
 use crate::{
     errors::{SqliteFailure, statement::StatementStepErrors},
-    internal_sqlite::rows_dao::Rows,
+    internal_sqlite::{
+        hooks::DeliverOnDrop,
+        interrupt::{self, Interruption},
+        lazy_pool::ReaderStatement,
+        rows_dao::Rows,
+    },
     traits::{row_mapper::RowMapper, to_sql::ToSql},
-    utility::utils::get_sqlite_failiure,
+    utility::utils::{DbMutexGuard, get_sqlite_failiure},
 };
 
 pub struct PreparredStmt {
     pub stmt: *mut sqlite3_stmt,
     pub conn: *mut sqlite3,
+    /// stepping fails with `Timeout` after this
+    pub deadline: Option<Instant>,
+    /// owns `stmt` if it runs on a reader checked out of a `LazyPool`,
+    /// which gets both back once the statement was reset
+    pub reader: Option<ReaderStatement>,
 }
 
 impl Drop for PreparredStmt {
@@ -29,6 +40,7 @@ impl Drop for PreparredStmt {
 impl PreparredStmt {
     /// indexes start at 1
     pub fn bind_parameter(&self, index: i32, value: impl ToSql) -> Result<(), SqliteFailure> {
+        let _lock = unsafe { DbMutexGuard::lock(self.conn) };
         let code = unsafe { value.bind_to(self.stmt, index) };
 
         if code != SQLITE_OK {
@@ -39,9 +51,29 @@ impl PreparredStmt {
         }
     }
 
+    /// Number of parameters in the statement. For `?NNN` this is the largest index used
+    pub fn parameter_count(&self) -> i32 {
+        unsafe { sqlite3_bind_parameter_count(self.stmt) }
+    }
+
+    /// Looks up the index of a named parameter such as `:name`, `@name` or `$name`.
+    /// The prefix has to be included. Returns `None` if the statement has no such parameter
+    pub fn parameter_index(&self, name: &str) -> Option<i32> {
+        let c_name = CString::new(name).ok()?;
+        let index = unsafe { sqlite3_bind_parameter_index(self.stmt, c_name.as_ptr()) };
+
+        if index == 0 { None } else { Some(index) }
+    }
+
     /// Strictly only used for write only operation (UPDATE, INSERT etc.)
     pub fn step(&mut self) -> Result<(), StatementStepErrors> {
-        let code = unsafe { sqlite3_step(self.stmt) };
+        let _lock = unsafe { DbMutexGuard::lock(self.conn) };
+        let _deliver = DeliverOnDrop(self.conn);
+        let code = match unsafe { interrupt::step(self.conn, self.stmt, self.deadline) } {
+            Ok(code) => code,
+            Err(Interruption::Interrupted) => return Err(StatementStepErrors::Interrupted),
+            Err(Interruption::Timeout) => return Err(StatementStepErrors::Timeout),
+        };
 
         if code == SQLITE_DONE || code == SQLITE_ROW {
             return Ok(());
@@ -0,0 +1,155 @@
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    ffi::c_int,
+    hash::{BuildHasher, Hasher},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use libsqlite3_sys::{SQLITE_BUSY, SQLITE_LOCKED};
+
+use crate::errors::{Error, SqliteFailure};
+
+thread_local! {
+    /// Statements of this thread that failed with `SQLITE_BUSY`/`SQLITE_LOCKED` so far
+    static BUSY_STEPS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// How often a transaction is re-run from scratch when SQLite reports `SQLITE_BUSY` or
+/// `SQLITE_LOCKED` on `BEGIN`, on one of its statements or on `COMMIT`.
+///
+/// Set it with `LazyConnection::set_retry_policy` or `OpenOptions::retry_policy`. Without one,
+/// transactions are attempted once. Every retry waits `initial_backoff * 2^(retry - 1)`,
+/// capped at `max_backoff`. With `jitter` that wait is randomly shortened by up to half so
+/// that competing writers do not retry in lockstep.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The default policy with `max_attempts` attempts
+    pub fn new(max_attempts: u32) -> Self {
+        RetryPolicy {
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// How long to wait before the attempt following `attempt` (starting at 1),
+    /// or `None` if there is none left. `0` is treated like `1`.
+    pub fn delay_after(&self, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+
+        if self.jitter {
+            Some(delay / 2 + delay.mul_f64(random_fraction() / 2.0))
+        } else {
+            Some(delay)
+        }
+    }
+
+    /// Calls `attempt` until it returns [`Attempt::Done`] or the policy runs out of attempts.
+    /// Used by the `transaction` methods.
+    #[doc(hidden)]
+    pub fn run<T, E>(
+        policy: Option<&RetryPolicy>,
+        mut attempt: impl FnMut() -> Attempt<T, E>,
+    ) -> Result<T, E> {
+        let mut attempts = 1;
+        loop {
+            match attempt() {
+                Attempt::Done(result) => return result,
+                Attempt::Busy(err) => match policy.and_then(|p| p.delay_after(attempts)) {
+                    Some(delay) => {
+                        thread::sleep(delay);
+                        attempts += 1;
+                    }
+                    None => return Err(err),
+                },
+            }
+        }
+    }
+}
+
+/// Outcome of one attempt at running a transaction
+#[doc(hidden)]
+pub enum Attempt<T, E> {
+    Done(Result<T, E>),
+    /// failed because of `SQLITE_BUSY` / `SQLITE_LOCKED` and was rolled back, so it may be retried
+    Busy(E),
+}
+
+impl<T, E: From<Error>> Attempt<T, E> {
+    /// `BEGIN` failed, which is worth retrying if the database was busy
+    pub fn begin_failed(failure: SqliteFailure) -> Self {
+        if is_busy(failure.code) {
+            Attempt::Busy(E::from(Error::Db(failure)))
+        } else {
+            Attempt::Done(Err(E::from(Error::Db(failure))))
+        }
+    }
+}
+
+/// Whether `code` (primary or extended) is `SQLITE_BUSY` or `SQLITE_LOCKED`
+pub fn is_busy(code: c_int) -> bool {
+    let primary = code & 0xff;
+    primary == SQLITE_BUSY || primary == SQLITE_LOCKED
+}
+
+/// Counts `code` towards [`busy_steps`] if it is `SQLITE_BUSY`/`SQLITE_LOCKED`
+pub(crate) fn note_step_result(code: c_int) {
+    if is_busy(code) {
+        BUSY_STEPS.with(|steps| steps.set(steps.get() + 1));
+    }
+}
+
+/// How many statements of this thread failed with `SQLITE_BUSY`/`SQLITE_LOCKED`. A transaction
+/// compares it before and after its closure ran to tell whether an error of a type it cannot
+/// look into came from a busy database
+pub(crate) fn busy_steps() -> u64 {
+    BUSY_STEPS.with(Cell::get)
+}
+
+/// In `[0, 1)`. Good enough for jitter without pulling in a rng crate
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
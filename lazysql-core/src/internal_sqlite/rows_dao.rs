@@ -1,10 +1,16 @@
 use std::marker::PhantomData;
 
-use libsqlite3_sys::{SQLITE_BUSY, SQLITE_DONE, SQLITE_ROW, sqlite3_step};
+use libsqlite3_sys::{SQLITE_BUSY, SQLITE_DONE, SQLITE_ROW};
 
 use crate::{
-    errors::row::RowMapperError, internal_sqlite::preparred_statement::PreparredStmt,
-    traits::row_mapper::RowMapper, utility::utils::get_sqlite_failiure,
+    errors::row::RowMapperError,
+    internal_sqlite::{
+        hooks::DeliverOnDrop,
+        interrupt::{self, Interruption},
+        preparred_statement::PreparredStmt,
+    },
+    traits::row_mapper::RowMapper,
+    utility::utils::{DbMutexGuard, get_sqlite_failiure},
 };
 
 #[allow(dead_code)]
@@ -19,7 +25,14 @@ impl<'a, M: RowMapper> Iterator for Rows<'a, M> {
     type Item = Result<M::Output, RowMapperError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let result_code = unsafe { sqlite3_step(self.stmt.stmt) };
+        let _lock = unsafe { DbMutexGuard::lock(self.stmt.conn) };
+        let _deliver = DeliverOnDrop(self.stmt.conn);
+        let result_code =
+            match unsafe { interrupt::step(self.stmt.conn, self.stmt.stmt, self.stmt.deadline) } {
+                Ok(code) => code,
+                Err(Interruption::Interrupted) => return Some(Err(RowMapperError::Interrupted)),
+                Err(Interruption::Timeout) => return Some(Err(RowMapperError::Timeout)),
+            };
 
         if result_code == SQLITE_ROW {
             // Call the map_row method on our stored mapper instance.
@@ -0,0 +1,112 @@
+use std::{
+    ffi::{CStr, CString, c_void},
+    ptr, slice,
+};
+
+use libsqlite3_sys::{
+    SQLITE_DESERIALIZE_FREEONCLOSE, SQLITE_DESERIALIZE_READONLY, SQLITE_DESERIALIZE_RESIZEABLE,
+    SQLITE_ERROR, SQLITE_NOMEM, SQLITE_OK, sqlite3_deserialize, sqlite3_errstr, sqlite3_free,
+    sqlite3_malloc64, sqlite3_serialize,
+};
+
+use crate::{
+    errors::{SerializeError, SqliteFailure},
+    internal_sqlite::{lazy_connection::LazyConnection, threading::ThreadingMode},
+    utility::utils::{DbMutexGuard, get_sqlite_failiure},
+};
+
+impl<M: ThreadingMode> LazyConnection<M> {
+    /// The content of database `schema` (`"main"`, `"temp"` or an attached one) as the bytes
+    /// of a database file. Works for in memory databases as well as file backed ones.
+    pub fn serialize(&self, schema: &str) -> Result<Vec<u8>, SerializeError> {
+        let c_schema = schema_name(schema)?;
+        let mut size: i64 = 0;
+
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        let data = unsafe { sqlite3_serialize(self.db, c_schema.as_ptr(), &mut size, 0) };
+
+        if data.is_null() {
+            return match size {
+                // a database without a single page
+                0 => Ok(Vec::new()),
+                -1 => Err(failure(SQLITE_ERROR, &format!("unknown database {schema}"))),
+                _ => Err(failure(SQLITE_NOMEM, "out of memory")),
+            };
+        }
+
+        let bytes = unsafe { slice::from_raw_parts(data, size as usize) }.to_vec();
+        unsafe { sqlite3_free(data as *mut c_void) };
+        Ok(bytes)
+    }
+
+    /// Replaces the main database with an in memory database holding a copy of `bytes`,
+    /// e.g. the output of [`serialize`](Self::serialize).
+    /// With `read_only` any write to it fails with `SQLITE_READONLY`.
+    ///
+    /// Fails if a transaction is open on the connection.
+    pub fn deserialize(&self, bytes: &[u8], read_only: bool) -> Result<(), SerializeError> {
+        self.deserialize_schema("main", bytes, read_only)
+    }
+
+    /// [`deserialize`](Self::deserialize) into `schema` (`"main"` or an attached database)
+    pub fn deserialize_schema(
+        &self,
+        schema: &str,
+        bytes: &[u8],
+        read_only: bool,
+    ) -> Result<(), SerializeError> {
+        let c_schema = schema_name(schema)?;
+
+        // sqlite takes ownership of the buffer (and frees it even on failure)
+        // so it has to come from its own allocator
+        let data = unsafe { sqlite3_malloc64(bytes.len().max(1) as u64) } as *mut u8;
+        if data.is_null() {
+            return Err(failure(SQLITE_NOMEM, "out of memory"));
+        }
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), data, bytes.len()) };
+
+        let flags = if read_only {
+            SQLITE_DESERIALIZE_FREEONCLOSE | SQLITE_DESERIALIZE_READONLY
+        } else {
+            SQLITE_DESERIALIZE_FREEONCLOSE | SQLITE_DESERIALIZE_RESIZEABLE
+        };
+
+        // statements prepared against the old content would only be re-prepared anyway
+        self.clear_statement_cache();
+
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        let len = bytes.len() as i64;
+        let code =
+            unsafe { sqlite3_deserialize(self.db, c_schema.as_ptr(), data, len, len, flags) };
+
+        if code != SQLITE_OK {
+            let (errcode, error_msg) = unsafe { get_sqlite_failiure(self.db) };
+            return Err(if errcode == SQLITE_OK {
+                failure(code, &errstr(code))
+            } else {
+                SqliteFailure { code, error_msg }.into()
+            });
+        }
+        Ok(())
+    }
+}
+
+fn failure(code: i32, error_msg: &str) -> SerializeError {
+    SqliteFailure {
+        code,
+        error_msg: error_msg.to_string(),
+    }
+    .into()
+}
+
+fn schema_name(schema: &str) -> Result<CString, SerializeError> {
+    CString::new(schema).map_err(|_| SerializeError::EmbeddedNullInSchemaName {
+        schema: schema.to_string(),
+    })
+}
+
+fn errstr(code: i32) -> String {
+    unsafe { CStr::from_ptr(sqlite3_errstr(code)) }
+        .to_string_lossy()
+        .into_owned()
+}
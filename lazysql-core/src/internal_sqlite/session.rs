@@ -0,0 +1,429 @@
+use std::{
+    ffi::{CStr, CString, c_char, c_int, c_void},
+    marker::PhantomData,
+    panic::{AssertUnwindSafe, catch_unwind},
+    ptr, slice,
+};
+
+use libsqlite3_sys::{
+    SQLITE_ABORT, SQLITE_CHANGESET_ABORT, SQLITE_CHANGESET_CONFLICT, SQLITE_CHANGESET_CONSTRAINT,
+    SQLITE_CHANGESET_DATA, SQLITE_CHANGESET_FOREIGN_KEY, SQLITE_CHANGESET_NOTFOUND,
+    SQLITE_CHANGESET_OMIT, SQLITE_CHANGESET_REPLACE, SQLITE_DONE, SQLITE_INSERT, SQLITE_OK,
+    SQLITE_ROW, SQLITE_UPDATE, sqlite3, sqlite3_changeset_iter, sqlite3_errstr, sqlite3_free,
+    sqlite3_session, sqlite3_value, sqlite3changeset_apply, sqlite3changeset_conflict,
+    sqlite3changeset_finalize, sqlite3changeset_fk_conflicts, sqlite3changeset_new,
+    sqlite3changeset_next, sqlite3changeset_old, sqlite3changeset_op, sqlite3changeset_pk,
+    sqlite3changeset_start, sqlite3session_attach, sqlite3session_changeset, sqlite3session_create,
+    sqlite3session_delete, sqlite3session_enable, sqlite3session_isempty, sqlite3session_patchset,
+};
+
+use crate::{
+    errors::{SessionError, SqliteFailure},
+    internal_sqlite::{hooks::ChangeOp, lazy_connection::LazyConnection, threading::ThreadingMode},
+    traits::{dynamic::Value, from_sql::FromSqlValue},
+    utility::utils::{DbMutexGuard, get_sqlite_failiure},
+};
+
+/// Records the changes made to some tables of a connection, see [`LazyConnection::session`].
+///
+/// Only tables with a `PRIMARY KEY` are recorded. A row changed several times shows up once,
+/// with its state from before the first and after the last change.
+pub struct Session<'conn, M: ThreadingMode> {
+    conn: &'conn LazyConnection<M>,
+    session: *mut sqlite3_session,
+}
+
+impl<M: ThreadingMode> LazyConnection<M> {
+    /// Starts recording changes to the `main` database. Nothing is recorded until
+    /// tables are added with [`Session::attach`]
+    pub fn session(&self) -> Result<Session<'_, M>, SessionError> {
+        self.session_on("main")
+    }
+
+    /// Same as [`session`](Self::session) for `temp` or an attached database
+    pub fn session_on(&self, database: &str) -> Result<Session<'_, M>, SessionError> {
+        let c_database = c_name(database)?;
+        let mut session = ptr::null_mut();
+
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        let code = unsafe { sqlite3session_create(self.db, c_database.as_ptr(), &mut session) };
+        if code != SQLITE_OK {
+            return Err(unsafe { failure(self.db, code) });
+        }
+        Ok(Session {
+            conn: self,
+            session,
+        })
+    }
+
+    /// Applies a changeset or patchset made by a [`Session`] (usually of another database).
+    /// Everything is applied in one savepoint: if `conflict` returns
+    /// [`ConflictAction::Abort`] nothing is changed and this fails with `Aborted`.
+    ///
+    /// `conflict` is called with every change that cannot be applied as is and must not use
+    /// the connection. A panic inside it aborts.
+    pub fn apply_changeset<F>(&self, changeset: &[u8], mut conflict: F) -> Result<(), SessionError>
+    where
+        F: FnMut(&Conflict) -> ConflictAction,
+    {
+        let len = changeset_len(changeset)?;
+
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        // the changeset is only read, despite the *mut
+        let code = unsafe {
+            sqlite3changeset_apply(
+                self.db,
+                len,
+                changeset.as_ptr() as *mut c_void,
+                None,
+                Some(call_conflict::<F>),
+                &mut conflict as *mut F as *mut c_void,
+            )
+        };
+
+        match code {
+            SQLITE_OK => Ok(()),
+            SQLITE_ABORT => Err(SessionError::Aborted),
+            code => Err(unsafe { failure(self.db, code) }),
+        }
+    }
+}
+
+impl<M: ThreadingMode> Session<'_, M> {
+    /// Records the changes to `table` from now on
+    pub fn attach(&mut self, table: &str) -> Result<(), SessionError> {
+        let c_table = c_name(table)?;
+        self.attach_raw(c_table.as_ptr())
+    }
+
+    /// Records the changes to every table, including the ones created later
+    pub fn attach_all(&mut self) -> Result<(), SessionError> {
+        self.attach_raw(ptr::null())
+    }
+
+    fn attach_raw(&mut self, table: *const c_char) -> Result<(), SessionError> {
+        let _lock = unsafe { DbMutexGuard::lock(self.conn.db) };
+        let code = unsafe { sqlite3session_attach(self.session, table) };
+        if code != SQLITE_OK {
+            return Err(unsafe { failure(self.conn.db, code) });
+        }
+        Ok(())
+    }
+
+    /// Pauses (`false`) or resumes (`true`) recording
+    pub fn set_enabled(&mut self, enabled: bool) {
+        let _lock = unsafe { DbMutexGuard::lock(self.conn.db) };
+        unsafe { sqlite3session_enable(self.session, enabled as c_int) };
+    }
+
+    /// Whether nothing was recorded so far
+    pub fn is_empty(&self) -> bool {
+        let _lock = unsafe { DbMutexGuard::lock(self.conn.db) };
+        unsafe { sqlite3session_isempty(self.session) != 0 }
+    }
+
+    /// The recorded changes, with the old and new values of every changed row.
+    /// Recording goes on afterwards, the next changeset contains these changes again
+    pub fn changeset(&self) -> Result<Vec<u8>, SessionError> {
+        self.collect(sqlite3session_changeset)
+    }
+
+    /// A smaller version of [`changeset`](Self::changeset) without the old values
+    /// (except the primary key), so `apply_changeset` cannot detect `Data` conflicts
+    pub fn patchset(&self) -> Result<Vec<u8>, SessionError> {
+        self.collect(sqlite3session_patchset)
+    }
+
+    fn collect(
+        &self,
+        make: unsafe extern "C" fn(*mut sqlite3_session, *mut c_int, *mut *mut c_void) -> c_int,
+    ) -> Result<Vec<u8>, SessionError> {
+        let mut len = 0;
+        let mut data = ptr::null_mut();
+
+        let _lock = unsafe { DbMutexGuard::lock(self.conn.db) };
+        let code = unsafe { make(self.session, &mut len, &mut data) };
+        if code != SQLITE_OK {
+            return Err(unsafe { failure(self.conn.db, code) });
+        }
+
+        let bytes = unsafe { bytes(data as *const u8, len) }.to_vec();
+        unsafe { sqlite3_free(data) };
+        Ok(bytes)
+    }
+}
+
+impl<M: ThreadingMode> Drop for Session<'_, M> {
+    fn drop(&mut self) {
+        let _lock = unsafe { DbMutexGuard::lock(self.conn.db) };
+        unsafe { sqlite3session_delete(self.session) };
+    }
+}
+
+/// One change of a changeset or patchset
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub table: String,
+    pub op: ChangeOp,
+    /// Made by a trigger or foreign key action rather than a statement
+    pub indirect: bool,
+    /// For every column whether it is part of the primary key
+    pub primary_key: Vec<bool>,
+    /// Values before an update or delete, `None` for columns the change does not carry
+    /// (unchanged columns of an update, everything but the primary key in a patchset).
+    /// Empty for inserts
+    pub old: Vec<Option<Value>>,
+    /// Values after an insert or update, `None` for columns an update leaves unchanged.
+    /// Empty for deletes
+    pub new: Vec<Option<Value>>,
+}
+
+/// Iterates over the changes of a changeset or patchset
+pub struct ChangesetIter<'a> {
+    iter: *mut sqlite3_changeset_iter,
+    done: bool,
+    _changeset: PhantomData<&'a [u8]>,
+}
+
+impl<'a> ChangesetIter<'a> {
+    pub fn new(changeset: &'a [u8]) -> Result<Self, SessionError> {
+        let len = changeset_len(changeset)?;
+        let mut iter = ptr::null_mut();
+        let code =
+            unsafe { sqlite3changeset_start(&mut iter, len, changeset.as_ptr() as *mut c_void) };
+        if code != SQLITE_OK {
+            return Err(errstr_failure(code));
+        }
+        Ok(ChangesetIter {
+            iter,
+            done: false,
+            _changeset: PhantomData,
+        })
+    }
+}
+
+impl Iterator for ChangesetIter<'_> {
+    type Item = Result<Change, SessionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match unsafe { sqlite3changeset_next(self.iter) } {
+            SQLITE_ROW => {
+                let change = unsafe { Change::read(self.iter) };
+                self.done = change.is_err();
+                Some(change)
+            }
+            SQLITE_DONE => {
+                self.done = true;
+                None
+            }
+            code => {
+                // a corrupt changeset
+                self.done = true;
+                Some(Err(errstr_failure(code)))
+            }
+        }
+    }
+}
+
+impl Drop for ChangesetIter<'_> {
+    fn drop(&mut self) {
+        unsafe { sqlite3changeset_finalize(self.iter) };
+    }
+}
+
+impl Change {
+    unsafe fn read(iter: *mut sqlite3_changeset_iter) -> Result<Change, SessionError> {
+        let mut table = ptr::null();
+        let mut columns = 0;
+        let mut op = 0;
+        let mut indirect = 0;
+        check(unsafe {
+            sqlite3changeset_op(iter, &mut table, &mut columns, &mut op, &mut indirect)
+        })?;
+
+        let mut pk = ptr::null_mut();
+        let mut pk_columns = 0;
+        check(unsafe { sqlite3changeset_pk(iter, &mut pk, &mut pk_columns) })?;
+        let primary_key = unsafe { bytes(pk, pk_columns) }
+            .iter()
+            .map(|&b| b != 0)
+            .collect();
+
+        let op = match op {
+            SQLITE_INSERT => ChangeOp::Insert,
+            SQLITE_UPDATE => ChangeOp::Update,
+            _ => ChangeOp::Delete,
+        };
+        let old = match op {
+            ChangeOp::Insert => Vec::new(),
+            _ => unsafe { values(iter, columns, sqlite3changeset_old) }?,
+        };
+        let new = match op {
+            ChangeOp::Delete => Vec::new(),
+            _ => unsafe { values(iter, columns, sqlite3changeset_new) }?,
+        };
+
+        Ok(Change {
+            table: unsafe { CStr::from_ptr(table) }
+                .to_string_lossy()
+                .into_owned(),
+            op,
+            indirect: indirect != 0,
+            primary_key,
+            old,
+            new,
+        })
+    }
+}
+
+type ReadValue =
+    unsafe extern "C" fn(*mut sqlite3_changeset_iter, c_int, *mut *mut sqlite3_value) -> c_int;
+
+/// Columns the change does not carry come back as a NULL pointer
+unsafe fn values(
+    iter: *mut sqlite3_changeset_iter,
+    columns: c_int,
+    read: ReadValue,
+) -> Result<Vec<Option<Value>>, SessionError> {
+    (0..columns)
+        .map(|i| {
+            let mut value = ptr::null_mut();
+            check(unsafe { read(iter, i, &mut value) })?;
+            Ok((!value.is_null()).then(|| unsafe { Value::from_sql_value(value) }))
+        })
+        .collect()
+}
+
+/// A change `apply_changeset` cannot apply as is
+#[derive(Debug, Clone)]
+pub enum Conflict {
+    /// The row to update or delete exists, but differs from the old values of the change.
+    /// `current` is the row as it is now
+    Data { change: Change, current: Vec<Value> },
+    /// The row to update or delete does not exist
+    NotFound { change: Change },
+    /// The row to insert already exists, `current` is the row as it is now
+    Conflict { change: Change, current: Vec<Value> },
+    /// Applying the change breaks a `NOT NULL`, `UNIQUE` or `CHECK` constraint
+    Constraint { change: Change },
+    /// The changeset as a whole leaves `count` foreign key violations behind,
+    /// reported once at the end
+    ForeignKey { count: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictAction {
+    /// Skips the change
+    Omit,
+    /// Overwrites the current row with the change. Only allowed for
+    /// [`Conflict::Data`] and [`Conflict::Conflict`], otherwise applying fails
+    Replace,
+    /// Undoes everything applied so far
+    Abort,
+}
+
+unsafe extern "C" fn call_conflict<F>(
+    data: *mut c_void,
+    kind: c_int,
+    iter: *mut sqlite3_changeset_iter,
+) -> c_int
+where
+    F: FnMut(&Conflict) -> ConflictAction,
+{
+    let callback = unsafe { &mut *(data as *mut F) };
+    let Ok(conflict) = (unsafe { read_conflict(kind, iter) }) else {
+        return SQLITE_CHANGESET_ABORT;
+    };
+
+    match catch_unwind(AssertUnwindSafe(|| callback(&conflict))) {
+        Ok(ConflictAction::Omit) => SQLITE_CHANGESET_OMIT,
+        Ok(ConflictAction::Replace) => SQLITE_CHANGESET_REPLACE,
+        Ok(ConflictAction::Abort) | Err(_) => SQLITE_CHANGESET_ABORT,
+    }
+}
+
+unsafe fn read_conflict(
+    kind: c_int,
+    iter: *mut sqlite3_changeset_iter,
+) -> Result<Conflict, SessionError> {
+    // the iterator does not point at a change for foreign keys
+    if kind == SQLITE_CHANGESET_FOREIGN_KEY {
+        let mut count = 0;
+        check(unsafe { sqlite3changeset_fk_conflicts(iter, &mut count) })?;
+        return Ok(Conflict::ForeignKey {
+            count: count as usize,
+        });
+    }
+
+    let change = unsafe { Change::read(iter) }?;
+    let current = || -> Result<Vec<Value>, SessionError> {
+        let columns = change.primary_key.len() as c_int;
+        let values = unsafe { values(iter, columns, sqlite3changeset_conflict) }?;
+        Ok(values
+            .into_iter()
+            .map(|v| v.unwrap_or(Value::Null))
+            .collect())
+    };
+
+    Ok(match kind {
+        SQLITE_CHANGESET_DATA => Conflict::Data {
+            current: current()?,
+            change,
+        },
+        SQLITE_CHANGESET_CONFLICT => Conflict::Conflict {
+            current: current()?,
+            change,
+        },
+        SQLITE_CHANGESET_NOTFOUND => Conflict::NotFound { change },
+        SQLITE_CHANGESET_CONSTRAINT => Conflict::Constraint { change },
+        _ => return Err(errstr_failure(libsqlite3_sys::SQLITE_MISUSE)),
+    })
+}
+
+fn changeset_len(changeset: &[u8]) -> Result<c_int, SessionError> {
+    c_int::try_from(changeset.len()).map_err(|_| SessionError::TooLarge {
+        len: changeset.len(),
+    })
+}
+
+fn c_name(name: &str) -> Result<CString, SessionError> {
+    CString::new(name).map_err(|_| SessionError::EmbeddedNullInName {
+        name: name.to_string(),
+    })
+}
+
+fn check(code: c_int) -> Result<(), SessionError> {
+    if code != SQLITE_OK {
+        return Err(errstr_failure(code));
+    }
+    Ok(())
+}
+
+/// The error of the connection, or the generic message of `code`
+/// if the session extension did not set one
+unsafe fn failure(db: *mut sqlite3, code: c_int) -> SessionError {
+    let (errcode, error_msg) = unsafe { get_sqlite_failiure(db) };
+    if errcode == SQLITE_OK {
+        return errstr_failure(code);
+    }
+    SqliteFailure { code, error_msg }.into()
+}
+
+fn errstr_failure(code: c_int) -> SessionError {
+    let error_msg = unsafe { CStr::from_ptr(sqlite3_errstr(code)) }
+        .to_string_lossy()
+        .into_owned();
+    SqliteFailure { code, error_msg }.into()
+}
+
+/// `data` may be a null pointer when empty
+unsafe fn bytes<'a>(data: *const u8, len: c_int) -> &'a [u8] {
+    if data.is_null() || len <= 0 {
+        return &[];
+    }
+    unsafe { slice::from_raw_parts(data, len as usize) }
+}
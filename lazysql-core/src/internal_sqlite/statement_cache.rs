@@ -0,0 +1,115 @@
+use std::collections::VecDeque;
+
+use libsqlite3_sys::{sqlite3_clear_bindings, sqlite3_finalize, sqlite3_reset, sqlite3_stmt};
+
+/// Default number of prepared statements `query_dynamic` and `execute_dynamic` keep around.
+pub const DEFAULT_STATEMENT_CACHE_CAPACITY: usize = 16;
+
+/// Snapshot of the dynamic statement cache counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatementCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub len: usize,
+    pub capacity: usize,
+}
+
+/// Bounded LRU cache of prepared statements keyed by their sql text.
+///
+/// A statement is removed from the cache while it is in use and handed back once done,
+/// so the same sql can be run twice at once (the second one is simply prepared again).
+pub struct StatementCache {
+    capacity: usize,
+    // front is the most recently used
+    entries: VecDeque<(String, *mut sqlite3_stmt)>,
+    hits: u64,
+    misses: u64,
+}
+
+impl StatementCache {
+    pub fn new(capacity: usize) -> Self {
+        StatementCache {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Takes the statement for `sql` out of the cache, if there is one.
+    pub fn take(&mut self, sql: &str) -> Option<*mut sqlite3_stmt> {
+        match self.entries.iter().position(|(key, _)| key == sql) {
+            Some(pos) => {
+                self.hits += 1;
+                self.entries.remove(pos).map(|(_, stmt)| stmt)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Hands a statement back to the cache. It is reset and its bindings are cleared so the
+    /// next user starts from a clean state. The least recently used statement is finalized
+    /// if the cache is full.
+    ///
+    /// # Safety
+    /// stmt must be a valid prepared statement that is not used anywhere else
+    pub unsafe fn put(&mut self, sql: &str, stmt: *mut sqlite3_stmt) {
+        unsafe {
+            sqlite3_reset(stmt);
+            sqlite3_clear_bindings(stmt);
+        }
+
+        if self.capacity == 0 {
+            unsafe { sqlite3_finalize(stmt) };
+            return;
+        }
+
+        // the same sql might have been prepared twice while the first one was checked out
+        if let Some(pos) = self.entries.iter().position(|(key, _)| key == sql)
+            && let Some((_, old)) = self.entries.remove(pos)
+        {
+            unsafe { sqlite3_finalize(old) };
+        }
+
+        self.entries.push_front((sql.to_string(), stmt));
+        self.evict();
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub fn stats(&self) -> StatementCacheStats {
+        StatementCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            len: self.entries.len(),
+            capacity: self.capacity,
+        }
+    }
+
+    /// Finalizes every cached statement. The hit/miss counters are kept.
+    pub fn clear(&mut self) {
+        for (_, stmt) in self.entries.drain(..) {
+            unsafe { sqlite3_finalize(stmt) };
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            if let Some((_, stmt)) = self.entries.pop_back() {
+                unsafe { sqlite3_finalize(stmt) };
+            }
+        }
+    }
+}
+
+impl Drop for StatementCache {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
@@ -0,0 +1,36 @@
+use std::ffi::c_int;
+
+use libsqlite3_sys::{SQLITE_OPEN_FULLMUTEX, SQLITE_OPEN_NOMUTEX};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// How a [`LazyConnection`](crate::internal_sqlite::lazy_connection::LazyConnection)
+/// may be used across threads. Chosen when the connection is opened.
+pub trait ThreadingMode: sealed::Sealed + 'static {
+    /// flag passed to `sqlite3_open_v2`
+    const OPEN_FLAG: c_int;
+}
+
+/// Opened with `SQLITE_OPEN_FULLMUTEX`. SQLite serializes every call on the connection,
+/// so it is `Send + Sync` and can be shared between threads through an `Arc`.
+/// This is the default.
+#[derive(Debug, Clone, Copy)]
+pub struct Serialized;
+
+/// Opened with `SQLITE_OPEN_NOMUTEX`. Skips SQLite's connection mutex, hence the connection
+/// is `Send` but not `Sync`: it can be moved to another thread but never used by two at once.
+#[derive(Debug, Clone, Copy)]
+pub struct PerThread;
+
+impl sealed::Sealed for Serialized {}
+impl sealed::Sealed for PerThread {}
+
+impl ThreadingMode for Serialized {
+    const OPEN_FLAG: c_int = SQLITE_OPEN_FULLMUTEX;
+}
+
+impl ThreadingMode for PerThread {
+    const OPEN_FLAG: c_int = SQLITE_OPEN_NOMUTEX;
+}
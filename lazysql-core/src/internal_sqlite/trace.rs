@@ -0,0 +1,216 @@
+use std::{
+    ffi::{CStr, c_char, c_int, c_uint, c_void},
+    io::{self, Write},
+    ops::BitOr,
+    panic::{AssertUnwindSafe, catch_unwind},
+    ptr,
+    time::Duration,
+};
+
+use libsqlite3_sys::{
+    SQLITE_TRACE_PROFILE, SQLITE_TRACE_ROW, SQLITE_TRACE_STMT, sqlite3_expanded_sql, sqlite3_free,
+    sqlite3_sql, sqlite3_stmt, sqlite3_trace_v2,
+};
+
+use crate::{
+    internal_sqlite::{lazy_connection::LazyConnection, threading::ThreadingMode},
+    utility::utils::DbMutexGuard,
+};
+
+/// Which events reach the [`TraceSink`], combined with `|`
+/// (e.g. `TraceEvents::STATEMENT | TraceEvents::PROFILE`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEvents(c_uint);
+
+impl TraceEvents {
+    /// A statement starts running (and every trigger it fires)
+    pub const STATEMENT: TraceEvents = TraceEvents(SQLITE_TRACE_STMT);
+    /// A statement finished, with how long it took
+    pub const PROFILE: TraceEvents = TraceEvents(SQLITE_TRACE_PROFILE);
+    /// A statement returned a row
+    pub const ROW: TraceEvents = TraceEvents(SQLITE_TRACE_ROW);
+    pub const ALL: TraceEvents =
+        TraceEvents(SQLITE_TRACE_STMT | SQLITE_TRACE_PROFILE | SQLITE_TRACE_ROW);
+
+    pub fn contains(self, other: TraceEvents) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for TraceEvents {
+    type Output = TraceEvents;
+
+    fn bitor(self, rhs: TraceEvents) -> TraceEvents {
+        TraceEvents(self.0 | rhs.0)
+    }
+}
+
+/// How bound parameters show up in the traced SQL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceParams {
+    /// replaced by their values (`sqlite3_expanded_sql`), e.g. `WHERE id = 42`
+    Inlined,
+    /// left as placeholders, e.g. `WHERE id = ?`. Keeps passwords and personal data out of logs
+    Redacted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceEvent<'a> {
+    Statement { sql: &'a str },
+    Profile { sql: &'a str, elapsed: Duration },
+    Row { sql: &'a str },
+}
+
+impl TraceEvent<'_> {
+    pub fn sql(&self) -> &str {
+        match self {
+            TraceEvent::Statement { sql }
+            | TraceEvent::Profile { sql, .. }
+            | TraceEvent::Row { sql } => sql,
+        }
+    }
+}
+
+/// Receives the events of [`LazyConnection::trace`]. Implemented for closures
+/// taking a `&TraceEvent`.
+///
+/// It is called while SQLite executes the statement, so it must not use the connection.
+/// A panic inside it is ignored.
+pub trait TraceSink: Send {
+    fn event(&mut self, event: &TraceEvent);
+}
+
+impl<F: FnMut(&TraceEvent) + Send> TraceSink for F {
+    fn event(&mut self, event: &TraceEvent) {
+        self(event)
+    }
+}
+
+/// A [`TraceSink`] writing every statement that took at least `threshold` to stderr
+/// (or another writer), one line each. Needs [`TraceEvents::PROFILE`].
+pub struct SlowQueryLogger {
+    threshold: Duration,
+    out: Box<dyn Write + Send>,
+}
+
+impl SlowQueryLogger {
+    pub fn new(threshold: Duration) -> Self {
+        Self::with_writer(threshold, io::stderr())
+    }
+
+    pub fn with_writer(threshold: Duration, out: impl Write + Send + 'static) -> Self {
+        SlowQueryLogger {
+            threshold,
+            out: Box::new(out),
+        }
+    }
+}
+
+impl TraceSink for SlowQueryLogger {
+    fn event(&mut self, event: &TraceEvent) {
+        if let TraceEvent::Profile { sql, elapsed } = event
+            && *elapsed >= self.threshold
+        {
+            // a full disk is no reason to fail the query
+            let _ = writeln!(self.out, "slow query ({elapsed:?}): {sql}");
+        }
+    }
+}
+
+pub(crate) struct Tracer {
+    params: TraceParams,
+    sink: Box<dyn TraceSink>,
+}
+
+impl<M: ThreadingMode> LazyConnection<M> {
+    /// Sends `events` of every statement run on this connection to `sink`,
+    /// replacing the previous sink. Generated `lazy_sql` methods are traced as well,
+    /// except the read only queries of structs created with `from_pool`,
+    /// which run on another connection.
+    pub fn trace(&self, events: TraceEvents, params: TraceParams, sink: impl TraceSink + 'static) {
+        let tracer = Box::new(Tracer {
+            params,
+            sink: Box::new(sink),
+        });
+        let data = &*tracer as *const Tracer as *mut c_void;
+
+        // a callback in flight holds the connection mutex, so the old sink is unused afterwards
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        unsafe { sqlite3_trace_v2(self.db, events.0, Some(trace_callback), data) };
+        *self.tracer.lock().unwrap_or_else(|e| e.into_inner()) = Some(tracer);
+    }
+
+    /// Removes the sink set by [`trace`](Self::trace)
+    pub fn untrace(&self) {
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        unsafe { sqlite3_trace_v2(self.db, 0, None, ptr::null_mut()) };
+        *self.tracer.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+unsafe extern "C" fn trace_callback(
+    event: c_uint,
+    data: *mut c_void,
+    p: *mut c_void,
+    x: *mut c_void,
+) -> c_int {
+    let tracer = unsafe { &mut *(data as *mut Tracer) };
+    let stmt = p as *mut sqlite3_stmt;
+
+    // triggers are reported as a `-- TRIGGER name` comment
+    if event == SQLITE_TRACE_STMT {
+        let text = unsafe { CStr::from_ptr(x as *const c_char) }.to_string_lossy();
+        if text.starts_with("--") {
+            tracer.deliver(&TraceEvent::Statement { sql: &text });
+            return 0;
+        }
+    }
+
+    let sql = unsafe { statement_sql(stmt, tracer.params) };
+    let sql = sql.as_deref().unwrap_or("");
+    let event = match event {
+        SQLITE_TRACE_STMT => TraceEvent::Statement { sql },
+        SQLITE_TRACE_PROFILE => {
+            let nanos = unsafe { *(x as *const i64) };
+            TraceEvent::Profile {
+                sql,
+                elapsed: Duration::from_nanos(nanos.max(0) as u64),
+            }
+        }
+        SQLITE_TRACE_ROW => TraceEvent::Row { sql },
+        _ => return 0,
+    };
+    tracer.deliver(&event);
+    0
+}
+
+impl Tracer {
+    fn deliver(&mut self, event: &TraceEvent) {
+        let _ = catch_unwind(AssertUnwindSafe(|| self.sink.event(event)));
+    }
+}
+
+/// The SQL of `stmt`, with its parameters inlined if asked for.
+/// Falls back to the placeholders if expanding fails (out of memory or too long)
+unsafe fn statement_sql(stmt: *mut sqlite3_stmt, params: TraceParams) -> Option<String> {
+    if params == TraceParams::Inlined {
+        let expanded = unsafe { sqlite3_expanded_sql(stmt) };
+        if !expanded.is_null() {
+            let sql = unsafe { CStr::from_ptr(expanded) }
+                .to_string_lossy()
+                .into_owned();
+            unsafe { sqlite3_free(expanded as *mut c_void) };
+            return Some(sql);
+        }
+    }
+
+    let sql = unsafe { sqlite3_sql(stmt) };
+    if sql.is_null() {
+        return None;
+    }
+    Some(
+        unsafe { CStr::from_ptr(sql) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
@@ -0,0 +1,305 @@
+use std::{
+    any::Any,
+    error::Error as StdError,
+    ffi::c_int,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+use crate::{
+    errors::{Error, ErrorCode, SqliteFailure},
+    internal_sqlite::{
+        lazy_connection::LazyConnection,
+        lazy_pool::WriterLock,
+        retry::{self, Attempt, is_busy},
+        threading::ThreadingMode,
+    },
+};
+
+/// How `BEGIN` acquires its locks. See <https://www.sqlite.org/lang_transaction.html>
+///
+/// `Deferred` only takes the write lock on the first write, which fails with `SQLITE_BUSY` if
+/// another connection wrote in the meantime. `Immediate` takes it right away (waiting for the
+/// busy timeout if needed), so it is the safer choice for transactions that write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransactionBehavior {
+    #[default]
+    Deferred,
+    Immediate,
+    Exclusive,
+}
+
+impl TransactionBehavior {
+    pub fn begin_sql(self) -> &'static str {
+        match self {
+            TransactionBehavior::Deferred => "BEGIN DEFERRED",
+            TransactionBehavior::Immediate => "BEGIN IMMEDIATE",
+            TransactionBehavior::Exclusive => "BEGIN EXCLUSIVE",
+        }
+    }
+}
+
+/// Something a [`Transaction`] can be opened on: a `&LazyConnection` or a `&mut` to a
+/// `#[lazy_sql]` struct. The transaction derefs to it, so its methods stay callable.
+pub trait TransactionTarget {
+    type Mode: ThreadingMode;
+
+    fn connection(&self) -> &LazyConnection<Self::Mode>;
+
+    /// Marks the target as being inside a transaction and returns whether it already was
+    #[doc(hidden)]
+    fn enter_transaction(&mut self) -> bool {
+        true
+    }
+
+    #[doc(hidden)]
+    fn leave_transaction(&mut self, _was_in_transaction: bool) {}
+
+    /// Lock a pooled target has to hold for as long as the transaction is open
+    #[doc(hidden)]
+    fn lock_writer(&self) -> Option<WriterLock> {
+        None
+    }
+}
+
+impl<M: ThreadingMode> TransactionTarget for &LazyConnection<M> {
+    type Mode = M;
+
+    fn connection(&self) -> &LazyConnection<M> {
+        self
+    }
+}
+
+/// An open transaction (or savepoint, if another transaction was already open), created with
+/// `begin()` on a `LazyConnection` or a `#[lazy_sql]` struct.
+///
+/// It derefs to whatever it was opened on, so queries run through it. It ends with
+/// [`commit`](Transaction::commit) or [`rollback`](Transaction::rollback) and rolls back if
+/// dropped without either.
+pub struct Transaction<'a, H: TransactionTarget = &'a LazyConnection> {
+    target: H,
+    savepoint: Option<Savepoint>,
+    was_in_transaction: bool,
+    finished: bool,
+    /// `retry::busy_steps()` when it started
+    busy_mark: u64,
+    // released only after the transaction ended
+    _writer_lock: Option<WriterLock>,
+    _marker: PhantomData<&'a ()>,
+}
+
+struct Savepoint {
+    name: String,
+    /// opened by a nested `begin`/`transaction` rather than by `savepoint(name)`
+    nested: bool,
+    /// `pending_change_mark` when it was opened, the changes after it are discarded on rollback
+    change_mark: usize,
+}
+
+impl<H: TransactionTarget> Transaction<'_, H> {
+    /// Starts a transaction, or a `SAVEPOINT sp_N` if one is already open (`behavior` is then ignored).
+    #[doc(hidden)]
+    pub fn begin(target: H, behavior: TransactionBehavior) -> Result<Self, SqliteFailure> {
+        let writer_lock = target.lock_writer();
+        let conn = target.connection();
+
+        let savepoint = if conn.in_transaction() {
+            let name = conn.push_savepoint();
+            let change_mark = conn.pending_change_mark();
+            if let Err(e) = conn.exec(&format!("SAVEPOINT {name}")) {
+                conn.pop_savepoint();
+                return Err(e);
+            }
+            Some(Savepoint {
+                name,
+                nested: true,
+                change_mark,
+            })
+        } else {
+            conn.exec(behavior.begin_sql())?;
+            None
+        };
+
+        Ok(Self::started(target, savepoint, writer_lock))
+    }
+
+    /// Opens `SAVEPOINT name`. Outside of a transaction it behaves like a deferred transaction.
+    #[doc(hidden)]
+    pub fn savepoint(target: H, name: &str) -> Result<Self, SqliteFailure> {
+        let writer_lock = target.lock_writer();
+        let name = format!("\"{}\"", name.replace('"', "\"\""));
+        let change_mark = target.connection().pending_change_mark();
+        target.connection().exec(&format!("SAVEPOINT {name}"))?;
+
+        let savepoint = Savepoint {
+            name,
+            nested: false,
+            change_mark,
+        };
+        Ok(Self::started(target, Some(savepoint), writer_lock))
+    }
+
+    fn started(mut target: H, savepoint: Option<Savepoint>, writer_lock: Option<WriterLock>) -> Self {
+        let was_in_transaction = target.enter_transaction();
+        Transaction {
+            target,
+            savepoint,
+            was_in_transaction,
+            finished: false,
+            busy_mark: retry::busy_steps(),
+            _writer_lock: writer_lock,
+            _marker: PhantomData,
+        }
+    }
+
+    /// `COMMIT`, or `RELEASE` for a savepoint. Rolls back if that fails.
+    pub fn commit(mut self) -> Result<(), SqliteFailure> {
+        self.finish(true)
+    }
+
+    /// `ROLLBACK`, or `ROLLBACK TO` for a savepoint
+    pub fn rollback(mut self) -> Result<(), SqliteFailure> {
+        self.finish(false)
+    }
+
+    /// Commits if `result` is `Ok` and rolls back otherwise. Used by the closure based `transaction` methods
+    #[doc(hidden)]
+    pub fn finish_with<T, E>(self, result: Result<T, E>) -> Result<T, E>
+    where
+        E: From<Error> + Into<Box<dyn StdError + Send + Sync>>,
+    {
+        match self.finish_inner(result, |_| false) {
+            Attempt::Done(result) => result,
+            Attempt::Busy(err) => Err(err),
+        }
+    }
+
+    /// Same as `finish_with`, but tells whether the transaction failed because of
+    /// `SQLITE_BUSY`/`SQLITE_LOCKED` and may be retried. Savepoints never are, only the
+    /// outermost transaction can be re-run from scratch.
+    ///
+    /// The result code of `E` decides if it is lazysql's `Error` or a boxed error. Other error
+    /// types count as busy if one of the statements run since `BEGIN` failed that way.
+    #[doc(hidden)]
+    pub fn finish_attempt<T, E>(self, result: Result<T, E>) -> Attempt<T, E>
+    where
+        E: From<Error> + Into<Box<dyn StdError + Send + Sync>> + 'static,
+    {
+        let busy_step = retry::busy_steps() > self.busy_mark;
+        self.finish_inner(result, |cause| match sqlite_code_of(cause) {
+            Some(code) => code.is_some_and(is_busy),
+            None => busy_step,
+        })
+    }
+
+    fn finish_inner<T, E>(
+        self,
+        result: Result<T, E>,
+        is_busy_error: impl Fn(&E) -> bool,
+    ) -> Attempt<T, E>
+    where
+        E: From<Error> + Into<Box<dyn StdError + Send + Sync>>,
+    {
+        let retryable = !self.is_savepoint();
+
+        match result {
+            Ok(val) => match self.commit() {
+                Ok(()) => Attempt::Done(Ok(val)),
+                Err(e) if retryable && is_busy(e.code) => Attempt::Busy(E::from(Error::Commit(e))),
+                Err(e) => Attempt::Done(Err(E::from(Error::Commit(e)))),
+            },
+            Err(cause) => {
+                let busy = retryable && is_busy_error(&cause);
+
+                match self.rollback() {
+                    Ok(()) if busy => Attempt::Busy(cause),
+                    Ok(()) => Attempt::Done(Err(cause)),
+                    Err(rollback) => Attempt::Done(Err(E::from(Error::Rollback {
+                        cause: cause.into(),
+                        rollback,
+                    }))),
+                }
+            }
+        }
+    }
+
+    /// Whether this is a savepoint inside another transaction rather than a transaction of its own
+    pub fn is_savepoint(&self) -> bool {
+        self.savepoint.is_some()
+    }
+
+    fn finish(&mut self, commit: bool) -> Result<(), SqliteFailure> {
+        self.finished = true;
+        self.target.leave_transaction(self.was_in_transaction);
+        let conn = self.target.connection();
+
+        let result = match &self.savepoint {
+            None if commit => conn.exec("COMMIT").inspect_err(|_| {
+                // a failed COMMIT might leave the transaction open (e.g. SQLITE_BUSY)
+                if conn.in_transaction() {
+                    let _ = conn.exec("ROLLBACK");
+                }
+            }),
+            // SQLite already rolled back on its own after some errors
+            None if !conn.in_transaction() => Ok(()),
+            None => conn.exec("ROLLBACK"),
+            Some(savepoint) => {
+                let released = if commit {
+                    conn.exec(&format!("RELEASE {}", savepoint.name))
+                } else {
+                    conn.exec(&format!("ROLLBACK TO {}", savepoint.name))
+                        .and_then(|_| conn.exec(&format!("RELEASE {}", savepoint.name)))
+                };
+                if commit && released.is_err() {
+                    // ROLLBACK TO keeps the savepoint open, hence the RELEASE
+                    let _ = conn.exec(&format!("ROLLBACK TO {}", savepoint.name));
+                    let _ = conn.exec(&format!("RELEASE {}", savepoint.name));
+                }
+                if !commit || released.is_err() {
+                    conn.discard_changes_since(savepoint.change_mark);
+                }
+                released
+            }
+        };
+
+        if let Some(Savepoint { nested: true, .. }) = self.savepoint {
+            conn.pop_savepoint();
+        }
+        result
+    }
+}
+
+/// The result code behind `cause` if its type can be looked into (lazysql's `Error` or a boxed
+/// error), `None` for any other type
+fn sqlite_code_of<E: 'static>(cause: &E) -> Option<Option<c_int>> {
+    let cause = cause as &dyn Any;
+    if let Some(e) = cause.downcast_ref::<Error>() {
+        Some(e.sqlite_code())
+    } else {
+        cause
+            .downcast_ref::<Box<dyn StdError + Send + Sync>>()
+            .map(|e| e.sqlite_code())
+    }
+}
+
+impl<H: TransactionTarget> Drop for Transaction<'_, H> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.finish(false);
+        }
+    }
+}
+
+impl<H: TransactionTarget + Deref> Deref for Transaction<'_, H> {
+    type Target = H::Target;
+
+    fn deref(&self) -> &Self::Target {
+        &self.target
+    }
+}
+
+impl<H: TransactionTarget + DerefMut> DerefMut for Transaction<'_, H> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.target
+    }
+}
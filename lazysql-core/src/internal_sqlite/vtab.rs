@@ -0,0 +1,562 @@
+use std::{
+    ffi::{CStr, CString, c_char, c_int, c_void},
+    mem,
+    panic::{AssertUnwindSafe, catch_unwind},
+    slice, thread,
+};
+
+use libsqlite3_sys::{
+    SQLITE_ERROR, SQLITE_INDEX_CONSTRAINT_EQ, SQLITE_INDEX_CONSTRAINT_GE,
+    SQLITE_INDEX_CONSTRAINT_GLOB, SQLITE_INDEX_CONSTRAINT_GT, SQLITE_INDEX_CONSTRAINT_IS,
+    SQLITE_INDEX_CONSTRAINT_ISNOT, SQLITE_INDEX_CONSTRAINT_ISNOTNULL,
+    SQLITE_INDEX_CONSTRAINT_ISNULL, SQLITE_INDEX_CONSTRAINT_LE, SQLITE_INDEX_CONSTRAINT_LIKE,
+    SQLITE_INDEX_CONSTRAINT_LIMIT, SQLITE_INDEX_CONSTRAINT_LT, SQLITE_INDEX_CONSTRAINT_MATCH,
+    SQLITE_INDEX_CONSTRAINT_NE, SQLITE_INDEX_CONSTRAINT_OFFSET, SQLITE_INDEX_CONSTRAINT_REGEXP,
+    SQLITE_INDEX_SCAN_UNIQUE, SQLITE_NULL, SQLITE_OK, sqlite3, sqlite3_context,
+    sqlite3_create_module_v2, sqlite3_declare_vtab, sqlite3_free, sqlite3_index_info,
+    sqlite3_module, sqlite3_mprintf, sqlite3_value, sqlite3_value_int64, sqlite3_value_type,
+    sqlite3_vtab, sqlite3_vtab_cursor,
+};
+
+use crate::{
+    errors::{ModuleError, SqliteFailure},
+    internal_sqlite::{
+        functions::{FunctionArgs, drop_boxed, panic_message, result_error},
+        lazy_connection::LazyConnection,
+        threading::ThreadingMode,
+    },
+    traits::{dynamic::Value, to_sql::ToSqlResult},
+    utility::utils::{DbMutexGuard, get_sqlite_failiure},
+};
+
+/// What the methods of [`Module`], [`VirtualTable`] and [`VTabCursor`] return. Any error
+/// converts into it with `?`, and so do strings: `Err("unknown option".into())`
+pub type VTabResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Creates the tables of a module registered with [`LazyConnection::create_module`].
+/// Implemented for closures taking the arguments.
+pub trait Module: Send + 'static {
+    type Table: VirtualTable;
+
+    /// Called for `CREATE VIRTUAL TABLE name USING module(args)` with the arguments as
+    /// written (including quotes), and with no arguments when the module is used as a table
+    /// of its own, e.g. `SELECT * FROM module` or `SELECT * FROM module(1, 2)`
+    fn connect(&self, args: &[&str]) -> VTabResult<Self::Table>;
+}
+
+impl<T, F> Module for F
+where
+    T: VirtualTable,
+    F: Fn(&[&str]) -> VTabResult<T> + Send + 'static,
+{
+    type Table = T;
+
+    fn connect(&self, args: &[&str]) -> VTabResult<T> {
+        self(args)
+    }
+}
+
+/// A table whose rows come from Rust.
+///
+/// It is read only unless `insert`, `update` and `delete` are implemented.
+/// An `Err` or a panic in any method fails the statement with that message.
+pub trait VirtualTable: Send + 'static {
+    type Cursor: VTabCursor;
+
+    /// The column definitions, e.g. `"key TEXT PRIMARY KEY, value TEXT"`. Columns marked
+    /// `HIDDEN` are left out of `SELECT *` and take the arguments of a table-valued function:
+    /// `SELECT * FROM module(1, 2)` is `WHERE hidden1 = 1 AND hidden2 = 2`
+    fn columns(&self) -> String;
+
+    /// Chooses how to scan the table for a query, see [`IndexInfo`]. By default every
+    /// constraint is checked by SQLite on a full scan
+    fn best_index(&self, info: &mut IndexInfo) -> VTabResult<()> {
+        let _ = info;
+        Ok(())
+    }
+
+    fn open(&self) -> VTabResult<Self::Cursor>;
+
+    /// Inserts a row with `values` (one per column, hidden ones included) and returns its rowid.
+    /// `rowid` is only set if the statement gave one
+    fn insert(&mut self, rowid: Option<i64>, values: &FunctionArgs) -> VTabResult<i64> {
+        let _ = (rowid, values);
+        Err("table is read only".into())
+    }
+
+    /// Replaces the row `old_rowid` with `values`, `new_rowid` differs if the rowid was changed
+    fn update(&mut self, old_rowid: i64, new_rowid: i64, values: &FunctionArgs) -> VTabResult<()> {
+        let _ = (old_rowid, new_rowid, values);
+        Err("table is read only".into())
+    }
+
+    fn delete(&mut self, rowid: i64) -> VTabResult<()> {
+        let _ = rowid;
+        Err("table is read only".into())
+    }
+}
+
+/// One scan over a [`VirtualTable`]. It cannot borrow from the table, share the data
+/// through e.g. an `Arc` instead.
+pub trait VTabCursor: Send + 'static {
+    /// Starts (or restarts) the scan and moves to the first row. `idx_num` and `idx_str`
+    /// are the ones set by `best_index`, `args` the values of the constraints it asked for
+    fn filter(
+        &mut self,
+        idx_num: i32,
+        idx_str: Option<&str>,
+        args: &FunctionArgs,
+    ) -> VTabResult<()>;
+
+    fn next(&mut self) -> VTabResult<()>;
+
+    /// Whether the scan moved past the last row
+    fn eof(&self) -> bool;
+
+    /// Column `index` of the current row, counted like in `columns` (hidden ones included)
+    fn column(&self, index: usize) -> VTabResult<Value>;
+
+    fn rowid(&self) -> VTabResult<i64>;
+}
+
+/// The operator of an [`IndexConstraint`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConstraintOp {
+    Eq,
+    Gt,
+    Le,
+    Lt,
+    Ge,
+    Match,
+    Like,
+    Glob,
+    Regexp,
+    Ne,
+    IsNot,
+    IsNotNull,
+    IsNull,
+    Is,
+    Limit,
+    Offset,
+    /// Operators SQLite adds later, or functions overloaded by a module (not supported here)
+    Function(u8),
+}
+
+/// A `WHERE` term on a column of the table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexConstraint {
+    /// `None` for the rowid
+    pub column: Option<usize>,
+    pub op: ConstraintOp,
+    /// Unusable constraints depend on tables that come later in the join and must be ignored
+    pub usable: bool,
+}
+
+/// A term of the `ORDER BY` clause
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexOrderBy {
+    pub column: Option<usize>,
+    pub desc: bool,
+}
+
+/// The query plan `best_index` fills in: which constraints are handed to
+/// [`VTabCursor::filter`] and what that costs.
+pub struct IndexInfo<'a> {
+    raw: &'a mut sqlite3_index_info,
+}
+
+impl IndexInfo<'_> {
+    pub fn constraints(&self) -> Vec<IndexConstraint> {
+        let raw = unsafe { raw_slice(self.raw.aConstraint, self.raw.nConstraint) };
+        raw.iter()
+            .map(|c| IndexConstraint {
+                column: usize::try_from(c.iColumn).ok(),
+                op: constraint_op(c.op),
+                usable: c.usable != 0,
+            })
+            .collect()
+    }
+
+    pub fn order_by(&self) -> Vec<IndexOrderBy> {
+        let raw = unsafe { raw_slice(self.raw.aOrderBy, self.raw.nOrderBy) };
+        raw.iter()
+            .map(|o| IndexOrderBy {
+                column: usize::try_from(o.iColumn).ok(),
+                desc: o.desc != 0,
+            })
+            .collect()
+    }
+
+    /// Passes the value of constraint `constraint` (its index in `constraints()`) to `filter`
+    /// as `args[argument]`. The arguments used have to be 0, 1, 2... without gaps.
+    /// With `omit` SQLite trusts the cursor to only return matching rows
+    pub fn use_constraint(&mut self, constraint: usize, argument: usize, omit: bool) {
+        assert!(
+            constraint < self.raw.nConstraint as usize,
+            "constraint {constraint} out of range"
+        );
+        let usage = unsafe { &mut *self.raw.aConstraintUsage.add(constraint) };
+        usage.argvIndex = argument as c_int + 1;
+        usage.omit = omit as u8;
+    }
+
+    /// A number handed to `filter` to tell the plans apart
+    pub fn set_idx_num(&mut self, idx_num: i32) {
+        self.raw.idxNum = idx_num;
+    }
+
+    /// A string handed to `filter`, for plans a number does not describe
+    pub fn set_idx_str(&mut self, idx_str: &str) {
+        if self.raw.needToFreeIdxStr != 0 {
+            unsafe { sqlite3_free(self.raw.idxStr as *mut c_void) };
+        }
+        self.raw.idxStr = sqlite_string(idx_str);
+        self.raw.needToFreeIdxStr = 1;
+    }
+
+    /// Roughly the number of disk reads the plan takes, SQLite picks the cheapest plan
+    pub fn set_estimated_cost(&mut self, cost: f64) {
+        self.raw.estimatedCost = cost;
+    }
+
+    pub fn set_estimated_rows(&mut self, rows: i64) {
+        self.raw.estimatedRows = rows;
+    }
+
+    /// The cursor returns the rows in the order of `order_by()`, so SQLite does not sort
+    pub fn set_order_by_consumed(&mut self, consumed: bool) {
+        self.raw.orderByConsumed = consumed as c_int;
+    }
+
+    /// The plan returns at most one row
+    pub fn set_unique(&mut self, unique: bool) {
+        if unique {
+            self.raw.idxFlags |= SQLITE_INDEX_SCAN_UNIQUE;
+        } else {
+            self.raw.idxFlags &= !SQLITE_INDEX_SCAN_UNIQUE;
+        }
+    }
+}
+
+impl<M: ThreadingMode> LazyConnection<M> {
+    /// Makes `module` usable as `CREATE VIRTUAL TABLE name USING module(...)`.
+    /// It is also eponymous: `SELECT * FROM module` and table-valued function calls
+    /// `SELECT * FROM module(1, 2)` work without creating a table first.
+    /// Registering the same name again replaces the module.
+    ///
+    /// `sql!` queries are validated without the module, use `query_dynamic` for them.
+    pub fn create_module<T: Module>(&self, name: &str, module: T) -> Result<(), ModuleError> {
+        let c_name = CString::new(name).map_err(|_| ModuleError::EmbeddedNullInName {
+            name: name.to_string(),
+        })?;
+        let data = Box::into_raw(Box::new(ModuleData {
+            raw: raw_module::<T>(),
+            module,
+        }));
+
+        let _lock = unsafe { DbMutexGuard::lock(self.db) };
+        // sqlite calls the destructor itself when this fails
+        let code = unsafe {
+            sqlite3_create_module_v2(
+                self.db,
+                c_name.as_ptr(),
+                &(*data).raw,
+                data as *mut c_void,
+                Some(drop_boxed::<ModuleData<T>>),
+            )
+        };
+        if code != SQLITE_OK {
+            let (code, error_msg) = unsafe { get_sqlite_failiure(self.db) };
+            return Err(SqliteFailure { code, error_msg }.into());
+        }
+        Ok(())
+    }
+}
+
+/// Handed to SQLite as client data, `raw` has to live as long as the module
+struct ModuleData<T> {
+    raw: sqlite3_module,
+    module: T,
+}
+
+#[repr(C)]
+struct RawTable<T> {
+    base: sqlite3_vtab,
+    table: T,
+}
+
+#[repr(C)]
+struct RawCursor<C> {
+    base: sqlite3_vtab_cursor,
+    cursor: C,
+}
+
+fn raw_module<T: Module>() -> sqlite3_module {
+    sqlite3_module {
+        iVersion: 1,
+        // the same function for both makes the module eponymous
+        xCreate: Some(connect::<T>),
+        xConnect: Some(connect::<T>),
+        xBestIndex: Some(best_index::<T::Table>),
+        xDisconnect: Some(disconnect::<T::Table>),
+        xDestroy: Some(disconnect::<T::Table>),
+        xOpen: Some(open::<T::Table>),
+        xClose: Some(close::<<T::Table as VirtualTable>::Cursor>),
+        xFilter: Some(filter::<<T::Table as VirtualTable>::Cursor>),
+        xNext: Some(next::<<T::Table as VirtualTable>::Cursor>),
+        xEof: Some(eof::<<T::Table as VirtualTable>::Cursor>),
+        xColumn: Some(column::<<T::Table as VirtualTable>::Cursor>),
+        xRowid: Some(rowid::<<T::Table as VirtualTable>::Cursor>),
+        xUpdate: Some(update::<T::Table>),
+        ..unsafe { mem::zeroed() }
+    }
+}
+
+unsafe extern "C" fn connect<T: Module>(
+    db: *mut sqlite3,
+    aux: *mut c_void,
+    argc: c_int,
+    argv: *const *const c_char,
+    vtab: *mut *mut sqlite3_vtab,
+    err: *mut *mut c_char,
+) -> c_int {
+    let data = unsafe { &*(aux as *const ModuleData<T>) };
+    // module name, database name and table name come first
+    let args: Vec<String> = unsafe { raw_slice(argv, argc) }
+        .iter()
+        .skip(3)
+        .map(|arg| {
+            unsafe { CStr::from_ptr(*arg) }
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        let table = data.module.connect(&args)?;
+        let columns = table.columns();
+        Ok((table, columns))
+    }));
+    let (table, columns) = match flatten(result) {
+        Ok(table) => table,
+        Err(msg) => {
+            unsafe { *err = sqlite_string(&msg) };
+            return SQLITE_ERROR;
+        }
+    };
+
+    let declaration = format!("CREATE TABLE x({columns})");
+    let code = match CString::new(declaration) {
+        Ok(sql) => unsafe { sqlite3_declare_vtab(db, sql.as_ptr()) },
+        Err(_) => SQLITE_ERROR,
+    };
+    if code != SQLITE_OK {
+        let msg = format!("invalid virtual table columns: {columns}");
+        unsafe { *err = sqlite_string(&msg) };
+        return code;
+    }
+
+    let raw = Box::new(RawTable {
+        base: unsafe { mem::zeroed() },
+        table,
+    });
+    unsafe { *vtab = Box::into_raw(raw) as *mut sqlite3_vtab };
+    SQLITE_OK
+}
+
+unsafe extern "C" fn best_index<T: VirtualTable>(
+    vtab: *mut sqlite3_vtab,
+    info: *mut sqlite3_index_info,
+) -> c_int {
+    let table = unsafe { &(*(vtab as *const RawTable<T>)).table };
+    let mut info = IndexInfo {
+        raw: unsafe { &mut *info },
+    };
+    unsafe { report(vtab, || table.best_index(&mut info)) }.map_or_else(|code| code, |()| SQLITE_OK)
+}
+
+unsafe extern "C" fn disconnect<T: VirtualTable>(vtab: *mut sqlite3_vtab) -> c_int {
+    drop(unsafe { Box::from_raw(vtab as *mut RawTable<T>) });
+    SQLITE_OK
+}
+
+unsafe extern "C" fn open<T: VirtualTable>(
+    vtab: *mut sqlite3_vtab,
+    cursor: *mut *mut sqlite3_vtab_cursor,
+) -> c_int {
+    let table = unsafe { &(*(vtab as *const RawTable<T>)).table };
+    match unsafe { report(vtab, || table.open()) } {
+        Ok(opened) => {
+            let raw = Box::new(RawCursor {
+                base: unsafe { mem::zeroed() },
+                cursor: opened,
+            });
+            unsafe { *cursor = Box::into_raw(raw) as *mut sqlite3_vtab_cursor };
+            SQLITE_OK
+        }
+        Err(code) => code,
+    }
+}
+
+unsafe extern "C" fn close<C: VTabCursor>(cursor: *mut sqlite3_vtab_cursor) -> c_int {
+    drop(unsafe { Box::from_raw(cursor as *mut RawCursor<C>) });
+    SQLITE_OK
+}
+
+unsafe extern "C" fn filter<C: VTabCursor>(
+    cursor: *mut sqlite3_vtab_cursor,
+    idx_num: c_int,
+    idx_str: *const c_char,
+    argc: c_int,
+    argv: *mut *mut sqlite3_value,
+) -> c_int {
+    let (vtab, cursor) = unsafe { cursor_parts::<C>(cursor) };
+    let idx_str = if idx_str.is_null() {
+        None
+    } else {
+        unsafe { CStr::from_ptr(idx_str) }.to_str().ok()
+    };
+    let args = unsafe { FunctionArgs::new(argc, argv) };
+
+    unsafe { report(vtab, || cursor.filter(idx_num, idx_str, &args)) }
+        .map_or_else(|code| code, |()| SQLITE_OK)
+}
+
+unsafe extern "C" fn next<C: VTabCursor>(cursor: *mut sqlite3_vtab_cursor) -> c_int {
+    let (vtab, cursor) = unsafe { cursor_parts::<C>(cursor) };
+    unsafe { report(vtab, || cursor.next()) }.map_or_else(|code| code, |()| SQLITE_OK)
+}
+
+unsafe extern "C" fn eof<C: VTabCursor>(cursor: *mut sqlite3_vtab_cursor) -> c_int {
+    let (_, cursor) = unsafe { cursor_parts::<C>(cursor) };
+    // there is no way to report an error here, a panic ends the scan
+    catch_unwind(AssertUnwindSafe(|| cursor.eof())).unwrap_or(true) as c_int
+}
+
+unsafe extern "C" fn column<C: VTabCursor>(
+    cursor: *mut sqlite3_vtab_cursor,
+    ctx: *mut sqlite3_context,
+    index: c_int,
+) -> c_int {
+    let (_, cursor) = unsafe { cursor_parts::<C>(cursor) };
+    let result = catch_unwind(AssertUnwindSafe(|| cursor.column(index as usize)));
+    // sqlite fails the statement on its own once a result error is set
+    match flatten(result) {
+        Ok(value) => unsafe { value.result_to(ctx) },
+        Err(msg) => unsafe { result_error(ctx, &msg) },
+    }
+    SQLITE_OK
+}
+
+unsafe extern "C" fn rowid<C: VTabCursor>(
+    cursor: *mut sqlite3_vtab_cursor,
+    rowid: *mut i64,
+) -> c_int {
+    let (vtab, cursor) = unsafe { cursor_parts::<C>(cursor) };
+    match unsafe { report(vtab, || cursor.rowid()) } {
+        Ok(id) => {
+            unsafe { *rowid = id };
+            SQLITE_OK
+        }
+        Err(code) => code,
+    }
+}
+
+/// `argv` is the old rowid for a delete, otherwise the old rowid (NULL for an insert),
+/// the new rowid and the column values
+unsafe extern "C" fn update<T: VirtualTable>(
+    vtab: *mut sqlite3_vtab,
+    argc: c_int,
+    argv: *mut *mut sqlite3_value,
+    rowid: *mut i64,
+) -> c_int {
+    let table = unsafe { &mut (*(vtab as *mut RawTable<T>)).table };
+    let argv = unsafe { raw_slice(argv, argc) };
+    let is_null = |value: *mut sqlite3_value| unsafe { sqlite3_value_type(value) } == SQLITE_NULL;
+    let int64 = |value: *mut sqlite3_value| unsafe { sqlite3_value_int64(value) };
+
+    let result = unsafe {
+        report(vtab, || {
+            if let [old] = argv {
+                return table.delete(int64(*old));
+            }
+
+            let values = FunctionArgs::new(argc - 2, argv[2..].as_ptr() as *mut *mut sqlite3_value);
+            if is_null(argv[0]) {
+                let new_rowid = (!is_null(argv[1])).then(|| int64(argv[1]));
+                *rowid = table.insert(new_rowid, &values)?;
+                Ok(())
+            } else {
+                table.update(int64(argv[0]), int64(argv[1]), &values)
+            }
+        })
+    };
+    result.map_or_else(|code| code, |()| SQLITE_OK)
+}
+
+/// The table of `cursor` and the cursor itself
+unsafe fn cursor_parts<'a, C>(cursor: *mut sqlite3_vtab_cursor) -> (*mut sqlite3_vtab, &'a mut C) {
+    let vtab = unsafe { (*cursor).pVtab };
+    (vtab, unsafe {
+        &mut (*(cursor as *mut RawCursor<C>)).cursor
+    })
+}
+
+/// Runs `f`, leaving an `Err` or a panic as the error message of `vtab` for SQLite to pick up
+unsafe fn report<T>(
+    vtab: *mut sqlite3_vtab,
+    f: impl FnOnce() -> VTabResult<T>,
+) -> Result<T, c_int> {
+    flatten(catch_unwind(AssertUnwindSafe(f))).map_err(|msg| {
+        unsafe {
+            sqlite3_free((*vtab).zErrMsg as *mut c_void);
+            (*vtab).zErrMsg = sqlite_string(&msg);
+        }
+        SQLITE_ERROR
+    })
+}
+
+fn flatten<T>(result: thread::Result<VTabResult<T>>) -> Result<T, String> {
+    match result {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(panic) => Err(panic_message(panic)),
+    }
+}
+
+/// A copy of `msg` allocated by sqlite, which frees it
+fn sqlite_string(msg: &str) -> *mut c_char {
+    let msg = CString::new(msg.replace('\0', "")).unwrap_or_default();
+    unsafe { sqlite3_mprintf(c"%s".as_ptr(), msg.as_ptr()) }
+}
+
+/// `data` may be a null pointer when empty
+unsafe fn raw_slice<'a, T>(data: *const T, len: c_int) -> &'a [T] {
+    if data.is_null() || len <= 0 {
+        return &[];
+    }
+    unsafe { slice::from_raw_parts(data, len as usize) }
+}
+
+fn constraint_op(op: u8) -> ConstraintOp {
+    match op as c_int {
+        SQLITE_INDEX_CONSTRAINT_EQ => ConstraintOp::Eq,
+        SQLITE_INDEX_CONSTRAINT_GT => ConstraintOp::Gt,
+        SQLITE_INDEX_CONSTRAINT_LE => ConstraintOp::Le,
+        SQLITE_INDEX_CONSTRAINT_LT => ConstraintOp::Lt,
+        SQLITE_INDEX_CONSTRAINT_GE => ConstraintOp::Ge,
+        SQLITE_INDEX_CONSTRAINT_MATCH => ConstraintOp::Match,
+        SQLITE_INDEX_CONSTRAINT_LIKE => ConstraintOp::Like,
+        SQLITE_INDEX_CONSTRAINT_GLOB => ConstraintOp::Glob,
+        SQLITE_INDEX_CONSTRAINT_REGEXP => ConstraintOp::Regexp,
+        SQLITE_INDEX_CONSTRAINT_NE => ConstraintOp::Ne,
+        SQLITE_INDEX_CONSTRAINT_ISNOT => ConstraintOp::IsNot,
+        SQLITE_INDEX_CONSTRAINT_ISNOTNULL => ConstraintOp::IsNotNull,
+        SQLITE_INDEX_CONSTRAINT_ISNULL => ConstraintOp::IsNull,
+        SQLITE_INDEX_CONSTRAINT_IS => ConstraintOp::Is,
+        SQLITE_INDEX_CONSTRAINT_LIMIT => ConstraintOp::Limit,
+        SQLITE_INDEX_CONSTRAINT_OFFSET => ConstraintOp::Offset,
+        _ => ConstraintOp::Function(op),
+    }
+}
@@ -3,7 +3,7 @@ pub enum Value {
     Integer(i64),
     Real(f64),
     Text(String),
-    // Blob(Vec<u8>),
+    Blob(Vec<u8>),
     Null,
 }
 
@@ -14,7 +14,7 @@ impl Value {
             Value::Integer(i) => i.to_string(),
             Value::Real(f) => f.to_string(),
             Value::Text(s) => s.clone(),
-            // Value::Blob(_) => "<Binary Data>".to_string(),
+            Value::Blob(_) => "<Binary Data>".to_string(),
             Value::Null => "NULL".to_string(),
         }
     }
@@ -59,8 +59,74 @@ impl Value {
         }
     }
 
+    /// Returns the bytes of a Blob (or of a Text). Empty for anything else
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Value::Blob(b) => b,
+            Value::Text(s) => s.as_bytes(),
+            _ => &[],
+        }
+    }
+
     /// checks for nulls.
     pub fn is_null(&self) -> bool {
         matches!(self, Value::Null)
     }
 }
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Integer(value)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Self {
+        Value::Integer(value as i64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Real(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Integer(value as i64)
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Self {
+        Value::Blob(value)
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(value: &[u8]) -> Self {
+        Value::Blob(value.to_vec())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        match value {
+            Some(v) => v.into(),
+            None => Value::Null,
+        }
+    }
+}
@@ -1,6 +1,11 @@
 use std::ffi::CStr;
 
-use libsqlite3_sys::{SQLITE_NULL, sqlite3_column_type, sqlite3_stmt};
+use libsqlite3_sys::{
+    SQLITE_BLOB, SQLITE_FLOAT, SQLITE_INTEGER, SQLITE_NULL, SQLITE_TEXT, sqlite3_column_type,
+    sqlite3_stmt, sqlite3_value, sqlite3_value_type,
+};
+
+use crate::traits::dynamic::Value;
 
 // no errors cuz sqlite does implicit conversion
 pub trait FromSql {
@@ -74,3 +79,86 @@ impl FromSql for Vec<u8> {
         unsafe { std::slice::from_raw_parts(ptr as *const u8, bytes as usize).to_vec() }
     }
 }
+
+/// Same as [`FromSql`] but reads a `sqlite3_value`, which is how arguments of
+/// user defined functions are handed over
+pub trait FromSqlValue {
+    /// # Safety
+    /// `value` must be a valid, protected sqlite3_value (e.g. an argument of the current function call)
+    unsafe fn from_sql_value(value: *mut sqlite3_value) -> Self;
+}
+
+impl FromSqlValue for String {
+    unsafe fn from_sql_value(value: *mut sqlite3_value) -> Self {
+        let c_string = unsafe { libsqlite3_sys::sqlite3_value_text(value) } as *const i8;
+        // NULL for a NULL value (or when out of memory)
+        if c_string.is_null() {
+            return String::new();
+        }
+        unsafe { CStr::from_ptr(c_string).to_string_lossy().into_owned() }
+    }
+}
+
+impl FromSqlValue for f64 {
+    unsafe fn from_sql_value(value: *mut sqlite3_value) -> Self {
+        unsafe { libsqlite3_sys::sqlite3_value_double(value) }
+    }
+}
+
+impl FromSqlValue for i32 {
+    unsafe fn from_sql_value(value: *mut sqlite3_value) -> Self {
+        unsafe { libsqlite3_sys::sqlite3_value_int(value) }
+    }
+}
+
+impl FromSqlValue for i64 {
+    unsafe fn from_sql_value(value: *mut sqlite3_value) -> Self {
+        unsafe { libsqlite3_sys::sqlite3_value_int64(value) }
+    }
+}
+
+impl FromSqlValue for bool {
+    unsafe fn from_sql_value(value: *mut sqlite3_value) -> Self {
+        let val = unsafe { libsqlite3_sys::sqlite3_value_int(value) };
+        val != 0
+    }
+}
+
+impl<T: FromSqlValue> FromSqlValue for Option<T> {
+    unsafe fn from_sql_value(value: *mut sqlite3_value) -> Self {
+        if unsafe { sqlite3_value_type(value) } == SQLITE_NULL {
+            None
+        } else {
+            Some(unsafe { T::from_sql_value(value) })
+        }
+    }
+}
+
+impl FromSqlValue for Vec<u8> {
+    unsafe fn from_sql_value(value: *mut sqlite3_value) -> Self {
+        let ptr = unsafe { libsqlite3_sys::sqlite3_value_blob(value) };
+        let bytes = unsafe { libsqlite3_sys::sqlite3_value_bytes(value) };
+
+        // NULL for a zero-length BLOB, same as sqlite3_column_blob
+        if ptr.is_null() {
+            return Vec::new();
+        }
+
+        unsafe { std::slice::from_raw_parts(ptr as *const u8, bytes as usize).to_vec() }
+    }
+}
+
+/// Keeps the storage class of the value
+impl FromSqlValue for Value {
+    unsafe fn from_sql_value(value: *mut sqlite3_value) -> Self {
+        unsafe {
+            match sqlite3_value_type(value) {
+                SQLITE_INTEGER => Value::Integer(i64::from_sql_value(value)),
+                SQLITE_FLOAT => Value::Real(f64::from_sql_value(value)),
+                SQLITE_TEXT => Value::Text(String::from_sql_value(value)),
+                SQLITE_BLOB => Value::Blob(Vec::from_sql_value(value)),
+                _ => Value::Null,
+            }
+        }
+    }
+}
@@ -1,4 +1,5 @@
 pub mod from_sql;
+pub mod params;
 pub mod row_mapper;
 pub mod to_sql;
 pub mod dynamic;
\ No newline at end of file
@@ -0,0 +1,169 @@
+use crate::{
+    errors::ParamsError,
+    internal_sqlite::preparred_statement::PreparredStmt,
+    traits::{dynamic::Value, to_sql::ToSql},
+};
+
+/// A set of parameters that can be bound to a statement in one go.
+///
+/// - Positional (`?`, `?NNN`): `()`, tuples of [`ToSql`], `&[Value]`, `Vec<Value>`, `[Value; N]`
+/// - Named (`:name`, `@name`, `$name`): `&[(&str, Value)]`, `Vec<(&str, Value)>`, `[(&str, Value); N]`
+///
+/// The number of parameters provided must match the number of parameters in the statement,
+/// and named ones must bind each of them exactly once.
+pub trait Params {
+    fn bind_params(self, stmt: &PreparredStmt) -> Result<(), ParamsError>;
+}
+
+fn check_count(stmt: &PreparredStmt, provided: usize) -> Result<(), ParamsError> {
+    let expected = stmt.parameter_count() as usize;
+    if expected != provided {
+        return Err(ParamsError::CountMismatch { expected, provided });
+    }
+    Ok(())
+}
+
+/// Accepts the name with (`:id`) or without (`id`) its prefix.
+fn resolve_name(stmt: &PreparredStmt, name: &str) -> Result<i32, ParamsError> {
+    let index = if name.starts_with([':', '@', '$', '?']) {
+        stmt.parameter_index(name)
+    } else {
+        [':', '@', '$']
+            .iter()
+            .find_map(|prefix| stmt.parameter_index(&format!("{prefix}{name}")))
+    };
+
+    index.ok_or_else(|| ParamsError::UnknownName(name.to_string()))
+}
+
+fn bind_positional(stmt: &PreparredStmt, values: &[Value]) -> Result<(), ParamsError> {
+    check_count(stmt, values.len())?;
+    for (i, value) in values.iter().enumerate() {
+        stmt.bind_parameter(i as i32 + 1, value)?;
+    }
+    Ok(())
+}
+
+fn bind_named(stmt: &PreparredStmt, values: &[(&str, Value)]) -> Result<(), ParamsError> {
+    check_count(stmt, values.len())?;
+    // with the count matching, no name repeating means every parameter is bound
+    let mut bound = vec![false; values.len()];
+    for (name, value) in values {
+        let index = resolve_name(stmt, name)?;
+        if std::mem::replace(&mut bound[index as usize - 1], true) {
+            return Err(ParamsError::DuplicateName(name.to_string()));
+        }
+        stmt.bind_parameter(index, value)?;
+    }
+    Ok(())
+}
+
+impl Params for () {
+    fn bind_params(self, stmt: &PreparredStmt) -> Result<(), ParamsError> {
+        check_count(stmt, 0)
+    }
+}
+
+impl Params for &[Value] {
+    fn bind_params(self, stmt: &PreparredStmt) -> Result<(), ParamsError> {
+        bind_positional(stmt, self)
+    }
+}
+
+impl Params for &Vec<Value> {
+    fn bind_params(self, stmt: &PreparredStmt) -> Result<(), ParamsError> {
+        bind_positional(stmt, self)
+    }
+}
+
+impl Params for Vec<Value> {
+    fn bind_params(self, stmt: &PreparredStmt) -> Result<(), ParamsError> {
+        bind_positional(stmt, &self)
+    }
+}
+
+impl<const N: usize> Params for [Value; N] {
+    fn bind_params(self, stmt: &PreparredStmt) -> Result<(), ParamsError> {
+        bind_positional(stmt, &self)
+    }
+}
+
+impl<const N: usize> Params for &[Value; N] {
+    fn bind_params(self, stmt: &PreparredStmt) -> Result<(), ParamsError> {
+        bind_positional(stmt, self)
+    }
+}
+
+impl Params for &[(&str, Value)] {
+    fn bind_params(self, stmt: &PreparredStmt) -> Result<(), ParamsError> {
+        bind_named(stmt, self)
+    }
+}
+
+impl Params for &Vec<(&str, Value)> {
+    fn bind_params(self, stmt: &PreparredStmt) -> Result<(), ParamsError> {
+        bind_named(stmt, self)
+    }
+}
+
+impl Params for Vec<(&str, Value)> {
+    fn bind_params(self, stmt: &PreparredStmt) -> Result<(), ParamsError> {
+        bind_named(stmt, &self)
+    }
+}
+
+impl<const N: usize> Params for [(&str, Value); N] {
+    fn bind_params(self, stmt: &PreparredStmt) -> Result<(), ParamsError> {
+        bind_named(stmt, &self)
+    }
+}
+
+impl<const N: usize> Params for &[(&str, Value); N] {
+    fn bind_params(self, stmt: &PreparredStmt) -> Result<(), ParamsError> {
+        bind_named(stmt, self)
+    }
+}
+
+macro_rules! impl_params_for_tuple {
+    ($count:expr; $($name:ident : $index:tt),+) => {
+        impl<$($name: ToSql),+> Params for ($($name,)+) {
+            fn bind_params(self, stmt: &PreparredStmt) -> Result<(), ParamsError> {
+                check_count(stmt, $count)?;
+                $(stmt.bind_parameter($index + 1, self.$index)?;)+
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_params_for_tuple!(1; A: 0);
+impl_params_for_tuple!(2; A: 0, B: 1);
+impl_params_for_tuple!(3; A: 0, B: 1, C: 2);
+impl_params_for_tuple!(4; A: 0, B: 1, C: 2, D: 3);
+impl_params_for_tuple!(5; A: 0, B: 1, C: 2, D: 3, E: 4);
+impl_params_for_tuple!(6; A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+impl_params_for_tuple!(7; A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6);
+impl_params_for_tuple!(8; A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7);
+impl_params_for_tuple!(9; A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8);
+impl_params_for_tuple!(10; A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9);
+impl_params_for_tuple!(11; A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9, K: 10);
+impl_params_for_tuple!(12; A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9, K: 10, L: 11);
+
+/// Builds a set of [`Params`](crate::traits::params::Params) for
+/// `query_dynamic_with` and `execute_dynamic_with`.
+///
+/// - `params![]` binds nothing
+/// - `params![1, "Alice", 2.5]` binds positionally. Anything implementing `ToSql` works
+/// - `params![":id" => 1, ":name" => "Alice"]` binds by name. Values are converted into a `Value`
+#[macro_export]
+macro_rules! params {
+    () => {
+        ()
+    };
+    ($($name:literal => $value:expr),+ $(,)?) => {
+        [$(($name, $crate::traits::dynamic::Value::from($value))),+]
+    };
+    ($($value:expr),+ $(,)?) => {
+        ($($value,)+)
+    };
+}
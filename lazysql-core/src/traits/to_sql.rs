@@ -1,6 +1,7 @@
-use libsqlite3_sys::{self as ffi, SQLITE_TRANSIENT, sqlite3_stmt};
+use libsqlite3_sys::{self as ffi, SQLITE_TRANSIENT, sqlite3_context, sqlite3_stmt};
+
+use crate::traits::dynamic::Value;
 
-// BLOB not implemented yet. TODO
 pub trait ToSql {
     /// - it is ok for it to be self consuming (tho it only applies to String)
     ///   because we are not gonna be using this rust type anymore
@@ -83,4 +84,142 @@ impl ToSql for &[u8] {
             )
         }
     }
-}
\ No newline at end of file
+}
+
+impl ToSql for Vec<u8> {
+    unsafe fn bind_to(self, stmt: *mut sqlite3_stmt, index: i32) -> i32 {
+        unsafe { self.as_slice().bind_to(stmt, index) }
+    }
+}
+
+/// A BLOB of `n` zero bytes, bound without allocating them.
+/// Reserves space that is filled in later through `LazyConnection::blob_open`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZeroBlob(pub i32);
+
+impl ToSql for ZeroBlob {
+    unsafe fn bind_to(self, stmt: *mut sqlite3_stmt, index: i32) -> i32 {
+        unsafe { ffi::sqlite3_bind_zeroblob(stmt, index, self.0) }
+    }
+}
+
+impl ToSql for &Value {
+    unsafe fn bind_to(self, stmt: *mut sqlite3_stmt, index: i32) -> i32 {
+        match self {
+            Value::Integer(i) => unsafe { i.bind_to(stmt, index) },
+            Value::Real(f) => unsafe { f.bind_to(stmt, index) },
+            Value::Text(s) => unsafe { s.as_str().bind_to(stmt, index) },
+            Value::Blob(b) => unsafe { b.as_slice().bind_to(stmt, index) },
+            Value::Null => unsafe { ffi::sqlite3_bind_null(stmt, index) },
+        }
+    }
+}
+
+impl ToSql for Value {
+    unsafe fn bind_to(self, stmt: *mut sqlite3_stmt, index: i32) -> i32 {
+        unsafe { (&self).bind_to(stmt, index) }
+    }
+}
+
+/// Values a user defined function (or a virtual table column) can return.
+/// Kept apart from [`ToSql`] so implementing binding for a type doesn't require this as well
+pub trait ToSqlResult {
+    /// Sets the value as the result of a user defined function using sqlite3_result_*
+    ///# Safety
+    ///
+    /// `ctx` must be the context of the function call currently running
+    unsafe fn result_to(self, ctx: *mut sqlite3_context);
+}
+
+impl ToSqlResult for String {
+    unsafe fn result_to(self, ctx: *mut sqlite3_context) {
+        unsafe { self.as_str().result_to(ctx) }
+    }
+}
+
+impl ToSqlResult for &str {
+    unsafe fn result_to(self, ctx: *mut sqlite3_context) {
+        let bytes = self.as_bytes();
+        let len = bytes.len() as i32;
+
+        unsafe {
+            ffi::sqlite3_result_text(ctx, bytes.as_ptr() as *const _, len, SQLITE_TRANSIENT())
+        }
+    }
+}
+
+impl ToSqlResult for i32 {
+    unsafe fn result_to(self, ctx: *mut sqlite3_context) {
+        unsafe { ffi::sqlite3_result_int(ctx, self) }
+    }
+}
+
+impl ToSqlResult for i64 {
+    unsafe fn result_to(self, ctx: *mut sqlite3_context) {
+        unsafe { ffi::sqlite3_result_int64(ctx, self) }
+    }
+}
+
+impl ToSqlResult for f64 {
+    unsafe fn result_to(self, ctx: *mut sqlite3_context) {
+        unsafe { ffi::sqlite3_result_double(ctx, self) }
+    }
+}
+
+impl ToSqlResult for bool {
+    unsafe fn result_to(self, ctx: *mut sqlite3_context) {
+        unsafe { ffi::sqlite3_result_int(ctx, self as i32) }
+    }
+}
+
+impl<T: ToSqlResult> ToSqlResult for Option<T> {
+    unsafe fn result_to(self, ctx: *mut sqlite3_context) {
+        match self {
+            Some(rust_value) => unsafe { rust_value.result_to(ctx) },
+            None => unsafe { ffi::sqlite3_result_null(ctx) },
+        }
+    }
+}
+
+impl ToSqlResult for &[u8] {
+    unsafe fn result_to(self, ctx: *mut sqlite3_context) {
+        unsafe {
+            ffi::sqlite3_result_blob(
+                ctx,
+                self.as_ptr() as *const _,
+                self.len() as i32,
+                ffi::SQLITE_TRANSIENT(),
+            )
+        }
+    }
+}
+
+impl ToSqlResult for Vec<u8> {
+    unsafe fn result_to(self, ctx: *mut sqlite3_context) {
+        unsafe { self.as_slice().result_to(ctx) }
+    }
+}
+
+impl ToSqlResult for ZeroBlob {
+    unsafe fn result_to(self, ctx: *mut sqlite3_context) {
+        unsafe { ffi::sqlite3_result_zeroblob(ctx, self.0) }
+    }
+}
+
+impl ToSqlResult for &Value {
+    unsafe fn result_to(self, ctx: *mut sqlite3_context) {
+        match self {
+            Value::Integer(i) => unsafe { i.result_to(ctx) },
+            Value::Real(f) => unsafe { f.result_to(ctx) },
+            Value::Text(s) => unsafe { s.as_str().result_to(ctx) },
+            Value::Blob(b) => unsafe { b.as_slice().result_to(ctx) },
+            Value::Null => unsafe { ffi::sqlite3_result_null(ctx) },
+        }
+    }
+}
+
+impl ToSqlResult for Value {
+    unsafe fn result_to(self, ctx: *mut sqlite3_context) {
+        unsafe { (&self).result_to(ctx) }
+    }
+}
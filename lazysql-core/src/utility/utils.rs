@@ -1,19 +1,23 @@
 use libsqlite3_sys::{
-    self as ffi, SQLITE_OK, SQLITE_OPEN_CREATE, SQLITE_OPEN_MEMORY, SQLITE_OPEN_READONLY,
-    SQLITE_OPEN_READWRITE, SQLITE_ROW, sqlite3, sqlite3_close, sqlite3_column_text,
-    sqlite3_errcode, sqlite3_exec, sqlite3_finalize, sqlite3_free, sqlite3_open_v2,
-    sqlite3_prepare_v2, sqlite3_step, sqlite3_stmt,
+    self as ffi, SQLITE_MISUSE, SQLITE_OK, SQLITE_OPEN_CREATE, SQLITE_OPEN_MEMORY,
+    SQLITE_OPEN_READONLY, SQLITE_OPEN_READWRITE, SQLITE_ROW, sqlite3, sqlite3_close,
+    sqlite3_column_text, sqlite3_errcode, sqlite3_exec, sqlite3_finalize, sqlite3_free,
+    sqlite3_open_v2, sqlite3_prepare_v2, sqlite3_step, sqlite3_stmt, sqlite3_stmt_readonly,
 };
 use std::{
     collections::HashMap,
-    ffi::{CStr, CString, c_char, c_void},
+    ffi::{CStr, CString, c_char, c_int, c_void},
     fs,
     path::Path,
     ptr,
 };
-use type_inference::{expr::BaseType, table::ColumnInfo};
+use type_inference::{
+    expr::BaseType,
+    functions::{FunctionKind, FunctionSignature},
+    table::ColumnInfo,
+};
 
-use crate::errors::connection::SqlitePrepareErrors;
+use crate::{errors::connection::SqlitePrepareErrors, internal_sqlite::extension};
 
 pub enum RustTypes {
     Integer,
@@ -51,6 +55,34 @@ pub unsafe fn get_sqlite_failiure(db: *mut sqlite3) -> (i32, String) {
     (code, error_msg)
 }
 
+/// Holds the mutex of a connection (`sqlite3_db_mutex`) until dropped.
+///
+/// SQLite only locks it for the duration of a single call, so without this another thread
+/// could overwrite (or free) the error message of a failed call before it is read.
+/// The mutex is recursive, hence sqlite calls made while holding it are fine.
+/// For connections opened without a mutex (per thread) this is a no-op.
+pub struct DbMutexGuard {
+    mutex: *mut ffi::sqlite3_mutex,
+}
+
+impl DbMutexGuard {
+    /// # Safety
+    ///
+    /// - db must be a valid sqlite3 connection which is not NULL
+    pub unsafe fn lock(db: *mut sqlite3) -> Self {
+        let mutex = unsafe { ffi::sqlite3_db_mutex(db) };
+        // sqlite3_mutex_enter is a no-op for NULL
+        unsafe { ffi::sqlite3_mutex_enter(mutex) };
+        DbMutexGuard { mutex }
+    }
+}
+
+impl Drop for DbMutexGuard {
+    fn drop(&mut self) {
+        unsafe { ffi::sqlite3_mutex_leave(self.mutex) };
+    }
+}
+
 ///
 /// # Safety
 ///
@@ -61,6 +93,7 @@ pub unsafe fn close_db(db: *mut sqlite3) {
     unsafe { ffi::sqlite3_close(db) };
 }
 
+/// A null byte in `sql` fails with `SQLITE_MISUSE`, the same as `LazyConnection::exec`
 ///# Safety
 /// db must be a valid pointer
 pub unsafe fn prepare_stmt(
@@ -68,7 +101,11 @@ pub unsafe fn prepare_stmt(
     stmt: &mut *mut sqlite3_stmt,
     sql: &str,
 ) -> Result<(), SqlitePrepareErrors> {
-    let c_sql_query = CString::new(sql).unwrap(); //TODO
+    let c_sql_query = CString::new(sql).map_err(|_| SqlitePrepareErrors::SqliteFailure {
+        code: SQLITE_MISUSE,
+        error_msg: format!("{sql:?} contains a null byte"),
+    })?;
+    let _lock = unsafe { DbMutexGuard::lock(db) };
     let code =
         unsafe { ffi::sqlite3_prepare_v2(db, c_sql_query.as_ptr(), -1, stmt, ptr::null_mut()) };
 
@@ -216,13 +253,61 @@ pub fn get_db_schema(db_path: &str) -> Result<Vec<String>, String> {
     }
 }
 
+/// Prepares `sql` against an in memory database holding `tables`, a stub of every declared
+/// function in `functions` (taking the declared number of arguments and returning NULL)
+/// and of every collation in `collations`, after loading the `(path, entry point)` `extensions`.
+/// Every schema in `attached` (and of every `schema.table` in `tables`) is attached as an empty
+/// in memory database.
+/// On success, returns whether the statement is read only (`sqlite3_stmt_readonly`)
 pub fn validate_sql_syntax_with_sqlite(
     tables: &HashMap<String, Vec<ColumnInfo>>,
+    functions: &[FunctionSignature],
+    collations: &[String],
+    extensions: &[(String, Option<String>)],
+    attached: &[String],
     sql: &str,
-) -> Result<(), String> {
+) -> Result<bool, String> {
     let handle = SqliteHandle::open_memory()?;
 
     unsafe {
+        for (path, entry_point) in extensions {
+            extension::load(handle.db, Path::new(path), entry_point.as_deref())
+                .map_err(|err| err.to_string())?;
+        }
+
+        for function in functions {
+            create_stub_function(handle.db, function)?;
+        }
+
+        for collation in collations {
+            create_stub_collation(handle.db, collation)?;
+        }
+
+        // tables of attached databases are keyed `schema.table`
+        let mut schemas: Vec<&str> = tables
+            .keys()
+            .filter_map(|name| name.split_once('.').map(|(schema, _)| schema))
+            .chain(attached.iter().map(String::as_str))
+            .filter(|schema| *schema != "temp")
+            .collect();
+        schemas.sort_unstable();
+        schemas.dedup();
+        for schema in schemas {
+            let attach = CString::new(format!("ATTACH DATABASE ':memory:' AS \"{schema}\""))
+                .map_err(|_| format!("Invalid schema name: {schema}"))?;
+            let rc = sqlite3_exec(
+                handle.db,
+                attach.as_ptr(),
+                None,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            );
+            if rc != SQLITE_OK {
+                let (_, msg) = get_sqlite_failiure(handle.db);
+                return Err(format!("Failed to attach schema {}: {}", schema, msg));
+            }
+        }
+
         for (table_name, columns) in tables {
             let col_defs: Vec<String> = columns
                 .iter()
@@ -271,18 +356,114 @@ pub fn validate_sql_syntax_with_sqlite(
         let prepare_rc =
             sqlite3_prepare_v2(handle.db, c_sql.as_ptr(), -1, &mut stmt, ptr::null_mut());
 
+        let read_only = !stmt.is_null() && sqlite3_stmt_readonly(stmt) != 0;
+
         if !stmt.is_null() {
             sqlite3_finalize(stmt);
         }
 
         if prepare_rc == SQLITE_OK {
-            Ok(())
+            Ok(read_only)
         } else {
             let (_, msg) = get_sqlite_failiure(handle.db);
             Err(msg.to_string())
         }
     }
 }
+
+unsafe extern "C" fn stub_function(
+    _ctx: *mut ffi::sqlite3_context,
+    _argc: c_int,
+    _argv: *mut *mut ffi::sqlite3_value,
+) {
+}
+
+unsafe extern "C" fn stub_final(_ctx: *mut ffi::sqlite3_context) {}
+
+unsafe extern "C" fn stub_compare(
+    _arg: *mut c_void,
+    _len_a: c_int,
+    _a: *const c_void,
+    _len_b: c_int,
+    _b: *const c_void,
+) -> c_int {
+    0
+}
+
+unsafe fn create_stub_collation(db: *mut sqlite3, name: &str) -> Result<(), String> {
+    let c_name =
+        CString::new(name).map_err(|_| format!("Collation name {name:?} contains a null byte"))?;
+
+    let rc = unsafe {
+        ffi::sqlite3_create_collation_v2(
+            db,
+            c_name.as_ptr(),
+            ffi::SQLITE_UTF8,
+            ptr::null_mut(),
+            Some(stub_compare),
+            None,
+        )
+    };
+
+    if rc != SQLITE_OK {
+        let (_, msg) = unsafe { get_sqlite_failiure(db) };
+        return Err(format!("Failed to declare collation {name}: {msg}"));
+    }
+    Ok(())
+}
+
+unsafe fn create_stub_function(db: *mut sqlite3, function: &FunctionSignature) -> Result<(), String> {
+    let c_name = CString::new(function.name.as_str())
+        .map_err(|_| format!("Function name {:?} contains a null byte", function.name))?;
+    let n_args = function.args.len() as c_int;
+    let flags = ffi::SQLITE_UTF8 | ffi::SQLITE_DETERMINISTIC;
+
+    let rc = unsafe {
+        match function.kind {
+            FunctionKind::Scalar => ffi::sqlite3_create_function_v2(
+                db,
+                c_name.as_ptr(),
+                n_args,
+                flags,
+                ptr::null_mut(),
+                Some(stub_function),
+                None,
+                None,
+                None,
+            ),
+            FunctionKind::Aggregate => ffi::sqlite3_create_function_v2(
+                db,
+                c_name.as_ptr(),
+                n_args,
+                flags,
+                ptr::null_mut(),
+                None,
+                Some(stub_function),
+                Some(stub_final),
+                None,
+            ),
+            FunctionKind::Window => ffi::sqlite3_create_window_function(
+                db,
+                c_name.as_ptr(),
+                n_args,
+                flags,
+                ptr::null_mut(),
+                Some(stub_function),
+                Some(stub_final),
+                Some(stub_final),
+                Some(stub_function),
+                None,
+            ),
+        }
+    };
+
+    if rc != SQLITE_OK {
+        let (_, msg) = unsafe { get_sqlite_failiure(db) };
+        return Err(format!("Failed to declare function {}: {}", function.name, msg));
+    }
+    Ok(())
+}
+
 // mod tests {
 
 //     use super::*;
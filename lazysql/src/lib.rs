@@ -1,5 +1,7 @@
 #![doc = include_str!("../README.md")]
 
 pub use lazysql_core::internal_sqlite::lazy_connection::LazyConnection;
+pub use lazysql_core::internal_sqlite::lazy_pool::LazyPool;
+pub use lazysql_core::internal_sqlite::open_options::OpenOptions;
 pub use lazysql_core::*;
-pub use lazysql_macros::*;
\ No newline at end of file
+pub use lazysql_macros::*;
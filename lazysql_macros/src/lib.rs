@@ -9,9 +9,13 @@ use syn::{
     spanned::Spanned,
 };
 use type_inference::{
-    binding_patterns::get_type_of_binding_parameters, expr::BaseType, pg_cast_syntax_to_sqlite,
-    select_patterns::get_types_from_select, table::create_tables, validate_insert_strict,
-    validate_single_statement,
+    binding_patterns::get_type_of_binding_parameters,
+    expr::BaseType,
+    functions::{FunctionKind, FunctionSignature, parse_base_type, with_user_functions},
+    pg_cast_syntax_to_sqlite,
+    select_patterns::get_types_from_select,
+    table::{create_tables, create_tables_in, with_attached},
+    validate_insert_strict, validate_single_statement,
 };
 
 /// This nicely formats the sql string.
@@ -74,41 +78,242 @@ fn parse_runtime_macro(ty: &syn::Type) -> syn::Result<Option<RuntimeSqlInput>> {
     Ok(None)
 }
 
-#[proc_macro_attribute]
-pub fn lazy_sql(args: TokenStream, input: TokenStream) -> TokenStream {
-    let path_lit_opt = if args.is_empty() {
-        None
-    } else {
-        match syn::parse::<syn::LitStr>(args) {
-            Ok(lit) => {
-                let manifest_dir = env::var("CARGO_MANIFEST_DIR").expect("No MANIFEST_DIR");
-                let full_path = Path::new(&manifest_dir).join(lit.value());
-                let full_path_str = full_path.to_str().expect("Invalid path string");
-
-                Some(syn::LitStr::new(
-                    full_path_str,
-                    proc_macro2::Span::call_site(),
-                ))
+/// `#[lazy_sql("schema.sql", transaction = "immediate", functions(slugify(Text) -> Text), collations(natsort),
+/// extensions("ext/spellfix", "ext/mylib" = "sqlite3_mylib_init"), attach(archive = "archive.db"))]`.
+/// Every part is optional
+struct LazySqlArgs {
+    path: Option<LitStr>,
+    transaction: Option<proc_macro2::TokenStream>,
+    functions: Vec<FunctionSignature>,
+    collations: Vec<String>,
+    /// paths (relative ones resolved against the crate root) and entry points
+    extensions: Vec<(String, Option<String>)>,
+    /// schema names and the absolute paths of their databases
+    attach: Vec<(String, LitStr)>,
+}
+
+impl syn::parse::Parse for LazySqlArgs {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let mut args = LazySqlArgs {
+            path: None,
+            transaction: None,
+            functions: Vec::new(),
+            collations: Vec::new(),
+            extensions: Vec::new(),
+            attach: Vec::new(),
+        };
+
+        if input.peek(LitStr) {
+            args.path = Some(input.parse()?);
+            if !input.is_empty() {
+                input.parse::<syn::Token![,]>()?;
             }
-            Err(_) => {
-                let err = syn::Error::new(
-                    proc_macro2::Span::call_site(),
-                    "lazy_sql requires either no arguments or a path string to a sql/db file.",
-                );
-                let err_tokens = err.to_compile_error();
-                let input_tokens = proc_macro2::TokenStream::from(input);
-                return quote! {
-                    #err_tokens
-                    #input_tokens
+        }
+
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            match key.to_string().as_str() {
+                "transaction" => {
+                    input.parse::<syn::Token![=]>()?;
+                    let value: LitStr = input.parse()?;
+                    let behavior = match value.value().to_lowercase().as_str() {
+                        "deferred" => quote! { Deferred },
+                        "immediate" => quote! { Immediate },
+                        "exclusive" => quote! { Exclusive },
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.span(),
+                                "transaction must be \"deferred\", \"immediate\" or \"exclusive\"",
+                            ));
+                        }
+                    };
+                    args.transaction = Some(behavior);
+                }
+                "functions" => {
+                    let content;
+                    syn::parenthesized!(content in input);
+                    while !content.is_empty() {
+                        args.functions.push(parse_function_signature(&content)?);
+                        if !content.is_empty() {
+                            content.parse::<syn::Token![,]>()?;
+                        }
+                    }
+                }
+                "collations" => {
+                    let content;
+                    syn::parenthesized!(content in input);
+                    let names =
+                        content.parse_terminated(|name| name.parse::<Ident>(), syn::Token![,])?;
+                    args.collations
+                        .extend(names.iter().map(|name| name.to_string()));
+                }
+                "extensions" => {
+                    let content;
+                    syn::parenthesized!(content in input);
+                    while !content.is_empty() {
+                        let path: LitStr = content.parse()?;
+                        let entry_point = if content.peek(syn::Token![=]) {
+                            content.parse::<syn::Token![=]>()?;
+                            Some(content.parse::<LitStr>()?.value())
+                        } else {
+                            None
+                        };
+
+                        let manifest_dir = env::var("CARGO_MANIFEST_DIR").expect("No MANIFEST_DIR");
+                        let full_path = Path::new(&manifest_dir).join(path.value());
+                        let full_path = full_path.to_str().ok_or_else(|| {
+                            syn::Error::new(path.span(), "extension path is not valid UTF-8")
+                        })?;
+                        args.extensions.push((full_path.to_string(), entry_point));
+
+                        if !content.is_empty() {
+                            content.parse::<syn::Token![,]>()?;
+                        }
+                    }
                 }
-                .into();
+                "attach" => {
+                    let content;
+                    syn::parenthesized!(content in input);
+                    while !content.is_empty() {
+                        let schema: Ident = content.parse()?;
+                        content.parse::<syn::Token![=]>()?;
+                        let path: LitStr = content.parse()?;
+
+                        let name = schema.to_string().to_lowercase();
+                        if name == "main" || name == "temp" {
+                            return Err(syn::Error::new(
+                                schema.span(),
+                                format!("`{name}` is not a name an attached database can have"),
+                            ));
+                        }
+
+                        let manifest_dir = env::var("CARGO_MANIFEST_DIR").expect("No MANIFEST_DIR");
+                        let full_path = Path::new(&manifest_dir).join(path.value());
+                        let full_path = full_path.to_str().ok_or_else(|| {
+                            syn::Error::new(path.span(), "database path is not valid UTF-8")
+                        })?;
+                        args.attach
+                            .push((name, LitStr::new(full_path, path.span())));
+
+                        if !content.is_empty() {
+                            content.parse::<syn::Token![,]>()?;
+                        }
+                    }
+                }
+                _ => {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        format!("unknown lazy_sql option `{key}`"),
+                    ));
+                }
+            }
+
+            if !input.is_empty() {
+                input.parse::<syn::Token![,]>()?;
             }
         }
+
+        Ok(args)
+    }
+}
+
+/// `[aggregate | window] name(Type, Type?, ...) -> Type[?]`
+fn parse_function_signature(input: syn::parse::ParseStream) -> syn::Result<FunctionSignature> {
+    let first: Ident = input.parse()?;
+    let (kind, name) = match first.to_string().as_str() {
+        "aggregate" if input.peek(Ident) => (FunctionKind::Aggregate, input.parse::<Ident>()?),
+        "window" if input.peek(Ident) => (FunctionKind::Window, input.parse::<Ident>()?),
+        _ => (FunctionKind::Scalar, first),
     };
 
+    let params;
+    syn::parenthesized!(params in input);
+    let mut args = Vec::new();
+    while !params.is_empty() {
+        args.push(parse_declared_type(&params, true)?);
+        if !params.is_empty() {
+            params.parse::<syn::Token![,]>()?;
+        }
+    }
+
+    input.parse::<syn::Token![->]>()?;
+    let returns = parse_declared_type(input, false)?;
+
+    Ok(FunctionSignature {
+        name: name.to_string(),
+        kind,
+        args,
+        returns,
+    })
+}
+
+/// `Integer`, `Real`, `Text`, `Blob`, `Bool` (or `Any` for parameters), followed by `?` if nullable
+fn parse_declared_type(
+    input: syn::parse::ParseStream,
+    is_param: bool,
+) -> syn::Result<type_inference::expr::Type> {
+    let ident: Ident = input.parse()?;
+    let base_type = parse_base_type(&ident.to_string(), is_param).ok_or_else(|| {
+        let expected = if is_param {
+            "Integer, Real, Text, Blob, Bool or Any"
+        } else {
+            "Integer, Real, Text, Blob or Bool"
+        };
+        syn::Error::new(
+            ident.span(),
+            format!("unknown type `{ident}`, expected {expected}"),
+        )
+    })?;
+
+    let nullable = input.peek(syn::Token![?]);
+    if nullable {
+        input.parse::<syn::Token![?]>()?;
+    }
+
+    Ok(type_inference::expr::Type {
+        base_type,
+        nullable,
+        contains_placeholder: false,
+    })
+}
+
+#[proc_macro_attribute]
+pub fn lazy_sql(args: TokenStream, input: TokenStream) -> TokenStream {
+    let args = match syn::parse::<LazySqlArgs>(args) {
+        Ok(args) => args,
+        Err(err) => {
+            let err_tokens = err.to_compile_error();
+            let input_tokens = proc_macro2::TokenStream::from(input);
+            return quote! {
+                #err_tokens
+                #input_tokens
+            }
+            .into();
+        }
+    };
+
+    let path_lit_opt = args.path.as_ref().map(|lit| {
+        let manifest_dir = env::var("CARGO_MANIFEST_DIR").expect("No MANIFEST_DIR");
+        let full_path = Path::new(&manifest_dir).join(lit.value());
+        let full_path_str = full_path.to_str().expect("Invalid path string");
+
+        syn::LitStr::new(full_path_str, proc_macro2::Span::call_site())
+    });
+
     let mut item_struct = parse_macro_input!(input as ItemStruct);
 
-    match expand(&mut item_struct, path_lit_opt.as_ref()) {
+    let attached: Vec<String> = args
+        .attach
+        .iter()
+        .map(|(schema, _)| schema.clone())
+        .collect();
+    let expanded = with_user_functions(&args.functions, || {
+        with_attached(&attached, || {
+            expand(&mut item_struct, path_lit_opt.as_ref(), &args)
+        })
+    });
+
+    match expanded {
         Ok(output) => {
             let watcher = if let Some(abs_path) = path_lit_opt {
                 quote! {
@@ -118,9 +323,12 @@ pub fn lazy_sql(args: TokenStream, input: TokenStream) -> TokenStream {
                 quote! {}
             };
 
+            // rebuild when an attached database changes as well
+            let attach_paths = args.attach.iter().map(|(_, path)| path);
             let final_output = quote! {
                 #output
                 #watcher
+                #(const _: &[u8] = include_bytes!(#attach_paths);)*
             };
 
             final_output.into()
@@ -129,9 +337,26 @@ pub fn lazy_sql(args: TokenStream, input: TokenStream) -> TokenStream {
     }
 }
 
+/// `CREATE TABLE` as well as `CREATE TEMP TABLE` / `CREATE TEMPORARY TABLE`
+fn is_create_table(sql: &str) -> bool {
+    let words: Vec<String> = sql
+        .split_whitespace()
+        .take(3)
+        .map(str::to_uppercase)
+        .collect();
+    match words.as_slice() {
+        [create, table, ..] if create == "CREATE" && table == "TABLE" => true,
+        [create, temp, table] => {
+            create == "CREATE" && (temp == "TEMP" || temp == "TEMPORARY") && table == "TABLE"
+        }
+        _ => false,
+    }
+}
+
 fn expand(
     item_struct: &mut ItemStruct,
     db_path_lit: Option<&syn::LitStr>,
+    args: &LazySqlArgs,
 ) -> syn::Result<proc_macro2::TokenStream> {
     let mut all_tables = HashMap::new();
 
@@ -148,6 +373,23 @@ fn expand(
         }
     }
 
+    for (schema_name, path) in &args.attach {
+        let schemas = get_db_schema(&path.value()).map_err(|err| {
+            syn::Error::new(
+                path.span(),
+                format!("Failed to load schema of attached database {schema_name}: {err}"),
+            )
+        })?;
+        for schema in schemas {
+            create_tables_in(&schema, schema_name, &mut all_tables);
+        }
+    }
+
+    let attached: Vec<String> = args
+        .attach
+        .iter()
+        .map(|(schema, _)| schema.clone())
+        .collect();
     let struct_name = &item_struct.ident;
 
     let fields = match &mut item_struct.fields {
@@ -172,7 +414,11 @@ fn expand(
         let field_attrs = &field.attrs;
 
         // Check if type is sql!("...")
-        if let Some(sql_lit) = parse_sql_macro_type(&field.ty)? {
+        if let Some(SqlField {
+            sql: sql_lit,
+            timeout_ms,
+        }) = parse_sql_macro_type(&field.ty)?
+        {
             let sql_query = pg_cast_syntax_to_sqlite(&sql_lit.value());
 
             if !validate_single_statement(&sql_query) {
@@ -185,15 +431,23 @@ fn expand(
 
             let transpiled_sql_lit = syn::LitStr::new(&sql_query, sql_lit.span());
 
-            if let Err(err_msg) = validate_sql_syntax_with_sqlite(&all_tables, &sql_query) {
-                return Err(syn::Error::new(sql_lit.span(), err_msg.to_string()));
-            }
+            let read_only = match validate_sql_syntax_with_sqlite(
+                &all_tables,
+                &args.functions,
+                &args.collations,
+                &args.extensions,
+                &attached,
+                &sql_query,
+            ) {
+                Ok(read_only) => read_only,
+                Err(err_msg) => return Err(syn::Error::new(sql_lit.span(), err_msg.to_string())),
+            };
 
             if let Err(err_msg) = validate_insert_strict(&sql_query, &all_tables) {
                 return Err(syn::Error::new(sql_lit.span(), err_msg.to_string()));
             }
 
-            if sql_query.trim().to_uppercase().starts_with("CREATE TABLE") {
+            if is_create_table(&sql_query) {
                 create_tables(&sql_query, &mut all_tables);
 
                 field.ty = parse_quote!(lazysql::internal_sqlite::lazy_statement::LazyStmt);
@@ -205,23 +459,12 @@ fn expand(
                 });
 
                 let doc_comment = format!(" \n**SQL**\n```sql\n{}", format_sql(&sql_query));
+                let statement_setup = statement_setup(ident, false, timeout_ms.as_ref());
                 generated_methods.push(quote! {
                     #(#field_attrs)*
                     #[doc = #doc_comment]
                     pub fn #ident(&mut self) -> Result<(), lazysql::errors::SqlWriteError> {
-                        if self.#ident.stmt.is_null() {
-                            unsafe {
-                                lazysql::utility::utils::prepare_stmt(
-                                    self.__db.db,
-                                    &mut self.#ident.stmt,
-                                    self.#ident.sql_query
-                                )?;
-                            }
-                        }
-                        let mut preparred_statement = lazysql::internal_sqlite::preparred_statement::PreparredStmt {
-                            stmt: self.#ident.stmt,
-                            conn: self.__db.db,
-                        };
+                        #statement_setup
                         preparred_statement.step()?;
                         Ok(())
                     }
@@ -283,6 +526,13 @@ fn expand(
                 }
             };
 
+            // only queries returning rows are worth sending to a pooled reader
+            let statement_setup = statement_setup(
+                ident,
+                read_only && !select_types.is_empty(),
+                timeout_ms.as_ref(),
+            );
+
             let formated_sql_query = format_sql(&sql_query);
             let doc_comment = format!(" \n**SQL**\n```sql\n{}", formated_sql_query);
 
@@ -300,19 +550,7 @@ fn expand(
                     #(#field_attrs)*
                     #[doc = #doc_comment]
                     pub fn #ident(&mut self) -> Result<(), lazysql::errors::SqlWriteError> {
-                        if self.#ident.stmt.is_null() {
-                            unsafe {
-                                lazysql::utility::utils::prepare_stmt(
-                                    self.__db.db,
-                                    &mut self.#ident.stmt,
-                                    self.#ident.sql_query
-                                )?;
-                            }
-                        }
-                        let mut preparred_statement = lazysql::internal_sqlite::preparred_statement::PreparredStmt {
-                            stmt: self.#ident.stmt,
-                            conn: self.__db.db,
-                        };
+                        #statement_setup
                         preparred_statement.step()?;
                         Ok(())
                     }
@@ -351,20 +589,7 @@ fn expand(
                     #(#field_attrs)*
                     #[doc = #doc_comment]
                     pub fn #ident(&mut self, #(#method_args),*) -> Result<(), lazysql::errors::SqlWriteBindingError> {
-                        if self.#ident.stmt.is_null() {
-                            unsafe {
-                                lazysql::utility::utils::prepare_stmt(
-                                    self.__db.db,
-                                    &mut self.#ident.stmt,
-                                    self.#ident.sql_query
-                                )?;
-                            }
-                        }
-
-                        let mut preparred_statement = lazysql::internal_sqlite::preparred_statement::PreparredStmt {
-                            stmt: self.#ident.stmt,
-                            conn: self.__db.db,
-                        };
+                        #statement_setup
 
                         #(#bind_calls)*
 
@@ -425,20 +650,7 @@ fn expand(
                     #(#field_attrs)*
                 #[doc = #doc_comment]
                 pub fn #ident(&mut self) -> Result<lazysql::internal_sqlite::rows_dao::Rows<'_, #mapper_struct_name>, lazysql::errors::SqlReadError> {
-                        if self.#ident.stmt.is_null() {
-                            unsafe {
-                                lazysql::utility::utils::prepare_stmt(
-                                    self.__db.db,
-                                    &mut self.#ident.stmt,
-                                    self.#ident.sql_query
-                                )?;
-                            }
-                        }
-
-            let preparred_statement = lazysql::internal_sqlite::preparred_statement::PreparredStmt {
-                stmt: self.#ident.stmt,
-                conn: self.__db.db,
-            };
+                        #statement_setup
             Ok(preparred_statement.query(#struct_name))
         }
     });
@@ -523,20 +735,7 @@ fn expand(
                     #(#field_attrs)*
                     #[doc = #doc_comment]
                     pub fn #ident(&mut self, #(#method_args),*) -> Result<lazysql::internal_sqlite::rows_dao::Rows<'_, #mapper_struct_name>, lazysql::errors::SqlReadErrorBindings> {
-                        if self.#ident.stmt.is_null() {
-                            unsafe {
-                                lazysql::utility::utils::prepare_stmt(
-                                    self.__db.db,
-                                    &mut self.#ident.stmt,
-                                    self.#ident.sql_query
-                                )?;
-                            }
-                        }
-
-                        let mut preparred_statement = lazysql::internal_sqlite::preparred_statement::PreparredStmt {
-                            stmt: self.#ident.stmt,
-                            conn: self.__db.db,
-                        };
+                        #statement_setup
 
                         #(#bind_calls)*
 
@@ -574,6 +773,7 @@ fn expand(
             }
 
             let doc_comment = format!(" \n**SQL**\n```sql\n{}", format_sql(&sql_lit.value()));
+            let statement_setup = statement_setup(ident, false, None);
 
             if let Some(ret_type) = runtime_input.return_type {
                 let mapper_type = if let syn::Type::Path(type_path) = &ret_type {
@@ -601,20 +801,7 @@ fn expand(
                     #[doc = #doc_comment]
                     // SELECT
                     pub fn #ident(&mut self, #(#method_args),*) -> Result<lazysql::internal_sqlite::rows_dao::Rows<#mapper_type>, lazysql::errors::SqlReadErrorBindings> {
-                        if self.#ident.stmt.is_null() {
-                            unsafe {
-                                lazysql::utility::utils::prepare_stmt(
-                                    self.__db.db,
-                                    &mut self.#ident.stmt,
-                                    self.#ident.sql_query
-                                )?;
-                            }
-                        }
-
-                        let mut preparred_statement = lazysql::internal_sqlite::preparred_statement::PreparredStmt {
-                            stmt: self.#ident.stmt,
-                            conn: self.__db.db,
-                        };
+                        #statement_setup
 
                         #(#bind_calls)*
 
@@ -627,20 +814,7 @@ fn expand(
                     #(#field_attrs)*
                     #[doc = #doc_comment]
                     pub fn #ident(&mut self, #(#method_args),*) -> Result<(), lazysql::errors::SqlWriteBindingError> {
-                        if self.#ident.stmt.is_null() {
-                            unsafe {
-                                lazysql::utility::utils::prepare_stmt(
-                                    self.__db.db,
-                                    &mut self.#ident.stmt,
-                                    self.#ident.sql_query
-                                )?;
-                            }
-                        }
-
-                        let mut preparred_statement = lazysql::internal_sqlite::preparred_statement::PreparredStmt {
-                            stmt: self.#ident.stmt,
-                            conn: self.__db.db,
-                        };
+                        #statement_setup
 
                         #(#bind_calls)*
 
@@ -660,12 +834,44 @@ fn expand(
 
     fields.named.insert(
         0,
-parse_quote! { __db: std::sync::Arc<lazysql::internal_sqlite::lazy_connection::LazyConnection> }
+parse_quote! { __db: std::sync::Arc<lazysql::internal_sqlite::lazy_connection::LazyConnection<__Mode>> }
 ,
     );
+    fields.named.push(parse_quote! { __in_transaction: bool });
+    fields
+        .named
+        .push(parse_quote! { __pool: Option<lazysql::internal_sqlite::lazy_pool::LazyPool> });
+
+    // `from_pool` only exists for the default (serialized) threading mode
+    let user_generics = item_struct.generics.clone();
+    let (pool_impl_generics, _, pool_where_clause) = user_generics.split_for_impl();
+    let user_type_args = user_generics.params.iter().map(|param| match param {
+        syn::GenericParam::Lifetime(l) => {
+            let lifetime = &l.lifetime;
+            quote! { #lifetime }
+        }
+        syn::GenericParam::Type(t) => {
+            let ident = &t.ident;
+            quote! { #ident }
+        }
+        syn::GenericParam::Const(c) => {
+            let ident = &c.ident;
+            quote! { #ident }
+        }
+    });
+    let pool_ty_generics = quote! {
+        <#(#user_type_args,)* lazysql::internal_sqlite::threading::Serialized>
+    };
 
+    item_struct.generics.params.push(parse_quote! {
+        __Mode: lazysql::internal_sqlite::threading::ThreadingMode = lazysql::internal_sqlite::threading::Serialized
+    });
     let (impl_generics, ty_generics, where_clause) = item_struct.generics.split_for_impl();
 
+    let mut tx_generics = item_struct.generics.clone();
+    tx_generics.params.insert(0, parse_quote! { '__tx });
+    let (tx_impl_generics, _, _) = tx_generics.split_for_impl();
+
     let mod_name = quote::format_ident!(
         "__lazy_sql_inner_{}",
         struct_name.to_string().to_lowercase()
@@ -673,6 +879,11 @@ parse_quote! { __db: std::sync::Arc<lazysql::internal_sqlite::lazy_connection::L
 
     item_struct.vis = parse_quote!(pub);
 
+    let default_behavior = args
+        .transaction
+        .clone()
+        .unwrap_or_else(|| quote! { Deferred });
+
     Ok(quote! {
         #[doc(hidden)]
         mod #mod_name {
@@ -682,62 +893,274 @@ parse_quote! { __db: std::sync::Arc<lazysql::internal_sqlite::lazy_connection::L
 
             impl #impl_generics #struct_name #ty_generics #where_clause {
                     pub fn new(
-                db: impl Into<std::sync::Arc<lazysql::internal_sqlite::lazy_connection::LazyConnection>>,
+                db: impl Into<std::sync::Arc<lazysql::internal_sqlite::lazy_connection::LazyConnection<__Mode>>>,
                 #(#standard_params),*
             ) -> Self {
                 Self {
                     __db: db.into(), // Call .into() to turn it into the Arc
                     #(#standard_assignments,)*
                     #(#sql_assignments,)*
+                    __in_transaction: false,
+                    __pool: None,
                     }
                 }
 
 
-    pub fn transaction<T, F>(&mut self, f: F) -> Result<T, lazysql::errors::Error>
+    /// Runs `f` between `BEGIN` and `COMMIT`, rolling back if it returns an error.
+    /// Starts it with the behavior set by `#[lazy_sql(transaction = "..")]` (deferred by default).
+    ///
+    /// `f` may fail with any error that `lazysql::errors::Error` converts into and that can be
+    /// boxed as a `std::error::Error`. A failed `COMMIT` is reported as `Error::Commit`, a failed
+    /// `ROLLBACK` as `Error::Rollback` holding the error of `f`.
+    /// `f` is re-run on `SQLITE_BUSY`/`SQLITE_LOCKED` according to the connection's retry policy,
+    /// see `LazyConnection::transaction`
+    pub fn transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
+    where
+        F: FnMut(&mut Self) -> Result<T, E>,
+        E: From<lazysql::errors::Error> + Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+    {
+        self.transaction_with(lazysql::internal_sqlite::transaction::TransactionBehavior::#default_behavior, f)
+    }
+
+    /// Same as `transaction` but starts it with `BEGIN DEFERRED`, `BEGIN IMMEDIATE` or `BEGIN EXCLUSIVE`.
+    /// Inside another transaction it runs in a savepoint instead and `behavior` is ignored
+    pub fn transaction_with<T, E, F>(
+        &mut self,
+        behavior: lazysql::internal_sqlite::transaction::TransactionBehavior,
+        mut f: F,
+    ) -> Result<T, E>
+    where
+        F: FnMut(&mut Self) -> Result<T, E>,
+        E: From<lazysql::errors::Error> + Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+    {
+        let policy = self.__db.retry_policy();
+        lazysql::internal_sqlite::retry::RetryPolicy::run(policy.as_ref(), || match self.begin_with(behavior) {
+            Ok(mut tx) => {
+                let result = f(&mut tx);
+                tx.finish_attempt(result)
+            }
+            Err(e) => lazysql::internal_sqlite::retry::Attempt::begin_failed(e),
+        })
+    }
+
+    /// Runs `f` in `SAVEPOINT name`, releasing it on success and rolling back to it on error
+    pub fn savepoint<T, E, F>(&mut self, name: &str, f: F) -> Result<T, E>
     where
-        F: FnOnce(&mut Self) -> Result<T, lazysql::errors::Error>,
+        F: FnOnce(&mut Self) -> Result<T, E>,
+        E: From<lazysql::errors::Error> + Into<Box<dyn std::error::Error + Send + Sync>>,
     {
-        self.__db.exec("BEGIN")
+        let mut tx = lazysql::internal_sqlite::transaction::Transaction::savepoint(self, name)
             .map_err(lazysql::errors::Error::from)?;
+        let result = f(&mut tx);
+        tx.finish_with(result)
+    }
+
+    /// Runs `f` with a deadline of `timeout` from now, see `LazyConnection::with_timeout`.
+    /// Queries of `f` fail with `Timeout` once it passed, on top of the `timeout_ms` of their field
+    pub fn with_timeout<T>(&mut self, timeout: std::time::Duration, f: impl FnOnce(&mut Self) -> T) -> T {
+        let db = std::sync::Arc::clone(&self.__db);
+        db.with_timeout(timeout, |_| f(self))
+    }
+
+    /// Starts a transaction that is rolled back unless committed before it is dropped.
+    /// The methods of this struct can be called through it
+    pub fn begin(
+        &mut self,
+    ) -> Result<lazysql::internal_sqlite::transaction::Transaction<'_, &mut Self>, lazysql::errors::SqliteFailure> {
+        self.begin_with(lazysql::internal_sqlite::transaction::TransactionBehavior::#default_behavior)
+    }
+
+    /// Same as `begin` with `BEGIN DEFERRED`, `BEGIN IMMEDIATE` or `BEGIN EXCLUSIVE`
+    pub fn begin_with(
+        &mut self,
+        behavior: lazysql::internal_sqlite::transaction::TransactionBehavior,
+    ) -> Result<lazysql::internal_sqlite::transaction::Transaction<'_, &mut Self>, lazysql::errors::SqliteFailure> {
+        lazysql::internal_sqlite::transaction::Transaction::begin(self, behavior)
+    }
 
-        let result = f(self);
 
-        match result {
-            Ok(val) => {
-                if let Err(e) = self.__db.exec("COMMIT") {
-                    return Err(lazysql::errors::Error::from(e));
+                #(#generated_methods)*
+            }
+
+            impl #tx_impl_generics lazysql::internal_sqlite::transaction::TransactionTarget
+                for &'__tx mut #struct_name #ty_generics #where_clause
+            {
+                type Mode = __Mode;
+
+                fn connection(&self) -> &lazysql::internal_sqlite::lazy_connection::LazyConnection<__Mode> {
+                    &self.__db
+                }
+
+                fn enter_transaction(&mut self) -> bool {
+                    std::mem::replace(&mut self.__in_transaction, true)
+                }
+
+                fn leave_transaction(&mut self, was_in_transaction: bool) {
+                    self.__in_transaction = was_in_transaction;
+                }
+
+                // keep other users of the pool's writer out until COMMIT/ROLLBACK
+                fn lock_writer(&self) -> Option<lazysql::internal_sqlite::lazy_pool::WriterLock> {
+                    self.__pool
+                        .as_ref()
+                        .filter(|_| !self.__in_transaction)
+                        .map(|pool| pool.lock_writer())
                 }
-                Ok(val)
             }
-            Err(e) => {
-                // Attempt rollback, ignoring failure since we are already erroring
-                let _ = self.__db.exec("ROLLBACK");
-                Err(e)
+
+            impl #pool_impl_generics #struct_name #pool_ty_generics #pool_where_clause {
+                /// Runs read only queries on a reader of the pool and everything else on its writer.
+                /// Each query checks out a reader until its rows are dropped, so it waits if every
+                /// reader is in use and fails with `SQLITE_BUSY` after the pool's reader timeout.
+                pub fn from_pool(
+                    pool: &lazysql::internal_sqlite::lazy_pool::LazyPool,
+                    #(#standard_params),*
+                ) -> Self {
+                    Self {
+                        __db: pool.writer_connection(),
+                        #(#standard_assignments,)*
+                        #(#sql_assignments,)*
+                        __in_transaction: false,
+                        __pool: Some(pool.clone()),
+                    }
+                }
             }
         }
+
+        pub use #mod_name::#struct_name;
+    })
+}
+
+/// Prepares the statement of the field on first use and binds it to `preparred_statement`.
+///
+/// For structs created with `from_pool`, read only queries run on the pooled reader
+/// (except inside a transaction, where they have to see its uncommitted writes) and
+/// everything else runs on the writer while holding the pool's write lock.
+fn statement_setup(
+    ident: &Ident,
+    read_only: bool,
+    timeout_ms: Option<&syn::LitInt>,
+) -> proc_macro2::TokenStream {
+    // the deadline of `with_timeout` applies to the pooled reader as well
+    let field_deadline = match timeout_ms {
+        Some(ms) => quote! {
+            std::time::Instant::now().checked_add(std::time::Duration::from_millis(#ms))
+        },
+        None => quote! { None },
+    };
+    let deadline = quote! {
+        lazysql::internal_sqlite::interrupt::earliest(self.__db.deadline(), #field_deadline)
+    };
+
+    if read_only {
+        quote! {
+            let reader = match self.__pool.as_ref().filter(|_| !self.__in_transaction) {
+                Some(pool) => Some(lazysql::internal_sqlite::lazy_pool::ReaderStatement::prepare(
+                    pool,
+                    self.#ident.sql_query,
+                )?),
+                None => None,
+            };
+            let (stmt, conn) = match &reader {
+                Some(reader) => (reader.stmt(), reader.db()),
+                None => {
+                    if self.#ident.stmt.is_null() {
+                        unsafe {
+                            lazysql::utility::utils::prepare_stmt(
+                                self.__db.db,
+                                &mut self.#ident.stmt,
+                                self.#ident.sql_query
+                            )?;
+                        }
+                    }
+                    (self.#ident.stmt, self.__db.db)
+                }
+            };
+
+            #[allow(unused_mut)]
+            let mut preparred_statement = lazysql::internal_sqlite::preparred_statement::PreparredStmt {
+                stmt,
+                conn,
+                deadline: #deadline,
+                reader,
+            };
+        }
+    } else {
+        quote! {
+            let _write_guard = self
+                .__pool
+                .as_ref()
+                .filter(|_| !self.__in_transaction)
+                .map(|pool| pool.lock_writer());
+            if self.#ident.stmt.is_null() {
+                unsafe {
+                    lazysql::utility::utils::prepare_stmt(
+                        self.__db.db,
+                        &mut self.#ident.stmt,
+                        self.#ident.sql_query
+                    )?;
+                }
+            }
+
+            #[allow(unused_mut)]
+            let mut preparred_statement = lazysql::internal_sqlite::preparred_statement::PreparredStmt {
+                stmt: self.#ident.stmt,
+                conn: self.__db.db,
+                deadline: #deadline,
+                reader: None,
+            };
+        }
     }
+}
 
+/// `sql!("SELECT ...")`, optionally followed by `timeout_ms = 500`
+struct SqlField {
+    sql: LitStr,
+    timeout_ms: Option<syn::LitInt>,
+}
 
-                #(#generated_methods)*
+impl syn::parse::Parse for SqlField {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let sql = input.parse()?;
+        let mut timeout_ms = None;
+
+        while !input.is_empty() {
+            input.parse::<syn::Token![,]>()?;
+            if input.is_empty() {
+                break;
             }
+
+            let key: Ident = input.parse()?;
+            if key != "timeout_ms" {
+                return Err(syn::Error::new(
+                    key.span(),
+                    format!("unknown sql! option `{key}`, expected `timeout_ms`"),
+                ));
+            }
+            input.parse::<syn::Token![=]>()?;
+            let value: syn::LitInt = input.parse()?;
+            value.base10_parse::<u64>()?;
+            timeout_ms = Some(value);
         }
 
-        pub use #mod_name::#struct_name;
-    })
+        Ok(SqlField { sql, timeout_ms })
+    }
 }
 
-fn parse_sql_macro_type(ty: &Type) -> syn::Result<Option<LitStr>> {
+fn parse_sql_macro_type(ty: &Type) -> syn::Result<Option<SqlField>> {
     if let Type::Macro(type_macro) = ty
         && type_macro.mac.path.is_ident("sql")
     {
-        let lit = syn::parse2(type_macro.mac.tokens.clone()).map_err(|_| {
-            syn::Error::new(
-                type_macro.mac.tokens.span(),
+        let tokens = type_macro.mac.tokens.clone();
+        let first_token = tokens.clone().into_iter().take(1).collect();
+        if syn::parse2::<LitStr>(first_token).is_err() {
+            return Err(syn::Error::new(
+                tokens.span(),
                 "sql!(...) must contain a string",
-            )
-        })?;
+            ));
+        }
 
-        return Ok(Some(lit));
+        return syn::parse2(tokens).map(Some);
     }
 
     Ok(None)
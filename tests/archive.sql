@@ -0,0 +1,5 @@
+CREATE TABLE orders (
+    id INTEGER PRIMARY KEY NOT NULL,
+    total TEXT NOT NULL,
+    archived_at TEXT NOT NULL
+);
@@ -20,6 +20,91 @@ pub struct ShopDao {
     select: sql!("SELECT * FROM persons"),
 }
 
+#[lazy_sql]
+pub struct CounterDao {
+    init: sql!(
+        "CREATE TABLE IF NOT EXISTS hits (id INTEGER PRIMARY KEY NOT NULL, thread INTEGER NOT NULL)"
+    ),
+    add: sql!("INSERT INTO hits (thread) VALUES (?)"),
+    count: sql!("SELECT count(*) AS total FROM hits"),
+}
+
+#[lazy_sql(transaction = "immediate")]
+pub struct ImmediateCounterDao {
+    init: sql!("CREATE TABLE IF NOT EXISTS hits (id INTEGER PRIMARY KEY NOT NULL, thread INTEGER NOT NULL)"),
+    add: sql!("INSERT INTO hits (thread) VALUES (?)"),
+}
+
+#[lazy_sql]
+pub struct OrderDao {
+    init_customers: sql!("CREATE TABLE IF NOT EXISTS customers (id INTEGER PRIMARY KEY NOT NULL)"),
+    init_orders: sql!(
+        "CREATE TABLE IF NOT EXISTS orders (
+            id INTEGER PRIMARY KEY NOT NULL,
+            customer INTEGER NOT NULL REFERENCES customers (id) DEFERRABLE INITIALLY DEFERRED,
+            qty INTEGER NOT NULL
+        )"
+    ),
+    place: sql!("INSERT INTO orders (customer, qty) VALUES (?, ?)"),
+    count: sql!("SELECT count(*) AS total FROM orders"),
+}
+
+#[lazy_sql(functions(
+    slugify(Text) -> Text,
+    score(Integer, Real?) -> Real,
+    aggregate median(Real?) -> Real?,
+))]
+pub struct PostDao {
+    init: sql!(
+        "CREATE TABLE IF NOT EXISTS posts (
+            id INTEGER PRIMARY KEY NOT NULL,
+            title TEXT NOT NULL,
+            rating REAL
+        )"
+    ),
+    add: sql!("INSERT INTO posts (title, rating) VALUES (?, ?)"),
+    slugs: sql!("SELECT id, slugify(title) AS slug, score(id, rating) AS score FROM posts ORDER BY id"),
+    by_slug: sql!("SELECT id FROM posts WHERE slugify(title) = slugify(?)"),
+    median_rating: sql!("SELECT median(rating) AS median FROM posts"),
+}
+
+#[lazy_sql(collations(natsort))]
+pub struct FileDao {
+    init: sql!(
+        "CREATE TABLE IF NOT EXISTS files (
+            id INTEGER PRIMARY KEY NOT NULL,
+            name TEXT NOT NULL COLLATE natsort
+        )"
+    ),
+    add: sql!("INSERT INTO files (name) VALUES (?)"),
+    sorted: sql!("SELECT name FROM files ORDER BY name"),
+    sorted_binary: sql!("SELECT name COLLATE BINARY AS name FROM files ORDER BY 1"),
+    by_name: sql!("SELECT id FROM files WHERE name = ? COLLATE NOCASE"),
+}
+
+#[lazy_sql]
+pub struct RecursiveDao {
+    forever: sql!(
+        "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) SELECT count(*) AS n FROM c",
+        timeout_ms = 50
+    ),
+    count_to: sql!(
+        "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < ?) SELECT count(*) AS n FROM c"
+    ),
+}
+
+#[lazy_sql(attach(archive = "archive.sql"))]
+pub struct ArchiveDao {
+    init: sql!("CREATE TABLE IF NOT EXISTS orders (id INTEGER PRIMARY KEY NOT NULL, total REAL NOT NULL)"),
+    init_scratch: sql!("CREATE TEMP TABLE IF NOT EXISTS scratch (note TEXT NOT NULL)"),
+    place: sql!("INSERT INTO main.orders (total) VALUES (?)"),
+    archive: sql!("INSERT INTO archive.orders (id, total, archived_at) VALUES (?, ?, ?)"),
+    live: sql!("SELECT id, total FROM orders ORDER BY id"),
+    archived: sql!("SELECT orders.id, orders.total, a.archived_at FROM archive.orders JOIN archive.orders a USING (id) ORDER BY id"),
+    note: sql!("INSERT INTO temp.scratch (note) VALUES (?)"),
+    notes: sql!("SELECT note FROM scratch"),
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -42,4 +127,1971 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_dynamic_params() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::{params, traits::dynamic::Value};
+
+        let conn = LazyConnection::open_memory()?;
+        conn.execute_dynamic("CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, score REAL)")?;
+
+        conn.execute_dynamic_with(
+            "INSERT INTO users (id, name, score) VALUES (?, ?, ?)",
+            params![1, "Alice", 2.5],
+        )?;
+        conn.execute_dynamic_with(
+            "INSERT INTO users (id, name, score) VALUES (?1, ?2, ?3)",
+            &[Value::Integer(2), Value::Text("Bob".into()), Value::Null][..],
+        )?;
+        conn.execute_dynamic_with(
+            "INSERT INTO users (id, name, score) VALUES (:id, @name, $score)",
+            params![":id" => 3, "name" => "Robert'); DROP TABLE users;--", "$score" => None::<f64>],
+        )?;
+
+        let row = conn
+            .query_dynamic_with("SELECT name FROM users WHERE id = :id", params![":id" => 3])?
+            .first()?
+            .unwrap();
+        assert_eq!(row[0].as_string(), "Robert'); DROP TABLE users;--");
+
+        let rows = conn
+            .query_dynamic_with("SELECT id FROM users WHERE score IS NULL", params![])?
+            .all()?;
+        assert_eq!(rows.len(), 2);
+
+        let err = conn
+            .execute_dynamic_with("DELETE FROM users WHERE id = ?", params![1, 2])
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            lazysql::errors::SqlDynamicError::Params(lazysql::errors::ParamsError::CountMismatch {
+                expected: 1,
+                provided: 2
+            })
+        ));
+
+        let result =
+            conn.query_dynamic_with("SELECT * FROM users WHERE id = :id", params![":nope" => 1]);
+        assert!(matches!(
+            result,
+            Err(lazysql::errors::SqlDynamicError::Params(
+                lazysql::errors::ParamsError::UnknownName(_)
+            ))
+        ));
+
+        // the count matches, but ":name" would be left NULL
+        let result = conn.execute_dynamic_with(
+            "UPDATE users SET name = :name WHERE id = :id",
+            params![":id" => 1, "id" => 2],
+        );
+        assert!(matches!(
+            result,
+            Err(lazysql::errors::SqlDynamicError::Params(
+                lazysql::errors::ParamsError::DuplicateName(_)
+            ))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dynamic_statement_cache() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::params;
+
+        let conn = LazyConnection::open_memory()?;
+        conn.execute_dynamic("CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT)")?;
+
+        for i in 0..5 {
+            conn.execute_dynamic_with("INSERT INTO t (id, v) VALUES (?, ?)", params![i, "x"])?;
+        }
+        let stats = conn.statement_cache_stats();
+        assert_eq!((stats.hits, stats.misses), (4, 2));
+
+        // a cached statement comes back without the previous bindings, so both are NULL
+        conn.execute_dynamic("INSERT INTO t (id, v) VALUES (?, ?)")?;
+        let row = conn
+            .query_dynamic("SELECT v FROM t WHERE id = 5")?
+            .first()?
+            .unwrap();
+        assert!(row[0].is_null());
+
+        // the same sql can be in use twice at once
+        let a = conn.query_dynamic("SELECT id FROM t ORDER BY id")?;
+        let b = conn.query_dynamic("SELECT id FROM t ORDER BY id")?;
+        assert_eq!(a.all()?.len(), b.all()?.len());
+
+        conn.set_statement_cache_capacity(1);
+        assert_eq!(conn.statement_cache_stats().len, 1);
+
+        conn.clear_statement_cache();
+        assert_eq!(conn.statement_cache_stats().len, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pool_routing() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::LazyPool;
+
+        let path = std::env::temp_dir().join(format!("lazysql_pool_{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+
+        let pool = LazyPool::open(&path, 2)?;
+        CounterDao::from_pool(&pool).init()?;
+
+        let handles: Vec<_> = (0..4)
+            .map(|thread| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    let mut dao = CounterDao::from_pool(&pool);
+                    for _ in 0..25 {
+                        dao.add(thread).unwrap();
+                    }
+                    dao.count().unwrap().first().unwrap().unwrap().total
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert!(handle.join().unwrap() >= 25);
+        }
+
+        let mut dao = CounterDao::from_pool(&pool);
+        assert_eq!(dao.count()?.first()?.unwrap().total, 100);
+
+        // reads inside a transaction see its own uncommitted writes
+        let in_tx = dao.transaction(|tx| -> Result<_, lazysql::errors::Error> {
+            tx.add(99)?;
+            Ok(tx.count()?.first()?.unwrap().total)
+        })?;
+        assert_eq!(in_tx, 101);
+
+        // readers are only checked out per query, so there can be more structs than readers,
+        // and a write nested in a transaction of the same thread does not wait for itself
+        let mut others = [
+            CounterDao::from_pool(&pool),
+            CounterDao::from_pool(&pool),
+        ];
+        dao.transaction(|tx| -> Result<_, lazysql::errors::Error> {
+            tx.add(98)?;
+            others[0].add(97)?;
+            Ok(())
+        })?;
+        for other in &mut others {
+            assert_eq!(other.count()?.first()?.unwrap().total, 103);
+        }
+        assert_eq!(pool.writer().execute_dynamic("DELETE FROM hits WHERE thread IN (97, 98)")?, 2);
+
+        {
+            let writer = pool.writer();
+            writer.exec("BEGIN")?;
+            writer.execute_dynamic("INSERT INTO hits (thread) VALUES (7)")?;
+            // runs on the reader, which does not see the pending write
+            assert_eq!(dao.count()?.first()?.unwrap().total, 101);
+            writer.exec("ROLLBACK")?;
+        }
+
+        // an in memory database cannot use WAL, so its readers would not share it
+        assert!(matches!(
+            LazyPool::open(":memory:", 2),
+            Err(lazysql::errors::connection::SqliteOpenErrors::WalUnavailable { mode }) if mode == "memory"
+        ));
+
+        // readers are read only
+        assert!(pool.reader()?.execute_dynamic("DELETE FROM hits").is_err());
+        assert_eq!(pool.writer().execute_dynamic("DELETE FROM hits")?, 101);
+
+        // a thread holding every reader gets an error instead of waiting for itself
+        let small = LazyPool::open(&path, 1)?;
+        small.set_reader_timeout(std::time::Duration::from_millis(50));
+        let (mut first, mut second) = (CounterDao::from_pool(&small), CounterDao::from_pool(&small));
+        let held = first.count()?;
+        assert!(second.count().is_err());
+        drop(held);
+        assert_eq!(second.count()?.first()?.unwrap().total, 0);
+
+        drop(dao);
+        drop(pool);
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_threading_modes() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::internal_sqlite::threading::PerThread;
+        use std::sync::Arc;
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        fn assert_send<T: Send>() {}
+        assert_send_sync::<LazyConnection>();
+        assert_send_sync::<ShopDao>();
+        assert_send::<LazyConnection<PerThread>>();
+
+        // a serialized connection can be shared by structs living on different threads
+        let conn = LazyConnection::open_memory()?;
+        CounterDao::new(conn.clone()).init()?;
+        let handles: Vec<_> = (0..4)
+            .map(|thread| {
+                let conn = Arc::clone(&conn);
+                std::thread::spawn(move || {
+                    let mut dao = CounterDao::new(conn);
+                    for _ in 0..25 {
+                        dao.add(thread).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(CounterDao::new(conn).count()?.first()?.unwrap().total, 100);
+
+        // a per thread connection can be moved to another thread and used there
+        let conn = LazyConnection::open_memory_per_thread()?;
+        let total = std::thread::spawn(move || {
+            let mut dao = CounterDao::new(conn);
+            dao.init().unwrap();
+            dao.add(1).unwrap();
+            dao.count().unwrap().first().unwrap().unwrap().total
+        })
+        .join()
+        .unwrap();
+        assert_eq!(total, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_options() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::errors::connection::SqliteOpenErrors;
+        use lazysql::internal_sqlite::open_options::{DbConfig, JournalMode, Synchronous};
+        use std::time::Duration;
+
+        let path = std::env::temp_dir().join(format!("lazysql_open_{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+
+        let conn = LazyConnection::builder()
+            .journal_mode(JournalMode::Truncate)
+            .synchronous(Synchronous::Normal)
+            .cache_size(-4000)
+            .foreign_keys(false)
+            .busy_timeout(Duration::from_millis(100))
+            .defensive(true)
+            .open(&path)?;
+        let pragma =
+            |sql: &str| conn.query_dynamic(sql).unwrap().first().unwrap().unwrap()[0].clone();
+        assert_eq!(pragma("PRAGMA journal_mode").as_string(), "truncate");
+        assert_eq!(pragma("PRAGMA synchronous").as_i64(), 1);
+        assert_eq!(pragma("PRAGMA cache_size").as_i64(), -4000);
+        assert_eq!(pragma("PRAGMA foreign_keys").as_i64(), 0);
+        assert!(conn.db_config(DbConfig::Defensive, true)?);
+        CounterDao::new(conn).init()?;
+
+        let read_only = LazyConnection::builder().read_only().open(&path)?;
+        assert!(read_only.execute_dynamic("DELETE FROM hits").is_err());
+        let immutable = LazyConnection::builder()
+            .immutable()
+            .per_thread()
+            .open(&path)?;
+        assert_eq!(
+            CounterDao::new(immutable).count()?.first()?.unwrap().total,
+            0
+        );
+
+        // two connections to the same shared in memory database
+        let uri = "file:lazysql_shared?mode=memory&cache=shared";
+        let first = std::sync::Arc::new(LazyConnection::builder().uri().open(uri)?);
+        let mut dao = CounterDao::new(first.clone());
+        dao.init()?;
+        dao.add(7)?;
+        let second = LazyConnection::builder().uri().open(uri)?;
+        assert_eq!(CounterDao::new(second).count()?.first()?.unwrap().total, 1);
+
+        assert!(matches!(
+            LazyConnection::builder().open("bad\0name.db"),
+            Err(SqliteOpenErrors::EmbeddedNullInFileName { .. })
+        ));
+        assert!(matches!(
+            LazyConnection::builder().vfs("no\0vfs").open_memory(),
+            Err(SqliteOpenErrors::EmbeddedNullInVfsName { .. })
+        ));
+
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_behavior() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::internal_sqlite::transaction::TransactionBehavior;
+        use std::time::Duration;
+
+        let path = std::env::temp_dir().join(format!("lazysql_behavior_{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+
+        let builder = LazyConnection::builder().busy_timeout(Duration::ZERO);
+        let conn = std::sync::Arc::new(builder.open(&path)?);
+        let other = builder.open(&path)?;
+
+        // the attribute makes `transaction` take the write lock on BEGIN
+        let mut dao = ImmediateCounterDao::new(conn.clone());
+        dao.init()?;
+        dao.transaction(|_| -> Result<_, lazysql::errors::Error> {
+            assert!(other.exec("BEGIN IMMEDIATE").is_err());
+            Ok(())
+        })?;
+
+        // a deferred transaction takes no lock until it reads or writes
+        dao.transaction_with(TransactionBehavior::Deferred, |tx| -> Result<_, lazysql::errors::Error> {
+            other.exec("BEGIN IMMEDIATE")?;
+            other.exec("ROLLBACK")?;
+            tx.add(1)?;
+            Ok(())
+        })?;
+
+        conn.transaction_with(TransactionBehavior::Exclusive, |tx| -> Result<_, lazysql::errors::Error> {
+            assert!(other.query_dynamic("SELECT * FROM hits").is_err());
+            tx.execute_dynamic("INSERT INTO hits (thread) VALUES (2)")?;
+            Ok(())
+        })?;
+        assert_eq!(CounterDao::new(conn).count()?.first()?.unwrap().total, 2);
+
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_transactions() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::errors::{Error, SqliteFailure};
+
+        let failure = || {
+            Error::Db(SqliteFailure {
+                code: 1,
+                error_msg: "give up".to_string(),
+            })
+        };
+
+        let conn = LazyConnection::open_memory()?;
+        let mut dao = CounterDao::new(conn.clone());
+        dao.init()?;
+
+        dao.transaction(|tx| -> Result<_, Error> {
+            tx.add(1)?;
+            // the failing inner transaction only rolls back its own writes
+            let inner = tx.transaction(|tx| {
+                tx.add(2)?;
+                Err::<(), _>(failure())
+            });
+            assert!(inner.is_err());
+            tx.transaction(|tx| -> Result<_, Error> {
+                tx.add(3)?;
+                tx.savepoint("deepest", |tx| tx.add(4).map_err(Error::from))
+            })?;
+            Ok(())
+        })?;
+        assert_eq!(dao.count()?.first()?.unwrap().total, 3);
+        assert!(!conn.in_transaction());
+
+        // explicit savepoints on the connection, with a name that needs quoting
+        let result = conn.savepoint("import \"batch\"", |tx| -> Result<_, Error> {
+            tx.execute_dynamic("INSERT INTO hits (thread) VALUES (5)")?;
+            assert!(tx.in_transaction());
+            tx.transaction(|_| Err::<(), _>(failure()))
+        });
+        assert!(result.is_err());
+        assert!(!conn.in_transaction());
+        assert_eq!(dao.count()?.first()?.unwrap().total, 3);
+
+        // an error in the outer transaction still undoes the released inner one
+        let result = conn.transaction(|tx| -> Result<(), Error> {
+            tx.transaction(|tx| tx.execute_dynamic("DELETE FROM hits").map_err(Error::from))?;
+            Err::<(), _>(failure())
+        });
+        assert!(result.is_err());
+        assert_eq!(dao.count()?.first()?.unwrap().total, 3);
+
+        // a null byte in the name is an error rather than a panic
+        let result = conn.savepoint("bad\0name", |_| Ok::<_, Error>(()));
+        assert!(matches!(result, Err(Error::Db(SqliteFailure { code: 21, .. }))));
+        assert!(!conn.in_transaction());
+
+        // the dynamic queries report it the same way
+        let result = conn.execute_dynamic("SELECT 1\0");
+        assert!(matches!(result, Err(SqliteFailure { code: 21, .. })));
+        let result = conn.query_dynamic("SELECT 1\0").map(|_| ());
+        assert!(matches!(result, Err(SqliteFailure { code: 21, .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_guard() -> Result<(), Box<dyn std::error::Error>> {
+        let conn = LazyConnection::open_memory()?;
+        let mut dao = CounterDao::new(conn.clone());
+        dao.init()?;
+
+        // dropped without commit
+        {
+            let tx = conn.begin()?;
+            tx.execute_dynamic("INSERT INTO hits (thread) VALUES (1)")?;
+        }
+        assert!(!conn.in_transaction());
+        assert_eq!(dao.count()?.first()?.unwrap().total, 0);
+
+        // early returns with `?` roll back too
+        fn import(conn: &LazyConnection, rows: &[i64]) -> Result<(), String> {
+            let tx = conn.begin().map_err(|e| e.to_string())?;
+            for &row in rows {
+                if row < 0 {
+                    return Err(format!("invalid row {row}"));
+                }
+                tx.execute_dynamic(&format!("INSERT INTO hits (thread) VALUES ({row})"))
+                    .map_err(|e| e.to_string())?;
+            }
+            tx.commit().map_err(|e| e.to_string())
+        }
+        assert!(import(&conn, &[1, 2, -3]).is_err());
+        import(&conn, &[1, 2])?;
+        assert_eq!(dao.count()?.first()?.unwrap().total, 2);
+
+        // generated methods are called through the guard, nested guards are savepoints
+        let mut tx = dao.begin()?;
+        assert!(!tx.is_savepoint());
+        tx.add(3)?;
+        {
+            let mut inner = tx.begin()?;
+            assert!(inner.is_savepoint());
+            inner.add(4)?;
+            inner.rollback()?;
+        }
+        let mut inner = tx.begin()?;
+        inner.add(5)?;
+        inner.commit()?;
+        assert_eq!(tx.count()?.first()?.unwrap().total, 4);
+        tx.commit()?;
+        assert_eq!(dao.count()?.first()?.unwrap().total, 4);
+
+        let tx = dao.begin()?;
+        tx.rollback()?;
+        assert!(!conn.in_transaction());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_error_types() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::errors::Error;
+
+        #[derive(Debug)]
+        enum ShopError {
+            Db(Error),
+            OutOfStock(i64),
+        }
+
+        impl<E: Into<Error>> From<E> for ShopError {
+            fn from(e: E) -> Self {
+                ShopError::Db(e.into())
+            }
+        }
+
+        impl std::fmt::Display for ShopError {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match self {
+                    ShopError::Db(e) => write!(f, "{e}"),
+                    ShopError::OutOfStock(qty) => write!(f, "{qty} are out of stock"),
+                }
+            }
+        }
+
+        impl std::error::Error for ShopError {}
+
+        let conn = LazyConnection::open_memory()?;
+        let mut dao = OrderDao::new(conn.clone());
+        dao.init_customers()?;
+        dao.init_orders()?;
+        conn.execute_dynamic("INSERT INTO customers (id) VALUES (1)")?;
+
+        // the closure's own error type comes back untouched after the rollback
+        let result = dao.transaction(|tx| {
+            tx.place(1, 2)?;
+            tx.place(1, 500)?;
+            Err::<(), _>(ShopError::OutOfStock(500))
+        });
+        assert!(matches!(result, Err(ShopError::OutOfStock(500))));
+        assert_eq!(dao.count()?.first()?.unwrap().total, 0);
+
+        // the deferred foreign key is only checked on COMMIT
+        let result = dao.transaction(|tx| -> Result<_, ShopError> {
+            tx.place(42, 1)?;
+            Ok(())
+        });
+        assert!(matches!(result, Err(ShopError::Db(Error::Commit(_)))));
+        assert!(!conn.in_transaction());
+        assert_eq!(dao.count()?.first()?.unwrap().total, 0);
+
+        // releasing the savepoint by hand makes the ROLLBACK TO fail
+        let result = conn.savepoint("manual", |tx| {
+            tx.exec("RELEASE manual")?;
+            Err::<(), _>(ShopError::OutOfStock(1))
+        });
+        match result {
+            Err(ShopError::Db(Error::Rollback { cause, .. })) => {
+                assert!(matches!(cause.downcast_ref(), Some(ShopError::OutOfStock(1))))
+            }
+            other => panic!("expected a rollback failure, got {other:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_policy() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::errors::Error;
+        use lazysql::internal_sqlite::retry::RetryPolicy;
+        use std::time::Duration;
+
+        let policy = RetryPolicy::new(4)
+            .backoff(Duration::from_millis(10), Duration::from_millis(25))
+            .jitter(false);
+        let delays: Vec<_> = (1..=4).map(|attempt| policy.delay_after(attempt)).collect();
+        assert_eq!(
+            delays,
+            [
+                Some(Duration::from_millis(10)),
+                Some(Duration::from_millis(20)),
+                Some(Duration::from_millis(25)),
+                None
+            ]
+        );
+        assert_eq!(policy.delay_after(0), Some(Duration::from_millis(10)));
+
+        let path = std::env::temp_dir().join(format!("lazysql_retry_{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+
+        let conn = LazyConnection::builder()
+            .busy_timeout(Duration::ZERO)
+            .retry_policy(RetryPolicy::new(100).backoff(Duration::from_millis(5), Duration::from_millis(20)))
+            .open(&path)?;
+        conn.execute_dynamic("CREATE TABLE hits (id INTEGER PRIMARY KEY NOT NULL, thread INTEGER NOT NULL)")?;
+
+        // another connection holds the write lock for a while
+        let hold_lock = |millis| -> Result<_, Box<dyn std::error::Error>> {
+            let other = LazyConnection::builder().busy_timeout(Duration::ZERO).open(&path)?;
+            other.exec("BEGIN IMMEDIATE")?;
+            Ok(std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(millis));
+                other.exec("COMMIT").unwrap();
+            }))
+        };
+
+        // the INSERT hits SQLITE_BUSY until the lock is released, then the closure is re-run
+        let holder = hold_lock(50)?;
+        let mut attempts = 0;
+        conn.transaction(|tx| -> Result<_, Error> {
+            attempts += 1;
+            tx.execute_dynamic("INSERT INTO hits (thread) VALUES (1)")?;
+            Ok(())
+        })?;
+        holder.join().unwrap();
+        assert!(attempts > 1);
+
+        // only the error the closure returned counts, not what the connection saw before it
+        let holder = hold_lock(50)?;
+        let mut attempts = 0;
+        type BoxError = Box<dyn std::error::Error + Send + Sync>;
+        let result = conn.transaction(|tx| -> Result<(), BoxError> {
+            attempts += 1;
+            assert!(tx.execute_dynamic("INSERT INTO hits (thread) VALUES (4)").is_err());
+            Err("gave up".into())
+        });
+        holder.join().unwrap();
+        assert_eq!(result.unwrap_err().to_string(), "gave up");
+        assert_eq!(attempts, 1);
+
+        // an error type of its own is retried when one of the statements was busy
+        #[derive(Debug)]
+        struct AppError(Error);
+
+        impl From<Error> for AppError {
+            fn from(e: Error) -> Self {
+                AppError(e)
+            }
+        }
+
+        impl std::fmt::Display for AppError {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "app: {}", self.0)
+            }
+        }
+
+        impl std::error::Error for AppError {}
+
+        let holder = hold_lock(50)?;
+        let mut attempts = 0;
+        conn.transaction(|tx| -> Result<_, AppError> {
+            attempts += 1;
+            tx.execute_dynamic("INSERT INTO hits (thread) VALUES (5)")
+                .map_err(Error::from)?;
+            Ok(())
+        })?;
+        holder.join().unwrap();
+        assert!(attempts > 1);
+
+        // BEGIN IMMEDIATE itself is retried as well
+        let holder = hold_lock(50)?;
+        let mut dao = CounterDao::new(conn);
+        let mut attempts = 0;
+        dao.transaction_with(
+            lazysql::internal_sqlite::transaction::TransactionBehavior::Immediate,
+            |tx| -> Result<_, Error> {
+                attempts += 1;
+                tx.add(2)?;
+                Ok(())
+            },
+        )?;
+        holder.join().unwrap();
+        assert_eq!(attempts, 1);
+        assert_eq!(dao.count()?.first()?.unwrap().total, 3);
+
+        // without a policy the first SQLITE_BUSY is returned
+        let conn = LazyConnection::builder().busy_timeout(Duration::ZERO).open(&path)?;
+        let holder = hold_lock(50)?;
+        let mut attempts = 0;
+        let result = conn.transaction(|tx| -> Result<_, Error> {
+            attempts += 1;
+            tx.execute_dynamic("INSERT INTO hits (thread) VALUES (3)")?;
+            Ok(())
+        });
+        holder.join().unwrap();
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::internal_sqlite::retry::RetryPolicy;
+        use std::time::Duration;
+
+        let path = std::env::temp_dir().join(format!("lazysql_backup_{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+
+        let conn = LazyConnection::open_memory()?;
+        let mut dao = CounterDao::new(conn.clone());
+        dao.init()?;
+        for i in 0..50 {
+            dao.add(i)?;
+        }
+        // spread over enough pages to need several steps
+        conn.execute_dynamic("CREATE TABLE padding (data BLOB NOT NULL)")?;
+        conn.execute_dynamic("WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 20) INSERT INTO padding SELECT randomblob(4000) FROM n")?;
+
+        let mut steps = Vec::new();
+        conn.backup_to(&path, 5, |progress| steps.push(progress))?;
+        assert!(steps.len() > 1);
+        assert_eq!(steps.last().unwrap().remaining, 0);
+        assert!(steps.iter().all(|p| p.page_count == steps[0].page_count));
+
+        let on_disk = LazyConnection::open(&path)?;
+        assert_eq!(CounterDao::new(on_disk.clone()).count()?.first()?.unwrap().total, 50);
+
+        // and back into memory
+        let restored = LazyConnection::open_memory()?;
+        restored.load_from_file(&path)?;
+        assert_eq!(CounterDao::new(restored.clone()).count()?.first()?.unwrap().total, 50);
+
+        // connection to connection, replacing what was there
+        let copy = LazyConnection::open_memory()?;
+        copy.execute_dynamic("CREATE TABLE other (x INTEGER)")?;
+        copy.restore_from(&conn, -1, |_| {})?;
+        assert_eq!(CounterDao::new(copy.clone()).count()?.first()?.unwrap().total, 50);
+        assert!(copy.query_dynamic("SELECT * FROM other").is_err());
+
+        // a missing source is an error, not an empty file restored over the data
+        let missing = format!("{path}.missing");
+        assert!(restored.load_from_file(&missing).is_err());
+        assert!(copy.restore_from(missing.as_str(), -1, |_| {}).is_err());
+        assert!(!std::path::Path::new(&missing).exists());
+        assert_eq!(CounterDao::new(restored.clone()).count()?.first()?.unwrap().total, 50);
+
+        // a locked destination fails right away without a retry policy ...
+        on_disk.set_busy_timeout(Duration::ZERO)?;
+        on_disk.exec("BEGIN EXCLUSIVE")?;
+        let dest = LazyConnection::builder().busy_timeout(Duration::ZERO).open(&path)?;
+        let err = conn.backup_to(&dest, -1, |_| {}).unwrap_err();
+        assert!(matches!(
+            err,
+            lazysql::errors::BackupError::Step(ref f) if lazysql::internal_sqlite::retry::is_busy(f.code)
+        ));
+
+        // ... and is waited out with one
+        conn.set_retry_policy(Some(
+            RetryPolicy::new(100).backoff(Duration::from_millis(5), Duration::from_millis(20)),
+        ));
+        let holder = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            on_disk.exec("COMMIT").unwrap();
+        });
+        conn.backup_to(&dest, -1, |_| {})?;
+        holder.join().unwrap();
+
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialize() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::errors::SerializeError;
+
+        let conn = LazyConnection::open_memory()?;
+        let mut dao = CounterDao::new(conn.clone());
+        dao.init()?;
+        dao.add(1)?;
+        dao.add(2)?;
+
+        let bytes = conn.serialize("main")?;
+        assert!(bytes.starts_with(b"SQLite format 3\0"));
+
+        // a copy that lives on independently
+        let clone = LazyConnection::open_memory()?;
+        clone.deserialize(&bytes, false)?;
+        let mut cloned_dao = CounterDao::new(clone.clone());
+        cloned_dao.add(3)?;
+        assert_eq!(cloned_dao.count()?.first()?.unwrap().total, 3);
+        assert_eq!(dao.count()?.first()?.unwrap().total, 2);
+
+        // read only copies reject writes
+        let snapshot = LazyConnection::open_memory()?;
+        snapshot.deserialize(&bytes, true)?;
+        let mut snapshot_dao = CounterDao::new(snapshot.clone());
+        assert_eq!(snapshot_dao.count()?.first()?.unwrap().total, 2);
+        assert!(snapshot_dao.add(4).is_err());
+
+        // round trip through a modified copy
+        assert_eq!(clone.serialize("main")?.len() % 512, 0);
+        conn.deserialize(&clone.serialize("main")?, false)?;
+        assert_eq!(dao.count()?.first()?.unwrap().total, 3);
+
+        assert!(matches!(conn.serialize("nope"), Err(SerializeError::Sqlite(_))));
+        assert!(matches!(
+            conn.serialize("ma\0in"),
+            Err(SerializeError::EmbeddedNullInSchemaName { .. })
+        ));
+        assert!(conn.deserialize(b"not a database", false).is_ok());
+        assert!(conn.query_dynamic("SELECT * FROM hits").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_blob_io() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::params;
+        use lazysql::traits::{dynamic::Value, to_sql::ZeroBlob};
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let conn = LazyConnection::open_memory()?;
+        conn.execute_dynamic("CREATE TABLE files (id INTEGER PRIMARY KEY, data BLOB NOT NULL)")?;
+        conn.execute_dynamic_with("INSERT INTO files (id, data) VALUES (1, ?)", params![ZeroBlob(10)])?;
+
+        // stream into the reserved space
+        let mut blob = conn.blob_open("files", "data", 1, true)?;
+        assert_eq!(blob.len(), 10);
+        blob.write_all(b"hello")?;
+        blob.write_all(b"world")?;
+        assert!(blob.write_all(b"!").is_err());
+        drop(blob);
+
+        // dynamic rows return blobs as Value::Blob
+        let row = conn.query_dynamic("SELECT data FROM files WHERE id = 1")?.first()?.unwrap();
+        assert!(matches!(&row[0], Value::Blob(b) if b == b"helloworld"));
+
+        // and read it back in pieces
+        let mut blob = conn.blob_open("files", "data", 1, false)?;
+        let mut first = [0u8; 5];
+        blob.read_exact(&mut first)?;
+        assert_eq!(&first, b"hello");
+        blob.seek(SeekFrom::End(-3))?;
+        let mut rest = String::new();
+        blob.read_to_string(&mut rest)?;
+        assert_eq!(rest, "rld");
+        assert!(blob.seek(SeekFrom::Current(1)).is_err());
+        blob.rewind()?;
+        assert!(blob.write(b"x").is_err());
+
+        // moving on to another row
+        conn.execute_dynamic_with("INSERT INTO files (id, data) VALUES (2, ?)", params![b"bytes".as_slice()])?;
+        blob.reopen(2)?;
+        let mut all = Vec::new();
+        blob.read_to_end(&mut all)?;
+        assert_eq!(all, b"bytes");
+        drop(blob);
+
+        // a handle on a changed row expires
+        let mut blob = conn.blob_open("files", "data", 1, false)?;
+        conn.execute_dynamic("UPDATE files SET data = x'00' WHERE id = 1")?;
+        assert!(blob.read(&mut first).is_err());
+        drop(blob);
+
+        assert!(conn.blob_open("files", "data", 99, false).is_err());
+        assert!(conn.blob_open("files", "missing", 1, false).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scalar_functions() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::internal_sqlite::functions::{FunctionArgs, FunctionFlags};
+        use lazysql::traits::dynamic::Value;
+
+        let conn = LazyConnection::open_memory()?;
+
+        conn.create_scalar_function(
+            "slugify",
+            1,
+            FunctionFlags::DETERMINISTIC | FunctionFlags::INNOCUOUS,
+            |args: &FunctionArgs| -> Result<_, String> {
+                let text: String = args.get(0);
+                Ok(text.to_lowercase().split_whitespace().collect::<Vec<_>>().join("-"))
+            },
+        )?;
+        let row = conn.query_dynamic("SELECT slugify('Hello Big World')")?.first()?.unwrap();
+        assert_eq!(row[0].as_string(), "hello-big-world");
+
+        // typed and optional arguments, any number of them
+        conn.create_scalar_function("total", -1, FunctionFlags::DETERMINISTIC, |args: &FunctionArgs| {
+            let sum: Option<f64> = (0..args.len())
+                .filter_map(|i| args.get::<Option<f64>>(i))
+                .reduce(|a, b| a + b);
+            Ok::<_, String>(sum)
+        })?;
+        let row = conn.query_dynamic("SELECT total(1, NULL, 2.5), total(), total(NULL)")?.first()?.unwrap();
+        assert_eq!(row[0].as_f64(), 3.5);
+        assert!(row[1].is_null() && row[2].is_null());
+
+        // Values keep their storage class in both directions
+        conn.create_scalar_function("echo", 1, FunctionFlags::NONE, |args: &FunctionArgs| {
+            Ok::<_, String>(args.value(0))
+        })?;
+        let row = conn.query_dynamic("SELECT echo(1), echo(1.5), echo('a'), echo(x'0102'), echo(NULL)")?.first()?.unwrap();
+        assert!(matches!(row[0], Value::Integer(1)));
+        assert!(matches!(row[1], Value::Real(_)));
+        assert!(matches!(&row[2], Value::Text(t) if t == "a"));
+        assert!(matches!(&row[3], Value::Blob(b) if b == &[1, 2]));
+        assert!(row[4].is_null());
+
+        // usable in the schema since it is deterministic
+        conn.execute_dynamic("CREATE TABLE posts (title TEXT NOT NULL, slug TEXT AS (slugify(title)))")?;
+        conn.execute_dynamic("INSERT INTO posts (title) VALUES ('First Post')")?;
+        let row = conn.query_dynamic("SELECT slug FROM posts")?.first()?.unwrap();
+        assert_eq!(row[0].as_string(), "first-post");
+
+        // errors and panics become SQL errors
+        conn.create_scalar_function("validate", 1, FunctionFlags::NONE, |args: &FunctionArgs| {
+            let n: i64 = args.get(0);
+            if n < 0 {
+                return Err(format!("{n} is negative"));
+            }
+            Ok(n)
+        })?;
+        conn.create_scalar_function("boom", 0, FunctionFlags::NONE, |_: &FunctionArgs| -> Result<i64, String> {
+            panic!("kaboom")
+        })?;
+        let err = conn.query_dynamic("SELECT validate(-1)")?.first().unwrap_err();
+        assert!(err.to_string().contains("-1 is negative"), "{err}");
+        let err = conn.query_dynamic("SELECT boom()")?.first().unwrap_err();
+        assert!(err.to_string().contains("kaboom"), "{err}");
+        assert_eq!(conn.query_dynamic("SELECT validate(2)")?.first()?.unwrap()[0].as_i64(), 2);
+
+        conn.remove_function("validate", 1)?;
+        assert!(conn.query_dynamic("SELECT validate(3)").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_aggregate_functions() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::internal_sqlite::functions::{
+            Aggregate, FunctionArgs, FunctionFlags, FunctionResult, WindowAggregate,
+        };
+
+        struct Median;
+
+        impl Aggregate for Median {
+            type State = Vec<f64>;
+            type Output = Option<f64>;
+
+            fn init(&self) -> Vec<f64> {
+                Vec::new()
+            }
+
+            fn step(&self, state: &mut Vec<f64>, args: &FunctionArgs) -> FunctionResult<()> {
+                match args.get::<Option<f64>>(0) {
+                    Some(x) if !x.is_finite() => Err(format!("{x} is not a finite number").into()),
+                    Some(x) => {
+                        state.push(x);
+                        Ok(())
+                    }
+                    None => Ok(()),
+                }
+            }
+
+            fn finalize(&self, mut state: Vec<f64>) -> FunctionResult<Option<f64>> {
+                if state.is_empty() {
+                    return Ok(None);
+                }
+                state.sort_by(f64::total_cmp);
+                let mid = state.len() / 2;
+                Ok(Some(if state.len().is_multiple_of(2) {
+                    (state[mid - 1] + state[mid]) / 2.0
+                } else {
+                    state[mid]
+                }))
+            }
+        }
+
+        struct MovingSum;
+
+        impl Aggregate for MovingSum {
+            type State = i64;
+            type Output = i64;
+
+            fn init(&self) -> i64 {
+                0
+            }
+
+            fn step(&self, state: &mut i64, args: &FunctionArgs) -> FunctionResult<()> {
+                *state += args.get::<i64>(0);
+                Ok(())
+            }
+
+            fn finalize(&self, state: i64) -> FunctionResult<i64> {
+                Ok(state)
+            }
+        }
+
+        impl WindowAggregate for MovingSum {
+            fn inverse(&self, state: &mut i64, args: &FunctionArgs) -> FunctionResult<()> {
+                *state -= args.get::<i64>(0);
+                Ok(())
+            }
+
+            fn value(&self, state: &i64) -> FunctionResult<i64> {
+                Ok(*state)
+            }
+        }
+
+        let conn = LazyConnection::open_memory()?;
+        conn.create_aggregate_function("median", 1, FunctionFlags::DETERMINISTIC, Median)?;
+        conn.create_window_function("moving_sum", 1, FunctionFlags::DETERMINISTIC, MovingSum)?;
+
+        conn.execute_dynamic("CREATE TABLE scores (team TEXT NOT NULL, points INTEGER)")?;
+        conn.execute_dynamic(
+            "INSERT INTO scores VALUES ('a', 1), ('a', 5), ('a', 3), ('b', 4), ('b', 10), ('b', NULL)",
+        )?;
+
+        let rows = conn
+            .query_dynamic("SELECT team, median(points) FROM scores GROUP BY team ORDER BY team")?
+            .all()?;
+        assert_eq!(rows[0][1].as_f64(), 3.0);
+        assert_eq!(rows[1][1].as_f64(), 7.0);
+
+        // an empty group still gets a result
+        let row = conn.query_dynamic("SELECT median(points) FROM scores WHERE team = 'z'")?.first()?.unwrap();
+        assert!(row[0].is_null());
+
+        // as a window function, including rows leaving the frame
+        let sums: Vec<i64> = conn
+            .query_dynamic(
+                "SELECT moving_sum(points) OVER (ORDER BY rowid ROWS BETWEEN 1 PRECEDING AND CURRENT ROW)
+                 FROM scores WHERE points IS NOT NULL",
+            )?
+            .all()?
+            .iter()
+            .map(|row| row[0].as_i64())
+            .collect();
+        assert_eq!(sums, [1, 6, 8, 7, 14]);
+
+        // a plain aggregate cannot be used with OVER
+        assert!(conn.query_dynamic("SELECT median(points) OVER () FROM scores").is_err());
+
+        // errors from step end the query
+        let err = conn.query_dynamic("SELECT median(points * 1e999) FROM scores")?.first();
+        let err = match err {
+            Ok(row) => panic!("expected an error, got {row:?}"),
+            Err(err) => err,
+        };
+        assert!(err.to_string().contains("inf is not a finite number"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_declared_functions() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::internal_sqlite::functions::{
+            Aggregate, FunctionArgs, FunctionFlags, FunctionResult,
+        };
+
+        struct Median;
+
+        impl Aggregate for Median {
+            type State = Vec<f64>;
+            type Output = Option<f64>;
+
+            fn init(&self) -> Vec<f64> {
+                Vec::new()
+            }
+
+            fn step(&self, state: &mut Vec<f64>, args: &FunctionArgs) -> FunctionResult<()> {
+                state.extend(args.get::<Option<f64>>(0));
+                Ok(())
+            }
+
+            fn finalize(&self, mut state: Vec<f64>) -> FunctionResult<Option<f64>> {
+                state.sort_by(f64::total_cmp);
+                Ok(state.get(state.len() / 2).copied())
+            }
+        }
+
+        let conn = LazyConnection::open_memory()?;
+        conn.create_scalar_function("slugify", 1, FunctionFlags::DETERMINISTIC, |args| {
+            let title: String = args.get(0);
+            Ok::<_, String>(title.to_lowercase().replace(' ', "-"))
+        })?;
+        conn.create_scalar_function("score", 2, FunctionFlags::DETERMINISTIC, |args| {
+            let rating: Option<f64> = args.get(1);
+            Ok::<_, String>(args.get::<f64>(0) * rating.unwrap_or(1.0))
+        })?;
+        conn.create_aggregate_function("median", 1, FunctionFlags::DETERMINISTIC, Median)?;
+
+        let mut dao = PostDao::new(conn);
+        dao.init()?;
+        dao.add("Hello World", Some(2.5))?;
+        dao.add("Second Post", None)?;
+
+        // the declared return types end up in the generated structs
+        let rows = dao.slugs()?.all()?;
+        let slug: &String = &rows[0].slug;
+        let score: f64 = rows[1].score;
+        assert_eq!(slug, "hello-world");
+        assert_eq!(rows[0].score, 2.5);
+        assert_eq!(score, 2.0);
+
+        // and the declared parameter types in the generated methods
+        let found = dao.by_slug("HELLO world")?.first()?.unwrap();
+        assert_eq!(found.id, 1);
+
+        let median: Option<f64> = dao.median_rating()?.first()?.unwrap().median;
+        assert_eq!(median, Some(2.5));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_collations() -> Result<(), Box<dyn std::error::Error>> {
+        use std::cmp::Ordering;
+
+        // "file2" < "file10": runs of digits compare by their value
+        fn natural(a: &str, b: &str) -> Ordering {
+            let (mut a, mut b) = (a, b);
+            loop {
+                let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+                let (na, nb) = (digits(a), digits(b));
+                let ordering = if na > 0 && nb > 0 {
+                    let value = |s: &str| s.parse::<u64>().unwrap_or(u64::MAX);
+                    value(&a[..na]).cmp(&value(&b[..nb]))
+                } else {
+                    a.chars().next().cmp(&b.chars().next())
+                };
+                if ordering != Ordering::Equal || a.is_empty() {
+                    return ordering;
+                }
+                let step = |s: &str, n: usize| n.max(s.chars().next().map_or(0, char::len_utf8));
+                (a, b) = (&a[step(a, na)..], &b[step(b, nb)..]);
+            }
+        }
+
+        let conn = LazyConnection::open_memory()?;
+        conn.create_collation("natsort", natural)?;
+
+        let mut dao = FileDao::new(conn);
+        dao.init()?;
+        for name in ["file10", "file2", "File1"] {
+            dao.add(name)?;
+        }
+
+        let names: Vec<String> = dao.sorted()?.all()?.into_iter().map(|r| r.name).collect();
+        assert_eq!(names, ["File1", "file2", "file10"]);
+
+        let names: Vec<String> = dao
+            .sorted_binary()?
+            .all()?
+            .into_iter()
+            .map(|r| r.name)
+            .collect();
+        assert_eq!(names, ["File1", "file10", "file2"]);
+
+        assert_eq!(dao.by_name("FILE2")?.first()?.unwrap().id, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_hooks() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::internal_sqlite::hooks::{ChangeEvent, ChangeOp};
+        use std::sync::mpsc;
+
+        let event = |op, rowid| ChangeEvent {
+            op,
+            database: "main".to_string(),
+            table: "items".to_string(),
+            rowid,
+        };
+
+        let conn = LazyConnection::open_memory()?;
+        conn.execute_dynamic("CREATE TABLE items (id INTEGER PRIMARY KEY, name TEXT)")?;
+
+        let (change_tx, changes) = mpsc::channel();
+        let (commit_tx, commits) = mpsc::channel();
+        let (rollback_tx, rollbacks) = mpsc::channel();
+        let on_change = conn.on_change(move |e| change_tx.send(e.clone()).unwrap());
+        conn.on_commit(move |batch| commit_tx.send(batch.to_vec()).unwrap());
+        conn.on_rollback(move |batch| rollback_tx.send(batch.to_vec()).unwrap());
+
+        // outside of a transaction every statement commits on its own
+        conn.execute_dynamic("INSERT INTO items (name) VALUES ('a')")?;
+        assert_eq!(changes.try_recv()?, event(ChangeOp::Insert, 1));
+        assert_eq!(commits.try_recv()?, [event(ChangeOp::Insert, 1)]);
+
+        conn.transaction(|tx| -> Result<_, lazysql::errors::Error> {
+            tx.execute_dynamic("UPDATE items SET name = 'b' WHERE id = 1")?;
+            tx.execute_dynamic("INSERT INTO items (name) VALUES ('c')")?;
+
+            // a rolled back savepoint drops its changes from the buffer
+            type BoxError = Box<dyn std::error::Error + Send + Sync>;
+            let _ = tx.savepoint("undo", |sp| -> Result<(), BoxError> {
+                sp.execute_dynamic("DELETE FROM items WHERE id = 1")?;
+                Err("undo".into())
+            });
+
+            // seen right away, but not committed yet
+            assert_eq!(changes.try_iter().count(), 3);
+            assert!(commits.try_recv().is_err());
+            assert_eq!(tx.pending_changes().len(), 2);
+            Ok(())
+        })?;
+        assert_eq!(
+            commits.try_recv()?,
+            [event(ChangeOp::Update, 1), event(ChangeOp::Insert, 2)]
+        );
+
+        conn.exec("BEGIN")?;
+        conn.execute_dynamic("DELETE FROM items WHERE id = 2")?;
+        conn.exec("ROLLBACK")?;
+        assert_eq!(changes.try_recv()?, event(ChangeOp::Delete, 2));
+        assert_eq!(rollbacks.try_recv()?, [event(ChangeOp::Delete, 2)]);
+        assert!(commits.try_recv().is_err());
+        assert!(conn.pending_changes().is_empty());
+
+        // a statement failing inside a transaction takes its changes with it,
+        // except for what `OR FAIL` keeps
+        conn.transaction(|tx| -> Result<_, lazysql::errors::Error> {
+            tx.execute_dynamic("INSERT INTO items (id, name) VALUES (10, 'd')")?;
+            let duplicate = "INSERT INTO items (id, name) VALUES (11, 'e'), (10, 'd')";
+            assert!(tx.execute_dynamic(duplicate).is_err());
+            let duplicate = "INSERT OR FAIL INTO items (id, name) VALUES (12, 'f'), (10, 'd')";
+            assert!(tx.exec(duplicate).is_err());
+            Ok(())
+        })?;
+        assert_eq!(changes.try_iter().count(), 3);
+        assert_eq!(
+            commits.try_recv()?,
+            [event(ChangeOp::Insert, 10), event(ChangeOp::Insert, 12)]
+        );
+
+        assert!(conn.unsubscribe(on_change));
+        assert!(!conn.unsubscribe(on_change));
+        conn.execute_dynamic("DELETE FROM items WHERE id = 2")?;
+        assert!(changes.try_recv().is_err());
+        assert_eq!(commits.try_recv()?, [event(ChangeOp::Delete, 2)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_hooks_after_commit() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::traits::dynamic::Value;
+        use std::sync::{Arc, Mutex};
+
+        let conn = Arc::new(LazyConnection::open_memory()?);
+        conn.exec(
+            "PRAGMA foreign_keys = ON;
+             CREATE TABLE parents (id INTEGER PRIMARY KEY);
+             CREATE TABLE children (
+                 id INTEGER PRIMARY KEY,
+                 parent_id INTEGER REFERENCES parents (id) DEFERRABLE INITIALLY DEFERRED
+             );",
+        )?;
+
+        let commits = Arc::new(Mutex::new(Vec::new()));
+        let rollbacks = Arc::new(Mutex::new(Vec::new()));
+        let (reader, seen) = (Arc::downgrade(&conn), Arc::clone(&commits));
+        conn.on_commit(move |batch| {
+            // the commit is done, so the connection can be used again
+            let row = reader
+                .upgrade()
+                .unwrap()
+                .query_dynamic("SELECT count(*) FROM children")
+                .unwrap()
+                .first()
+                .unwrap();
+            let count = match row.as_deref() {
+                Some([Value::Integer(count)]) => *count,
+                _ => -1,
+            };
+            seen.lock().unwrap().push((batch.len(), count));
+        });
+        let seen = Arc::clone(&rollbacks);
+        conn.on_rollback(move |batch| seen.lock().unwrap().push(batch.len()));
+
+        // the deferred foreign key makes COMMIT fail and leaves the transaction open
+        conn.exec("BEGIN")?;
+        conn.execute_dynamic("INSERT INTO children (parent_id) VALUES (1)")?;
+        assert!(conn.exec("COMMIT").is_err());
+        assert!(commits.lock().unwrap().is_empty());
+        assert_eq!(conn.pending_changes().len(), 1);
+
+        conn.execute_dynamic("INSERT INTO parents (id) VALUES (1)")?;
+        conn.exec("COMMIT")?;
+        assert_eq!(*commits.lock().unwrap(), [(2, 1)]);
+
+        conn.exec("BEGIN")?;
+        conn.execute_dynamic("INSERT INTO children (parent_id) VALUES (2)")?;
+        assert!(conn.exec("COMMIT").is_err());
+        conn.exec("ROLLBACK")?;
+        assert_eq!(*rollbacks.lock().unwrap(), [1]);
+        assert!(conn.pending_changes().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupt_and_timeout() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::errors::row::RowMapperError;
+        use std::{thread, time::Duration};
+
+        const FOREVER: &str =
+            "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) SELECT count(*) FROM c";
+
+        let conn = LazyConnection::open_memory()?;
+
+        // from another thread
+        let handle = conn.interrupt_handle();
+        let interrupter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            handle.interrupt();
+        });
+        let result = conn.query_dynamic(FOREVER)?.first();
+        interrupter.join().unwrap();
+        assert!(matches!(result, Err(RowMapperError::Interrupted)));
+
+        // per call
+        let result = conn.with_timeout(Duration::from_millis(50), |conn| {
+            conn.query_dynamic(FOREVER).unwrap().first()
+        });
+        assert!(matches!(result, Err(RowMapperError::Timeout)));
+        assert!(conn.deadline().is_none());
+
+        // execute_dynamic tells the two apart through the result code
+        let result = conn.with_timeout(Duration::from_millis(50), |conn| {
+            conn.execute_dynamic(FOREVER)
+        });
+        let code = result.unwrap_err().code;
+        assert_eq!(code, lazysql::errors::SQLITE_INTERRUPT_TIMEOUT);
+        assert_eq!(code & 0xff, lazysql::libsqlite3_sys::SQLITE_INTERRUPT);
+        let handle = conn.interrupt_handle();
+        let interrupter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            handle.interrupt();
+        });
+        let result = conn.execute_dynamic(FOREVER);
+        interrupter.join().unwrap();
+        assert_eq!(result.unwrap_err().code, lazysql::libsqlite3_sys::SQLITE_INTERRUPT);
+        assert_eq!(conn.query_dynamic("SELECT 1")?.first()?.unwrap()[0].as_i64(), 1);
+
+        // other threads sharing the connection keep running without a deadline
+        conn.with_timeout(Duration::from_millis(1), |conn| {
+            thread::scope(|scope| {
+                let other = scope.spawn(|| {
+                    assert!(conn.deadline().is_none());
+                    let counted = FOREVER.replace("FROM c)", "FROM c WHERE x < 100000)");
+                    conn.query_dynamic(&counted).map(|rows| rows.first())
+                });
+                thread::sleep(Duration::from_millis(5));
+                assert!(conn.deadline().is_some());
+                assert!(other.join().unwrap().unwrap().is_ok());
+            })
+        });
+
+        // per field
+        let mut dao = RecursiveDao::new(conn);
+        assert!(matches!(dao.forever()?.first(), Err(RowMapperError::Timeout)));
+
+        let result = dao.with_timeout(Duration::from_millis(50), |dao| {
+            dao.count_to(i64::MAX).unwrap().first()
+        });
+        assert!(matches!(result, Err(RowMapperError::Timeout)));
+        assert_eq!(dao.count_to(1000)?.first()?.unwrap().n, 1000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_trace() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::{
+            internal_sqlite::trace::{SlowQueryLogger, TraceEvent, TraceEvents, TraceParams},
+            params,
+        };
+        use std::{
+            io::Write,
+            sync::{Arc, Mutex},
+            time::Duration,
+        };
+
+        let conn = LazyConnection::open_memory()?;
+        conn.execute_dynamic("CREATE TABLE t (id INTEGER PRIMARY KEY, secret TEXT)")?;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        conn.trace(TraceEvents::ALL, TraceParams::Inlined, move |event: &TraceEvent| {
+            let kind = match event {
+                TraceEvent::Statement { .. } => "stmt",
+                TraceEvent::Profile { .. } => "profile",
+                TraceEvent::Row { .. } => "row",
+            };
+            sink.lock().unwrap().push((kind, event.sql().to_string()));
+        });
+
+        conn.execute_dynamic_with("INSERT INTO t (secret) VALUES (?)", params!["hunter2"])?;
+        conn.query_dynamic("SELECT id FROM t")?.all()?;
+        let insert = "INSERT INTO t (secret) VALUES ('hunter2')".to_string();
+        let select = "SELECT id FROM t".to_string();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                ("stmt", insert.clone()),
+                ("profile", insert),
+                ("stmt", select.clone()),
+                ("row", select.clone()),
+                ("profile", select),
+            ]
+        );
+
+        // replacing the sink, this time without the values
+        seen.lock().unwrap().clear();
+        let sink = Arc::clone(&seen);
+        conn.trace(TraceEvents::STATEMENT, TraceParams::Redacted, move |event: &TraceEvent| {
+            sink.lock().unwrap().push(("stmt", event.sql().to_string()));
+        });
+        conn.execute_dynamic_with("UPDATE t SET secret = ? WHERE id = ?", params!["hunter3", 1])?;
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [("stmt", "UPDATE t SET secret = ? WHERE id = ?".to_string())]
+        );
+
+        #[derive(Clone, Default)]
+        struct Log(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Log {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let log = Log::default();
+        let logger = SlowQueryLogger::with_writer(Duration::from_secs(60), log.clone());
+        conn.trace(TraceEvents::PROFILE, TraceParams::Redacted, logger);
+        conn.query_dynamic("SELECT count(*) FROM t")?.all()?;
+        assert!(log.0.lock().unwrap().is_empty());
+
+        let logger = SlowQueryLogger::with_writer(Duration::ZERO, log.clone());
+        conn.trace(TraceEvents::PROFILE, TraceParams::Redacted, logger);
+        conn.query_dynamic("SELECT count(*) FROM t")?.all()?;
+        let logged = String::from_utf8(log.0.lock().unwrap().clone())?;
+        assert!(logged.starts_with("slow query ("));
+        assert!(logged.ends_with("): SELECT count(*) FROM t\n"));
+
+        conn.untrace();
+        seen.lock().unwrap().clear();
+        conn.execute_dynamic("DELETE FROM t")?;
+        assert!(seen.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_authorizer() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::internal_sqlite::authorizer::{
+            AuthAction, AuthContext, AuthDecision, ReadOnlySandbox,
+        };
+        use lazysql::{libsqlite3_sys::SQLITE_AUTH, traits::dynamic::Value};
+        use std::sync::{Arc, Mutex};
+
+        // with_authorizer is limited to connections that are not shared between threads
+        let conn = LazyConnection::open_memory_per_thread()?;
+        conn.execute_dynamic("CREATE TABLE reports (id INTEGER PRIMARY KEY, title TEXT)")?;
+        conn.execute_dynamic("CREATE TABLE salaries (id INTEGER PRIMARY KEY, amount REAL)")?;
+        conn.execute_dynamic("CREATE VIEW payroll AS SELECT amount FROM salaries")?;
+        conn.execute_dynamic("INSERT INTO reports (title) VALUES ('q1')")?;
+        conn.execute_dynamic("INSERT INTO salaries (amount) VALUES (100.0)")?;
+
+        // cached before the sandbox, checked again once it is installed
+        assert_eq!(conn.query_dynamic("SELECT amount FROM salaries")?.all()?.len(), 1);
+
+        let sandbox = ReadOnlySandbox::new()
+            .allow_tables(["reports", "SALARIES"])
+            .deny_tables(["salaries"])
+            .allow_functions(["upper", "COUNT"]);
+        conn.with_authorizer(sandbox, |conn| {
+            let reports = conn.query_dynamic("SELECT id, upper(title) FROM Reports").unwrap();
+            assert_eq!(reports.all().unwrap().len(), 1);
+            let count = conn.query_dynamic("SELECT count(*) FROM reports").unwrap();
+            assert_eq!(count.all().unwrap().len(), 1);
+
+            for sql in [
+                "SELECT amount FROM salaries",
+                "SELECT * FROM payroll",
+                "INSERT INTO reports (title) VALUES ('q2')",
+                "DELETE FROM reports",
+                "ATTACH DATABASE ':memory:' AS other",
+                "PRAGMA user_version = 3",
+                "BEGIN",
+            ] {
+                let err = conn.execute_dynamic(sql).unwrap_err();
+                assert_eq!(err.code, SQLITE_AUTH, "{sql}");
+            }
+            // a denied function is reported as a plain SQLITE_ERROR
+            for sql in ["SELECT lower(title) FROM reports", "SELECT randomblob(8)"] {
+                let err = conn.execute_dynamic(sql).unwrap_err();
+                assert!(err.error_msg.contains("not authorized"), "{sql}");
+            }
+        });
+
+        // the previous (no) authorizer is back
+        conn.execute_dynamic("INSERT INTO reports (title) VALUES ('q2')")?;
+        assert_eq!(conn.query_dynamic("SELECT amount FROM salaries")?.all()?.len(), 1);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        conn.set_authorizer(move |ctx: &AuthContext| {
+            if let AuthAction::Read { table, column } = ctx.action {
+                let accessor = ctx.accessor.map(str::to_string);
+                log.lock()
+                    .unwrap()
+                    .push((table.to_string(), column.to_string(), accessor));
+                if column == "title" {
+                    return AuthDecision::Ignore;
+                }
+            }
+            AuthDecision::Allow
+        });
+        let row = conn
+            .query_dynamic("SELECT title FROM reports WHERE id = 1")?
+            .first()?
+            .unwrap();
+        assert!(matches!(row[0], Value::Null));
+        conn.query_dynamic("SELECT amount FROM payroll")?.all()?;
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                ("reports".to_string(), "title".to_string(), None),
+                ("reports".to_string(), "id".to_string(), None),
+                ("salaries".to_string(), "amount".to_string(), Some("payroll".to_string())),
+                ("payroll".to_string(), "amount".to_string(), None),
+            ]
+        );
+
+        conn.remove_authorizer();
+        let row = conn
+            .query_dynamic("SELECT title FROM reports WHERE id = 1")?
+            .first()?
+            .unwrap();
+        assert_eq!(row[0].as_string(), "q1");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "session")]
+    #[test]
+    fn test_session() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::{
+            errors::SessionError,
+            internal_sqlite::{
+                hooks::ChangeOp,
+                session::{ChangesetIter, Conflict, ConflictAction},
+            },
+            traits::dynamic::Value,
+        };
+
+        let schema = "CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT, done INTEGER)";
+        let local = LazyConnection::open_memory()?;
+        let server = LazyConnection::open_memory()?;
+        local.execute_dynamic(schema)?;
+        server.execute_dynamic(schema)?;
+
+        let mut session = local.session()?;
+        session.attach("notes")?;
+        assert!(session.is_empty());
+        local.execute_dynamic("INSERT INTO notes VALUES (1, 'milk', 0), (2, 'eggs', 0)")?;
+        local.execute_dynamic("UPDATE notes SET done = 1 WHERE id = 2")?;
+        let changeset = session.changeset()?;
+
+        // both rows are new to the changeset, so the update is folded into the insert
+        let changes = ChangesetIter::new(&changeset)?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(changes.len(), 2);
+        assert!(changes.iter().all(|c| c.table == "notes" && c.op == ChangeOp::Insert));
+        assert_eq!(changes[0].primary_key, [true, false, false]);
+        assert!(changes[1].old.is_empty());
+        assert!(matches!(changes[1].new[2], Some(Value::Integer(1))));
+
+        server.apply_changeset(&changeset, |_| ConflictAction::Abort)?;
+        let rows = server.query_dynamic("SELECT body FROM notes ORDER BY id")?.all()?;
+        assert_eq!(rows.len(), 2);
+        drop(session);
+
+        // both sides edit the same note, the local edit wins
+        let mut session = local.session()?;
+        session.attach_all()?;
+        server.execute_dynamic("UPDATE notes SET body = 'oat milk', done = 2 WHERE id = 1")?;
+        local.execute_dynamic("UPDATE notes SET done = 1 WHERE id = 1")?;
+        local.execute_dynamic("DELETE FROM notes WHERE id = 2")?;
+        server.execute_dynamic("DELETE FROM notes WHERE id = 2")?;
+        let changeset = session.changeset()?;
+
+        let changes = ChangesetIter::new(&changeset)?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(changes[0].op, ChangeOp::Update);
+        assert!(matches!(changes[0].old[0], Some(Value::Integer(1))));
+        assert!(changes[0].old[1].is_none());
+        assert!(matches!(changes[0].new[2], Some(Value::Integer(1))));
+        assert_eq!(changes[1].op, ChangeOp::Delete);
+
+        let err = server
+            .apply_changeset(&changeset, |_| ConflictAction::Abort)
+            .unwrap_err();
+        assert!(matches!(err, SessionError::Aborted));
+
+        let mut conflicts = Vec::new();
+        server.apply_changeset(&changeset, |conflict| match conflict {
+            Conflict::Data { current, .. } => {
+                conflicts.push(format!("data {}", current[1].as_string()));
+                ConflictAction::Replace
+            }
+            Conflict::NotFound { change } => {
+                conflicts.push(format!("not found {:?}", change.op));
+                ConflictAction::Omit
+            }
+            _ => ConflictAction::Abort,
+        })?;
+        assert_eq!(conflicts, ["data oat milk", "not found Delete"]);
+
+        let row = server
+            .query_dynamic("SELECT body, done FROM notes WHERE id = 1")?
+            .first()?
+            .unwrap();
+        assert_eq!(row[0].as_string(), "oat milk");
+        assert_eq!(row[1].as_i64(), 1);
+
+        let patchset = session.patchset()?;
+        assert!(patchset.len() < changeset.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_extension() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::{errors::ExtensionError, internal_sqlite::open_options::DbConfig};
+
+        // off by default, and still off after trying to load
+        let conn = LazyConnection::open_memory()?;
+        assert!(!conn.db_config_enabled(DbConfig::EnableLoadExtension)?);
+        let err = unsafe { conn.load_extension("does/not/exist", None) }.unwrap_err();
+        assert!(
+            matches!(&err, ExtensionError::Load { path, .. } if path.ends_with("does/not/exist"))
+        );
+        assert!(!conn.db_config_enabled(DbConfig::EnableLoadExtension)?);
+
+        let err = unsafe { conn.load_extension("does/not/exist", Some("bad\0entry")) }.unwrap_err();
+        assert!(matches!(err, ExtensionError::EmbeddedNullInEntryPoint { .. }));
+
+        // enabled on purpose stays enabled
+        let conn = LazyConnection::builder()
+            .db_config(DbConfig::EnableLoadExtension, true)
+            .open_memory()?;
+        assert!(unsafe { conn.load_extension("does/not/exist", None) }.is_err());
+        assert!(conn.db_config_enabled(DbConfig::EnableLoadExtension)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_virtual_tables() -> Result<(), Box<dyn std::error::Error>> {
+        use lazysql::{
+            errors::ModuleError,
+            internal_sqlite::{
+                functions::FunctionArgs,
+                vtab::{ConstraintOp, IndexInfo, VTabCursor, VTabResult, VirtualTable},
+            },
+            traits::dynamic::Value,
+        };
+        use std::{
+            collections::BTreeMap,
+            sync::{Arc, Mutex},
+        };
+
+        type Rows = Arc<Mutex<BTreeMap<i64, (String, String)>>>;
+
+        // settings kept in a map, looked up by rowid when the query allows it
+        struct Settings {
+            rows: Rows,
+            plans: Arc<Mutex<Vec<i32>>>,
+        }
+
+        struct SettingsCursor {
+            source: Rows,
+            plans: Arc<Mutex<Vec<i32>>>,
+            rows: Vec<(i64, (String, String))>,
+            pos: usize,
+        }
+
+        impl VirtualTable for Settings {
+            type Cursor = SettingsCursor;
+
+            fn columns(&self) -> String {
+                "name TEXT, value TEXT".to_string()
+            }
+
+            fn best_index(&self, info: &mut IndexInfo) -> VTabResult<()> {
+                let by_rowid = info
+                    .constraints()
+                    .iter()
+                    .position(|c| c.usable && c.column.is_none() && c.op == ConstraintOp::Eq);
+                if let Some(constraint) = by_rowid {
+                    info.use_constraint(constraint, 0, true);
+                    info.set_idx_num(1);
+                    info.set_unique(true);
+                    info.set_estimated_cost(1.0);
+                } else {
+                    info.set_estimated_cost(1000.0);
+                }
+                Ok(())
+            }
+
+            fn open(&self) -> VTabResult<SettingsCursor> {
+                Ok(SettingsCursor {
+                    source: Arc::clone(&self.rows),
+                    plans: Arc::clone(&self.plans),
+                    rows: Vec::new(),
+                    pos: 0,
+                })
+            }
+
+            fn insert(&mut self, rowid: Option<i64>, values: &FunctionArgs) -> VTabResult<i64> {
+                let mut rows = self.rows.lock().unwrap();
+                let id = rowid.unwrap_or_else(|| rows.keys().last().map_or(1, |id| id + 1));
+                if rows.contains_key(&id) {
+                    return Err(format!("setting {id} already exists").into());
+                }
+                rows.insert(id, (values.get(0), values.get(1)));
+                Ok(id)
+            }
+
+            fn update(&mut self, old: i64, new: i64, values: &FunctionArgs) -> VTabResult<()> {
+                let mut rows = self.rows.lock().unwrap();
+                rows.remove(&old);
+                rows.insert(new, (values.get(0), values.get(1)));
+                Ok(())
+            }
+
+            fn delete(&mut self, rowid: i64) -> VTabResult<()> {
+                self.rows.lock().unwrap().remove(&rowid);
+                Ok(())
+            }
+        }
+
+        impl VTabCursor for SettingsCursor {
+            fn filter(
+                &mut self,
+                idx_num: i32,
+                _: Option<&str>,
+                args: &FunctionArgs,
+            ) -> VTabResult<()> {
+                self.plans.lock().unwrap().push(idx_num);
+                let rows = self.source.lock().unwrap();
+                self.rows = if idx_num == 1 {
+                    let id: i64 = args.get(0);
+                    rows.get(&id)
+                        .map(|row| (id, row.clone()))
+                        .into_iter()
+                        .collect()
+                } else {
+                    rows.iter().map(|(id, row)| (*id, row.clone())).collect()
+                };
+                self.pos = 0;
+                Ok(())
+            }
+
+            fn next(&mut self) -> VTabResult<()> {
+                self.pos += 1;
+                Ok(())
+            }
+
+            fn eof(&self) -> bool {
+                self.pos >= self.rows.len()
+            }
+
+            fn column(&self, index: usize) -> VTabResult<Value> {
+                let (_, (name, value)) = &self.rows[self.pos];
+                Ok(if index == 0 { name } else { value }.as_str().into())
+            }
+
+            fn rowid(&self) -> VTabResult<i64> {
+                Ok(self.rows[self.pos].0)
+            }
+        }
+
+        // `series(start, stop)`, a read only table-valued function
+        struct Series;
+
+        struct SeriesCursor {
+            value: i64,
+            start: i64,
+            stop: i64,
+        }
+
+        impl VirtualTable for Series {
+            type Cursor = SeriesCursor;
+
+            fn columns(&self) -> String {
+                "value INTEGER, start HIDDEN, stop HIDDEN".to_string()
+            }
+
+            fn best_index(&self, info: &mut IndexInfo) -> VTabResult<()> {
+                let constraints = info.constraints();
+                let argument = |column| {
+                    constraints.iter().position(|c| {
+                        c.usable && c.column == Some(column) && c.op == ConstraintOp::Eq
+                    })
+                };
+                if let (Some(start), Some(stop)) = (argument(1), argument(2)) {
+                    info.use_constraint(start, 0, true);
+                    info.use_constraint(stop, 1, true);
+                    info.set_idx_num(1);
+                    info.set_estimated_cost(10.0);
+                } else {
+                    info.set_estimated_cost(f64::MAX);
+                }
+                Ok(())
+            }
+
+            fn open(&self) -> VTabResult<SeriesCursor> {
+                Ok(SeriesCursor {
+                    value: 0,
+                    start: 0,
+                    stop: -1,
+                })
+            }
+        }
+
+        impl VTabCursor for SeriesCursor {
+            fn filter(
+                &mut self,
+                idx_num: i32,
+                _: Option<&str>,
+                args: &FunctionArgs,
+            ) -> VTabResult<()> {
+                if idx_num != 1 {
+                    return Err("series(start, stop) needs both arguments".into());
+                }
+                (self.start, self.stop) = (args.get(0), args.get(1));
+                self.value = self.start;
+                Ok(())
+            }
+
+            fn next(&mut self) -> VTabResult<()> {
+                self.value += 1;
+                Ok(())
+            }
+
+            fn eof(&self) -> bool {
+                self.value > self.stop
+            }
+
+            fn column(&self, index: usize) -> VTabResult<Value> {
+                Ok([self.value, self.start, self.stop][index].into())
+            }
+
+            fn rowid(&self) -> VTabResult<i64> {
+                Ok(self.value)
+            }
+        }
+
+        let conn = LazyConnection::open_memory()?;
+        let single =
+            |sql: &str| conn.query_dynamic(sql).unwrap().first().unwrap().unwrap()[0].clone();
+
+        conn.create_module("series", |_: &[&str]| Ok(Series))?;
+        assert_eq!(
+            single("SELECT group_concat(value) FROM series(1, 5)").as_string(),
+            "1,2,3,4,5"
+        );
+        assert_eq!(
+            single("SELECT sum(value) FROM series(1, 10) WHERE value > 5").as_i64(),
+            40
+        );
+        // the inner call gets its stop from the outer one
+        assert_eq!(
+            single("SELECT count(*) FROM series(1, 3) a, series(1, a.value)").as_i64(),
+            6
+        );
+
+        let err = conn
+            .query_dynamic("SELECT * FROM series")?
+            .all()
+            .unwrap_err();
+        assert!(err.to_string().contains("needs both arguments"), "{err}");
+        let err = conn
+            .execute_dynamic("INSERT INTO series VALUES (1)")
+            .unwrap_err();
+        assert!(err.error_msg.contains("read only"), "{}", err.error_msg);
+
+        let rows: Rows = Arc::default();
+        let plans = Arc::new(Mutex::new(Vec::new()));
+        let (module_rows, module_plans) = (Arc::clone(&rows), Arc::clone(&plans));
+        conn.create_module("settings", move |args: &[&str]| {
+            if let Some(arg) = args.first() {
+                return Err(format!("unexpected argument {arg}").into());
+            }
+            Ok(Settings {
+                rows: Arc::clone(&module_rows),
+                plans: Arc::clone(&module_plans),
+            })
+        })?;
+        conn.execute_dynamic("CREATE VIRTUAL TABLE config USING settings")?;
+
+        conn.execute_dynamic(
+            "INSERT INTO config (name, value) VALUES ('theme', 'dark'), ('lang', 'en')",
+        )?;
+        assert_eq!(
+            rows.lock().unwrap()[&2],
+            ("lang".to_string(), "en".to_string())
+        );
+
+        assert_eq!(
+            single("SELECT value FROM config WHERE rowid = 2").as_string(),
+            "en"
+        );
+        assert_eq!(plans.lock().unwrap().last(), Some(&1));
+        assert_eq!(
+            single("SELECT value FROM config WHERE name = 'theme'").as_string(),
+            "dark"
+        );
+        assert_eq!(plans.lock().unwrap().last(), Some(&0));
+
+        conn.execute_dynamic("UPDATE config SET value = 'light' WHERE name = 'theme'")?;
+        conn.execute_dynamic("DELETE FROM config WHERE rowid = 2")?;
+        rows.lock()
+            .unwrap()
+            .insert(7, ("tz".to_string(), "utc".to_string()));
+        let all = conn
+            .query_dynamic("SELECT rowid, name, value FROM config")?
+            .all()?;
+        let all: Vec<_> = all
+            .iter()
+            .map(|r| (r[0].as_i64(), r[1].as_string(), r[2].as_string()))
+            .collect();
+        assert_eq!(
+            all,
+            [
+                (1, "theme".into(), "light".into()),
+                (7, "tz".into(), "utc".into())
+            ]
+        );
+
+        let err = conn
+            .execute_dynamic("INSERT INTO config (rowid, name, value) VALUES (1, 'a', 'b')")
+            .unwrap_err();
+        assert!(
+            err.error_msg.contains("setting 1 already exists"),
+            "{}",
+            err.error_msg
+        );
+        let err = conn
+            .execute_dynamic("CREATE VIRTUAL TABLE bad USING settings(oops)")
+            .unwrap_err();
+        assert!(
+            err.error_msg.contains("unexpected argument oops"),
+            "{}",
+            err.error_msg
+        );
+
+        let err = conn
+            .create_module("bad\0name", |_: &[&str]| Ok(Series))
+            .unwrap_err();
+        assert!(matches!(err, ModuleError::EmbeddedNullInName { .. }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_attach() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("lazysql_attach_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let archive_path = dir.join("archive.db");
+        let _ = std::fs::remove_file(&archive_path);
+        let archive_path = archive_path.to_str().unwrap();
+
+        let conn = LazyConnection::open_memory()?;
+        conn.attach(archive_path, "archive")?;
+        conn.execute_dynamic(
+            include_str!("../archive.sql")
+                .replace("orders", "archive.orders")
+                .as_str(),
+        )?;
+
+        let mut dao = ArchiveDao::new(conn.clone());
+        dao.init()?;
+        dao.init_scratch()?;
+        dao.place(12.5)?;
+        dao.archive(1, "12.50", "2026-01-01")?;
+
+        // same table name, different schemas and types
+        let live = dao.live()?.all()?;
+        assert_eq!((live[0].id, live[0].total), (1, 12.5));
+        let archived = dao.archived()?.all()?;
+        assert_eq!(archived[0].total, "12.50");
+        assert_eq!(archived[0].archived_at, "2026-01-01");
+
+        dao.note("temp only")?;
+        assert_eq!(dao.notes()?.first()?.unwrap().note, "temp only");
+
+        // prepared statements of the dao do not keep the database attached
+        conn.detach("archive")?;
+        assert!(conn.query_dynamic("SELECT * FROM archive.orders").is_err());
+        assert!(conn.detach("archive").is_err());
+
+        // the file kept the rows
+        let conn = LazyConnection::open(archive_path)?;
+        assert_eq!(conn.query_dynamic("SELECT * FROM orders")?.all()?.len(), 1);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
 }
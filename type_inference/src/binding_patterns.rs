@@ -1,6 +1,9 @@
 use crate::expr::{BaseType, Type, evaluate_expr_type};
+use crate::functions::user_function;
 use crate::pg_cast_syntax_to_sqlite;
-use crate::table::{ColumnInfo, get_table_names, normalize_identifier, normalize_part};
+use crate::table::{
+    ColumnInfo, find_table, get_table_names, normalize_identifier, normalize_part, table_key,
+};
 use sqlparser::ast::{
     BinaryOperator, DataType, Expr, FunctionArg, FunctionArgExpr, FunctionArguments, SetExpr,
     Spanned, Statement,
@@ -33,9 +36,7 @@ pub struct InferenceError {
 
 fn get_table_name_str(table: &sqlparser::ast::TableObject) -> String {
     match table {
-        sqlparser::ast::TableObject::TableName(obj_name) => {
-            obj_name.0.last().map(normalize_part).unwrap_or_default()
-        }
+        sqlparser::ast::TableObject::TableName(obj_name) => table_key(obj_name),
         _ => String::new(),
     }
 }
@@ -100,9 +101,7 @@ pub fn get_type_of_binding_parameters(
             or,
         } => {
             let table_name = match &table.relation {
-                sqlparser::ast::TableFactor::Table { name, .. } => {
-                    name.0.last().map(normalize_part).unwrap_or_default()
-                }
+                sqlparser::ast::TableFactor::Table { name, .. } => table_key(name),
                 _ => table.relation.to_string(),
             };
 
@@ -112,7 +111,7 @@ pub fn get_type_of_binding_parameters(
                         let col_name = obj_name.0.last().map(normalize_part).unwrap_or_default();
 
                         let mut hint = None;
-                        if let Some(cols) = all_tables.get(&table_name)
+                        if let Some(cols) = find_table(all_tables, &table_name)
                             && let Some(col_info) = cols.iter().find(|c| c.name == col_name)
                         {
                             hint = Some(col_info.data_type.clone());
@@ -139,8 +138,7 @@ pub fn get_type_of_binding_parameters(
                                 let col_name =
                                     name_obj.0.last().map(|p| p.to_string()).unwrap_or_default();
 
-                                let hint = all_tables
-                                    .get(&table_name)
+                                let hint = find_table(all_tables, &table_name)
                                     .and_then(|cols| cols.iter().find(|c| c.name == col_name))
                                     .map(|c| c.data_type.clone());
 
@@ -251,7 +249,7 @@ pub fn get_type_of_binding_parameters(
         Statement::Insert(insert_node) => {
             let t_name = get_table_name_str(&insert_node.table);
 
-            let expected_types = if let Some(table_cols) = all_tables.get(&t_name) {
+            let expected_types = if let Some(table_cols) = find_table(all_tables, &t_name) {
                 if insert_node.columns.is_empty() {
                     table_cols
                         .iter()
@@ -335,7 +333,7 @@ pub fn get_type_of_binding_parameters(
                     };
                     let mut hint = None;
                     if !col_name.is_empty()
-                        && let Some(cols) = all_tables.get(&t_name)
+                        && let Some(cols) = find_table(all_tables, &t_name)
                         && let Some(col_info) = cols.iter().find(|c| c.name == col_name)
                     {
                         hint = Some(col_info.data_type.clone());
@@ -625,6 +623,10 @@ fn traverse_expr(
 
         Expr::Nested(inner) => traverse_expr(inner, table_names, all_tables, results, parent_hint),
 
+        Expr::Collate { expr: inner, .. } => {
+            traverse_expr(inner, table_names, all_tables, results, parent_hint)
+        }
+
         Expr::InList {
             expr: match_expr,
             list,
@@ -1057,10 +1059,21 @@ fn traverse_expr(
                 e
             };
 
+            // declared functions hint every argument with the type of its parameter
+            let signature = user_function(&name);
+
             if let FunctionArguments::List(args_list) = &func.args {
-                for arg in &args_list.args {
+                for (i, arg) in args_list.args.iter().enumerate() {
                     if let FunctionArg::Unnamed(FunctionArgExpr::Expr(arg_expr)) = arg {
-                        traverse_expr(arg_expr, table_names, all_tables, results, arg_hint.clone())
+                        let hint = match &signature {
+                            Some(signature) => signature
+                                .args
+                                .get(i)
+                                .filter(|param| param.base_type != BaseType::Unknowns)
+                                .cloned(),
+                            None => arg_hint.clone(),
+                        };
+                        traverse_expr(arg_expr, table_names, all_tables, results, hint)
                             .map_err(err_mapper)?;
                     }
                 }
@@ -1224,13 +1237,9 @@ fn infer_cte_columns(
 
         let mut register_table = |relation: &sqlparser::ast::TableFactor| {
             if let sqlparser::ast::TableFactor::Table { name, alias, .. } = relation {
-                let real_name = name
-                    .0
-                    .last()
-                    .map(crate::table::normalize_part)
-                    .unwrap_or(name.to_string());
+                let real_name = table_key(name);
 
-                if let Some(cols) = all_tables.get(&real_name) {
+                if let Some(cols) = find_table(all_tables, &real_name) {
                     let effective_name = if let Some(a) = alias {
                         let alias_name = a.name.value.clone();
                         local_tables.insert(alias_name.clone(), cols.clone());
@@ -1323,14 +1332,10 @@ fn traverse_set_expr(
             ) -> Result<(), InferenceError> {
                 match factor {
                     sqlparser::ast::TableFactor::Table { name, alias, .. } => {
-                        let real_name = name
-                            .0
-                            .last()
-                            .map(normalize_part)
-                            .unwrap_or(name.to_string());
+                        let real_name = table_key(name);
                         let effective_name = if let Some(a) = alias {
                             let alias_name = a.name.value.clone();
-                            if let Some(cols) = all_tables.get(&real_name).cloned() {
+                            if let Some(cols) = find_table(all_tables, &real_name).cloned() {
                                 current_select_scope.insert(alias_name.clone(), cols);
                             }
                             alias_name
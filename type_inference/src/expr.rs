@@ -5,8 +5,9 @@ use sqlparser::ast::{
 };
 
 use crate::{
+    functions::user_function,
     select_patterns::traverse_select_output,
-    table::{ColumnInfo, normalize_identifier},
+    table::{ColumnInfo, find_table, normalize_identifier, qualified_key, scope_table},
 };
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BaseType {
@@ -39,7 +40,7 @@ pub fn evaluate_expr_type(
 
             let matches: Vec<&Type> = table_names_from_select
                 .iter()
-                .filter_map(|table_name| all_tables.get(table_name)) // Get columns for table
+                .filter_map(|table_name| find_table(all_tables, table_name)) // Get columns for table
                 .flat_map(|cols| cols.iter()) // Flatten Vec<Vec<Col>> into iterator of Cols
                 .filter(|col_info| col_info.name == search_name) // Compare normalized names
                 .map(|col_info| &col_info.data_type)
@@ -63,12 +64,16 @@ pub fn evaluate_expr_type(
 
             let table_ident = &idents[idents.len() - 2]; // 2nd to last is usually table
             let col_ident = &idents[idents.len() - 1]; // last is column
+            let schema =
+                (idents.len() > 2).then(|| normalize_identifier(&idents[idents.len() - 3]));
 
-            let table_lookup_key = normalize_identifier(table_ident);
+            let table_name = qualified_key(schema.as_deref(), &normalize_identifier(table_ident));
+            let table_lookup_key = scope_table(table_names_from_select, &table_name)
+                .unwrap_or(&table_name)
+                .to_string();
             let col_lookup_key = normalize_identifier(col_ident);
 
-            let column_infos = all_tables
-                .get(&table_lookup_key)
+            let column_infos = find_table(all_tables, &table_lookup_key)
                 .ok_or_else(|| format!("Table '{}' not found", table_lookup_key))?;
 
             column_infos
@@ -513,7 +518,11 @@ pub fn evaluate_expr_type(
 
         // ? category
         // Expr::Overlay { .. }
-        // Expr::Collate { } TODO
+
+        // collations only change how values compare, not their type
+        Expr::Collate { expr, .. } => {
+            evaluate_expr_type(expr, table_names_from_select, all_tables)
+        }
 
         // Datetime
         // Expr::AtTimeZone {..}  TODO
@@ -532,12 +541,14 @@ pub fn evaluate_expr_type(
             };
             let mut any_arg_nullable = false;
             let mut all_args_nullable = true; // track for COALESCE and ifnull
+            let mut arg_types = Vec::new(); // for functions declared by the user
 
             if let FunctionArguments::List(list) = &func.args {
                 for arg in &list.args {
                     if let FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) = arg {
                         let arg_type =
                             evaluate_expr_type(expr, table_names_from_select, all_tables)?;
+                        arg_types.push(arg_type.clone());
 
                         if arg_type.nullable {
                             any_arg_nullable = true;
@@ -574,6 +585,11 @@ pub fn evaluate_expr_type(
                 all_args_nullable = false;
             }
 
+            // declared functions take precedence since they may override a built-in
+            if let Some(signature) = user_function(&name) {
+                return signature.return_type(&arg_types);
+            }
+
             match name.as_str() {
                 // ---- core sqlite section --------
                 // https://sqlite.org/lang_corefunc.html TODO: not all of it is implemented
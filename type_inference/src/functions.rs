@@ -0,0 +1,95 @@
+use std::cell::RefCell;
+
+use crate::expr::{BaseType, Type};
+
+/// How a user defined function is registered at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionKind {
+    Scalar,
+    Aggregate,
+    Window,
+}
+
+/// Signature of a function registered at runtime (`create_scalar_function` and friends),
+/// declared with `#[lazy_sql(functions(slugify(Text) -> Text))]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionSignature {
+    pub name: String,
+    pub kind: FunctionKind,
+    /// `nullable` marks parameters that accept NULL. `BaseType::Unknowns` accepts anything
+    pub args: Vec<Type>,
+    pub returns: Type,
+}
+
+impl FunctionSignature {
+    /// Type of a call with arguments of type `args`.
+    ///
+    /// NULL propagates like it does for most built-ins: passing a nullable value to a parameter
+    /// not declared nullable makes the result nullable.
+    pub fn return_type(&self, args: &[Type]) -> Result<Type, String> {
+        if args.len() != self.args.len() {
+            return Err(format!(
+                "{}() expects {} argument(s) but {} were given",
+                self.name,
+                self.args.len(),
+                args.len()
+            ));
+        }
+
+        let propagates_null = args
+            .iter()
+            .zip(&self.args)
+            .any(|(arg, param)| arg.nullable && !param.nullable);
+
+        Ok(Type {
+            base_type: self.returns.base_type,
+            nullable: self.returns.nullable || propagates_null,
+            contains_placeholder: false,
+        })
+    }
+}
+
+/// `Integer`, `Real`, `Text`, `Blob` and `Bool`. `Any` is only allowed as a parameter
+pub fn parse_base_type(name: &str, is_param: bool) -> Option<BaseType> {
+    match name.to_lowercase().as_str() {
+        "integer" => Some(BaseType::Integer),
+        "real" => Some(BaseType::Real),
+        "text" => Some(BaseType::Text),
+        "blob" => Some(BaseType::Blob),
+        "bool" => Some(BaseType::Bool),
+        "any" if is_param => Some(BaseType::Unknowns),
+        _ => None,
+    }
+}
+
+// The signatures are needed deep inside the expression traversal. Rather than threading them
+// through every traversal function next to `all_tables`, they are set once per macro invocation.
+thread_local! {
+    static USER_FUNCTIONS: RefCell<Vec<FunctionSignature>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Makes `functions` known to the type inference while `f` runs
+pub fn with_user_functions<R>(functions: &[FunctionSignature], f: impl FnOnce() -> R) -> R {
+    struct Restore(Vec<FunctionSignature>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = std::mem::take(&mut self.0);
+            USER_FUNCTIONS.with(|cell| *cell.borrow_mut() = previous);
+        }
+    }
+
+    let previous = USER_FUNCTIONS.with(|cell| cell.replace(functions.to_vec()));
+    let _restore = Restore(previous);
+    f()
+}
+
+/// The declared signature of `name` (case insensitive), if any
+pub fn user_function(name: &str) -> Option<FunctionSignature> {
+    USER_FUNCTIONS.with(|cell| {
+        cell.borrow()
+            .iter()
+            .find(|signature| signature.name.eq_ignore_ascii_case(name))
+            .cloned()
+    })
+}
@@ -1,11 +1,16 @@
 use std::collections::{HashMap, HashSet};
 
-use sqlparser::{ast::Statement, dialect::SQLiteDialect, parser::Parser};
+use sqlparser::{
+    ast::{Statement, TableObject},
+    dialect::SQLiteDialect,
+    parser::Parser,
+};
 
-use crate::table::{ColumnInfo, normalize_identifier};
+use crate::table::{ColumnInfo, find_table, normalize_identifier, table_key};
 
 pub mod binding_patterns;
 pub mod expr;
+pub mod functions;
 pub mod select_patterns;
 pub mod table;
 
@@ -28,16 +33,12 @@ pub fn validate_insert_strict(
 
     for statement in ast {
         if let Statement::Insert(insert) = statement {
-            let raw_table_name = insert.table.to_string();
-
-            // Normalize table name (handle "public.users" -> "users")
-            let t_name = raw_table_name
-                .split('.')
-                .next_back()
-                .unwrap_or(&raw_table_name)
-                .to_lowercase();
+            let t_name = match &insert.table {
+                TableObject::TableName(name) => table_key(name),
+                table => table.to_string().to_lowercase(),
+            };
 
-            let schema_cols = match tables.get(&t_name) {
+            let schema_cols = match find_table(tables, &t_name) {
                 Some(cols) => cols,
                 None => return Err(format!("Table '{}' does not exist", t_name)),
             };
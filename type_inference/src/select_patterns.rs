@@ -13,7 +13,10 @@ use crate::{
     expr::{BaseType, evaluate_expr_type},
     table::normalize_identifier,
 };
-use crate::{pg_cast_syntax_to_sqlite, table::normalize_part};
+use crate::{
+    pg_cast_syntax_to_sqlite,
+    table::{find_table, scope_table, table_key},
+};
 
 pub fn get_types_from_select(
     sql: &str,
@@ -54,9 +57,7 @@ pub fn get_types_from_select(
         Statement::Insert(insert) => {
             if let Some(returning) = &insert.returning {
                 let table_name = match &insert.table {
-                    sqlparser::ast::TableObject::TableName(obj_name) => {
-                        obj_name.0.last().map(normalize_part).unwrap_or_default()
-                    }
+                    sqlparser::ast::TableObject::TableName(obj_name) => table_key(obj_name),
                     _ => return Ok(vec![]),
                 };
 
@@ -110,7 +111,7 @@ fn process_returning_clause(
     let mut local_scope_tables = vec![];
 
     // Put the target table into scope so 'RETURNING id' or 'RETURNING table.id' works
-    if let Some(cols) = all_tables.get(table_name) {
+    if let Some(cols) = find_table(all_tables, table_name) {
         working_tables.insert(table_name.to_string(), cols.clone());
         local_scope_tables.push(table_name.to_string());
     }
@@ -152,20 +153,18 @@ fn process_returning_clause(
             }
             // RETURNING *
             SelectItem::Wildcard(_) => {
-                if let Some(cols) = all_tables.get(table_name) {
+                if let Some(cols) = find_table(all_tables, table_name) {
                     output_columns.extend(cols.clone());
                 }
             }
             // RETURNING users.*
             SelectItem::QualifiedWildcard(kind, _) => {
                 if let SelectItemQualifiedWildcardKind::ObjectName(obj_name) = kind {
-                    let alias_name = obj_name
-                        .0
-                        .last()
-                        .map(normalize_part)
-                        .unwrap_or(obj_name.to_string());
+                    let alias_name = table_key(obj_name);
+                    let alias_name =
+                        scope_table(&local_scope_tables, &alias_name).unwrap_or(&alias_name);
 
-                    if let Some(cols) = working_tables.get(&alias_name) {
+                    if let Some(cols) = working_tables.get(alias_name) {
                         output_columns.extend(cols.clone());
                     }
                 }
@@ -181,7 +180,7 @@ fn extract_name_from_table_factor(relation: &TableFactor) -> Option<String> {
             if let Some(a) = alias {
                 Some(normalize_identifier(&a.name))
             } else {
-                name.0.last().map(normalize_part)
+                Some(table_key(name))
             }
         }
         _ => None,
@@ -289,13 +288,11 @@ pub fn traverse_select_output(
                     }
                     SelectItem::QualifiedWildcard(kind, _) => {
                         if let SelectItemQualifiedWildcardKind::ObjectName(obj_name) = kind {
-                            let alias_name = obj_name
-                                .0
-                                .last()
-                                .map(normalize_part)
-                                .unwrap_or(obj_name.to_string());
+                            let alias_name = table_key(obj_name);
+                            let alias_name = scope_table(&local_scope_tables, &alias_name)
+                                .unwrap_or(&alias_name);
 
-                            if let Some(column_infos) = working_tables.get(&alias_name) {
+                            if let Some(column_infos) = working_tables.get(alias_name) {
                                 for column_info in column_infos {
                                     output_columns.push(column_info.clone());
                                 }
@@ -449,11 +446,7 @@ fn resolve_table_factor(
 ) -> Result<(), String> {
     match relation {
         TableFactor::Table { name, alias, .. } => {
-            let lookup_name = name
-                .0
-                .last()
-                .map(normalize_part)
-                .ok_or("Table name is empty")?;
+            let lookup_name = table_key(name);
 
             let target_alias = if let Some(alias_node) = alias {
                 normalize_identifier(&alias_node.name)
@@ -461,7 +454,7 @@ fn resolve_table_factor(
                 lookup_name.clone()
             };
 
-            if let Some(cols) = all_tables.get(&lookup_name) {
+            if let Some(cols) = find_table(all_tables, &lookup_name) {
                 let mut cols = cols.clone();
                 if force_nullable {
                     for c in &mut cols {
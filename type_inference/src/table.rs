@@ -1,8 +1,10 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::ops::ControlFlow;
 
 use sqlparser::ast::{
-    BinaryOperator, ColumnOption, CreateTable, Expr, ObjectNamePart, Statement, visit_relations,
+    BinaryOperator, ColumnOption, CreateTable, Expr, ObjectName, ObjectNamePart, Statement,
+    visit_relations,
 };
 use sqlparser::dialect::SQLiteDialect;
 use sqlparser::parser::Parser;
@@ -31,6 +33,96 @@ pub fn normalize_part(part: &ObjectNamePart) -> String {
     }
 }
 
+/// The key a table is stored under: the plain name for `main` (and unqualified names),
+/// `schema.table` for `temp` and attached databases
+pub fn qualified_key(schema: Option<&str>, table: &str) -> String {
+    match schema.map(str::to_lowercase) {
+        Some(schema) if schema != "main" => format!("{schema}.{table}"),
+        _ => table.to_string(),
+    }
+}
+
+/// How a table reference is looked up with `find_table`: `archive.Orders` -> `archive.orders`.
+/// Unlike `qualified_key`, `main.users` stays qualified so it is not found in `temp`
+pub fn table_key(name: &ObjectName) -> String {
+    let parts: Vec<String> = name.0.iter().map(normalize_part).collect();
+    match parts.as_slice() {
+        [.., schema, table] => format!("{}.{table}", schema.to_lowercase()),
+        [table] => table.clone(),
+        [] => name.to_string(),
+    }
+}
+
+thread_local! {
+    static ATTACHED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Makes the order the databases in `attached` were attached in known to `find_table`
+/// while `f` runs
+pub fn with_attached<R>(attached: &[String], f: impl FnOnce() -> R) -> R {
+    struct Restore(Vec<String>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = std::mem::take(&mut self.0);
+            ATTACHED.with(|cell| *cell.borrow_mut() = previous);
+        }
+    }
+
+    let previous = ATTACHED.with(|cell| cell.replace(attached.to_vec()));
+    let _restore = Restore(previous);
+    f()
+}
+
+/// Looks up the columns of `key` the way SQLite resolves table names. A qualified key only
+/// matches in its schema, an unqualified one is tried in `temp`, then in `main` (and against
+/// aliases and CTEs), then in the attached databases in the order given to `with_attached`.
+/// Attached databases it does not name come last, by name.
+pub fn find_table<'a>(
+    tables: &'a HashMap<String, Vec<ColumnInfo>>,
+    key: &str,
+) -> Option<&'a Vec<ColumnInfo>> {
+    match key.split_once('.') {
+        Some(("main", table)) => tables.get(key).or_else(|| tables.get(table)),
+        Some(_) => tables.get(key),
+        None => tables
+            .get(&format!("temp.{key}"))
+            .or_else(|| tables.get(key))
+            .or_else(|| {
+                let mut attached: Vec<_> = tables
+                    .iter()
+                    .filter_map(|(name, cols)| match name.split_once('.') {
+                        Some((schema, table)) if table == key && schema != "temp" => {
+                            Some((schema, cols))
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                ATTACHED.with_borrow(|order| {
+                    attached.sort_by_key(|(schema, _)| {
+                        let position = order.iter().position(|name| name == schema);
+                        (position.unwrap_or(order.len()), *schema)
+                    })
+                });
+                attached.first().map(|(_, cols)| *cols)
+            }),
+    }
+}
+
+/// The entry of `scope` a qualifier like the `orders` of `orders.id` refers to.
+/// A table in `scope` can be named without its schema, `archive.orders` is also `orders`
+pub fn scope_table<'a>(scope: &'a [String], table: &str) -> Option<&'a str> {
+    scope
+        .iter()
+        .find(|name| *name == table)
+        .or_else(|| {
+            scope
+                .iter()
+                .find(|name| name.split_once('.').is_some_and(|(_, t)| t == table))
+        })
+        .map(String::as_str)
+}
+
 /// Bool type derived from CHECK constraint
 /// 1. CHECK (col IN (0, 1))
 /// 2. CHECK (col = 0 OR col = 1)
@@ -121,6 +213,12 @@ fn convert_sqlite_to_rust_type(sql: String, nullable: bool, is_bool_context: boo
 }
 
 pub fn create_tables(sql: &str, tables: &mut HashMap<String, Vec<ColumnInfo>>) {
+    create_tables_in(sql, "main", tables);
+}
+
+/// Like `create_tables`, for the schema of an attached database: unqualified tables
+/// are stored as `schema.table`
+pub fn create_tables_in(sql: &str, schema: &str, tables: &mut HashMap<String, Vec<ColumnInfo>>) {
     let dialect = SQLiteDialect {};
     let ast = Parser::parse_sql(&dialect, sql).unwrap();
 
@@ -129,14 +227,17 @@ pub fn create_tables(sql: &str, tables: &mut HashMap<String, Vec<ColumnInfo>>) {
             name,
             columns,
             without_rowid,
+            temporary,
             ..
         }) = statement
         {
-            let table_name = name
-                .0
-                .last()
-                .map(normalize_part)
-                .unwrap_or(name.to_string());
+            let parts: Vec<String> = name.0.iter().map(normalize_part).collect();
+            let table_name = match parts.as_slice() {
+                [.., schema, table] => qualified_key(Some(schema), table),
+                [table] if temporary => qualified_key(Some("temp"), table),
+                [table] => qualified_key(Some(schema), table),
+                [] => name.to_string(),
+            };
 
             let table_columns = columns
                 .iter()
@@ -212,12 +313,7 @@ pub fn get_table_names(sql: &str) -> Vec<String> {
     let statements = Parser::parse_sql(&SQLiteDialect {}, sql).unwrap();
     let mut visited = vec![];
     let _ = visit_relations(&statements, |expr| {
-        let name = expr
-            .0
-            .last()
-            .map(normalize_part)
-            .unwrap_or(expr.to_string());
-        visited.push(name);
+        visited.push(table_key(expr));
         ControlFlow::<()>::Continue(())
     });
     visited
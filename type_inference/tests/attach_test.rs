@@ -0,0 +1,198 @@
+use pretty_assertions::assert_eq;
+use std::collections::HashMap;
+use type_inference::{
+    binding_patterns::get_type_of_binding_parameters,
+    expr::BaseType,
+    select_patterns::get_types_from_select,
+    table::{ColumnInfo, create_tables, create_tables_in, get_table_names, with_attached},
+    validate_insert_strict,
+};
+
+/// `orders` exists in `main` and in the attached `archive` with different column types
+fn setup_tables() -> HashMap<String, Vec<ColumnInfo>> {
+    let mut tables = HashMap::new();
+
+    create_tables(
+        "CREATE TABLE orders (id INTEGER PRIMARY KEY, total REAL NOT NULL)",
+        &mut tables,
+    );
+    create_tables(
+        "CREATE TEMP TABLE scratch (id INTEGER PRIMARY KEY, note TEXT)",
+        &mut tables,
+    );
+    create_tables_in(
+        "CREATE TABLE orders (id INTEGER PRIMARY KEY, total TEXT NOT NULL, archived_at TEXT NOT NULL);
+         CREATE TABLE purges (id INTEGER PRIMARY KEY, purged_at INTEGER NOT NULL)",
+        "archive",
+        &mut tables,
+    );
+    tables
+}
+
+#[track_caller]
+fn select_types(sql: &str) -> Vec<(BaseType, bool)> {
+    get_types_from_select(sql, &setup_tables())
+        .unwrap()
+        .into_iter()
+        .map(|c| (c.data_type.base_type, c.data_type.nullable))
+        .collect()
+}
+
+#[test]
+fn test_tables_are_keyed_by_schema() {
+    let tables = setup_tables();
+    let mut names: Vec<_> = tables.keys().cloned().collect();
+    names.sort();
+    assert_eq!(
+        names,
+        ["archive.orders", "archive.purges", "orders", "temp.scratch"]
+    );
+}
+
+#[test]
+fn test_qualified_names_use_their_schema() {
+    assert_eq!(
+        select_types("SELECT total FROM archive.orders"),
+        [(BaseType::Text, false)]
+    );
+    assert_eq!(
+        select_types("SELECT total FROM main.orders"),
+        [(BaseType::Real, false)]
+    );
+    assert_eq!(
+        select_types("SELECT total FROM orders"),
+        [(BaseType::Real, false)]
+    );
+    assert_eq!(
+        select_types("SELECT note FROM temp.scratch"),
+        [(BaseType::Text, true)]
+    );
+    assert!(get_types_from_select("SELECT note FROM main.scratch", &setup_tables()).is_err());
+}
+
+#[test]
+fn test_unqualified_names_fall_back_to_temp_and_attached() {
+    assert_eq!(
+        select_types("SELECT note FROM scratch"),
+        [(BaseType::Text, true)]
+    );
+    assert_eq!(
+        select_types("SELECT purged_at FROM purges"),
+        [(BaseType::Integer, false)]
+    );
+}
+
+#[test]
+fn test_qualified_column_references() {
+    assert_eq!(
+        select_types("SELECT orders.total, archive.orders.archived_at FROM archive.orders"),
+        [(BaseType::Text, false), (BaseType::Text, false)]
+    );
+    assert_eq!(
+        select_types("SELECT orders.* FROM archive.orders"),
+        [
+            (BaseType::Integer, true),
+            (BaseType::Text, false),
+            (BaseType::Text, false)
+        ]
+    );
+    assert_eq!(
+        select_types(
+            "SELECT a.total, o.total FROM archive.orders a LEFT JOIN main.orders o ON a.id = o.id"
+        ),
+        [(BaseType::Text, false), (BaseType::Real, true)]
+    );
+}
+
+#[test]
+fn test_bindings_and_inserts_use_the_attached_schema() {
+    let tables = setup_tables();
+
+    let bindings =
+        get_type_of_binding_parameters("SELECT id FROM archive.orders WHERE total = ?", &tables)
+            .unwrap();
+    assert_eq!(bindings[0].base_type, BaseType::Text);
+
+    let bindings = get_type_of_binding_parameters(
+        "UPDATE archive.orders SET archived_at = ? WHERE id = ?",
+        &tables,
+    )
+    .unwrap();
+    assert_eq!(bindings[0].base_type, BaseType::Text);
+
+    assert!(validate_insert_strict("INSERT INTO orders (total) VALUES (1.5)", &tables).is_ok());
+    let err = validate_insert_strict("INSERT INTO archive.orders (total) VALUES ('1.5')", &tables)
+        .unwrap_err();
+    assert!(err.contains("archived_at"), "{err}");
+
+    let returning = get_types_from_select(
+        "INSERT INTO archive.orders (total, archived_at) VALUES (?, ?) RETURNING *",
+        &tables,
+    )
+    .unwrap();
+    assert_eq!(returning.len(), 3);
+}
+
+#[test]
+fn test_get_table_names_keeps_the_schema() {
+    assert_eq!(
+        get_table_names("SELECT * FROM archive.orders JOIN main.orders USING (id) JOIN scratch"),
+        ["archive.orders", "main.orders", "scratch"]
+    );
+}
+
+#[test]
+fn test_temp_tables_shadow_main() {
+    let mut tables = setup_tables();
+    create_tables(
+        "CREATE TEMP TABLE orders (id INTEGER PRIMARY KEY, total INTEGER)",
+        &mut tables,
+    );
+
+    let types = |sql| {
+        get_types_from_select(sql, &tables)
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.data_type.base_type, c.data_type.nullable))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        types("SELECT total FROM orders"),
+        [(BaseType::Integer, true)]
+    );
+    assert_eq!(
+        types("SELECT total FROM main.orders"),
+        [(BaseType::Real, false)]
+    );
+}
+
+#[test]
+fn test_attached_databases_are_searched_in_attach_order() {
+    let mut tables = HashMap::new();
+    create_tables_in(
+        "CREATE TABLE events (id INTEGER PRIMARY KEY, at TEXT NOT NULL)",
+        "zulu",
+        &mut tables,
+    );
+    create_tables_in(
+        "CREATE TABLE events (id INTEGER PRIMARY KEY, at INTEGER NOT NULL)",
+        "alpha",
+        &mut tables,
+    );
+
+    let at_type = || {
+        get_types_from_select("SELECT at FROM events", &tables).unwrap()[0]
+            .data_type
+            .base_type
+    };
+    let attach_order = |order: &[&str]| order.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+    assert_eq!(
+        with_attached(&attach_order(&["zulu", "alpha"]), at_type),
+        BaseType::Text
+    );
+    assert_eq!(
+        with_attached(&attach_order(&["alpha", "zulu"]), at_type),
+        BaseType::Integer
+    );
+}
@@ -838,4 +838,12 @@ mod tests {
         vec![BaseType::Real, BaseType::Real],
     );
     }
+
+    #[test]
+    fn test_collate() {
+        check_binding_types(
+            "SELECT id FROM users WHERE name = ? COLLATE NOCASE AND ? COLLATE BINARY < name",
+            vec![BaseType::Text, BaseType::Text],
+        );
+    }
 }
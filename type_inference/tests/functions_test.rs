@@ -0,0 +1,142 @@
+use pretty_assertions::assert_eq;
+use type_inference::*;
+
+use crate::{
+    binding_patterns::get_type_of_binding_parameters,
+    expr::{BaseType, Type},
+    functions::{FunctionKind, FunctionSignature, with_user_functions},
+    select_patterns::get_types_from_select,
+    table::{ColumnInfo, create_tables},
+};
+use std::collections::HashMap;
+
+fn setup_tables() -> HashMap<String, Vec<ColumnInfo>> {
+    let mut tables = HashMap::new();
+    create_tables(
+        "CREATE TABLE posts (id INTEGER NOT NULL, title TEXT NOT NULL, subtitle TEXT, rating REAL)",
+        &mut tables,
+    );
+    tables
+}
+
+fn ty(base_type: BaseType, nullable: bool) -> Type {
+    Type {
+        base_type,
+        nullable,
+        contains_placeholder: false,
+    }
+}
+
+fn functions() -> Vec<FunctionSignature> {
+    vec![
+        FunctionSignature {
+            name: "slugify".to_string(),
+            kind: FunctionKind::Scalar,
+            args: vec![ty(BaseType::Text, false)],
+            returns: ty(BaseType::Text, false),
+        },
+        FunctionSignature {
+            name: "score".to_string(),
+            kind: FunctionKind::Scalar,
+            args: vec![ty(BaseType::Integer, false), ty(BaseType::Real, true)],
+            returns: ty(BaseType::Real, false),
+        },
+        FunctionSignature {
+            name: "median".to_string(),
+            kind: FunctionKind::Aggregate,
+            args: vec![ty(BaseType::Real, true)],
+            returns: ty(BaseType::Real, true),
+        },
+        FunctionSignature {
+            name: "hash".to_string(),
+            kind: FunctionKind::Scalar,
+            args: vec![ty(BaseType::Unknowns, true)],
+            returns: ty(BaseType::Blob, false),
+        },
+    ]
+}
+
+#[track_caller]
+fn check_select_types(sql: &str, expected: Vec<(BaseType, bool)>) {
+    let tables = setup_tables();
+    let types: Vec<_> = with_user_functions(&functions(), || get_types_from_select(sql, &tables))
+        .unwrap()
+        .into_iter()
+        .map(|c| (c.data_type.base_type, c.data_type.nullable))
+        .collect();
+    assert_eq!(types, expected);
+}
+
+#[track_caller]
+fn check_binding_types(sql: &str, expected: Vec<(BaseType, bool)>) {
+    let tables = setup_tables();
+    let types: Vec<_> = with_user_functions(&functions(), || {
+        get_type_of_binding_parameters(sql, &tables)
+    })
+    .unwrap()
+    .into_iter()
+    .map(|t| (t.base_type, t.nullable))
+    .collect();
+    assert_eq!(types, expected);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_declared_return_types() {
+        check_select_types(
+            "SELECT slugify(title), score(id, rating), median(rating), hash(title) FROM posts",
+            vec![
+                (BaseType::Text, false),
+                (BaseType::Real, false),
+                (BaseType::Real, true),
+                (BaseType::Blob, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_declared_functions_are_case_insensitive() {
+        check_select_types(
+            "SELECT SLUGIFY(title) AS slug FROM posts",
+            vec![(BaseType::Text, false)],
+        );
+    }
+
+    #[test]
+    fn test_null_propagates_into_non_nullable_parameters() {
+        check_select_types(
+            "SELECT slugify(subtitle), score(id, NULL) FROM posts",
+            vec![(BaseType::Text, true), (BaseType::Real, false)],
+        );
+    }
+
+    #[test]
+    fn test_wrong_argument_count() {
+        let tables = setup_tables();
+        let result = with_user_functions(&functions(), || {
+            get_types_from_select("SELECT slugify(title, title) FROM posts", &tables)
+        });
+        assert!(result.unwrap_err().contains("expects 1 argument"));
+    }
+
+    #[test]
+    fn test_undeclared_functions_are_still_rejected() {
+        let tables = setup_tables();
+        assert!(get_types_from_select("SELECT slugify(title) FROM posts", &tables).is_err());
+    }
+
+    #[test]
+    fn test_declared_parameters_hint_placeholders() {
+        check_binding_types(
+            "SELECT id FROM posts WHERE score(?, ?) > 1 AND slugify(?) = 'a'",
+            vec![
+                (BaseType::Integer, false),
+                (BaseType::Real, true),
+                (BaseType::Text, false),
+            ],
+        );
+    }
+}
@@ -1290,4 +1290,12 @@ mod tests {
             ],
         );
     }
+
+    #[test]
+    fn test_collate() {
+        check_select_types(
+            "SELECT name COLLATE NOCASE, description COLLATE RTRIM AS d FROM users, products",
+            vec![t(BaseType::Text, false), t(BaseType::Text, true)],
+        );
+    }
 }